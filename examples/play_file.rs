@@ -0,0 +1,31 @@
+//! Play a MIDI file, without UI nor MIDI input
+//!
+//! Usage: `play_file MIDI_FILE [FONT]`, the default GM SoundFont is used if not given.
+
+use std::time::Duration;
+use anyhow::{Context, Result};
+use pianote::Piano;
+
+
+fn main() -> Result<()> {
+    let mut args = std::env::args_os().skip(1);
+    let midi_path = args.next().context("no MIDI file provided")?;
+    let path = match args.next() {
+        Some(name) => pianote::resolve_sfont(name)?,
+        None => pianote::find_default_sfont().context("no SoundFont provided or found")?,
+    };
+
+    let mut piano = Piano::new(Piano::DEFAULT_GAIN)?;
+    piano.load_sfont(&path)?;
+    piano.play()?;
+
+    let player = piano.play_file(&midi_path)?;
+    println!("Playing {:.1}s", player.duration().as_secs_f32());
+    while !player.is_finished() {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    // Let the last notes ring
+    std::thread::sleep(Duration::from_secs(1));
+    Ok(())
+}
@@ -0,0 +1,32 @@
+//! Play a C major scale, without UI nor MIDI input
+//!
+//! Usage: `scale [FONT]`, the default GM SoundFont is used if not given.
+
+use std::time::Duration;
+use anyhow::{Context, Result};
+use pianote::Piano;
+use wmidi::{Channel, Note, U7};
+
+
+fn main() -> Result<()> {
+    let path = match std::env::args_os().nth(1) {
+        Some(name) => pianote::resolve_sfont(name)?,
+        None => pianote::find_default_sfont().context("no SoundFont provided or found")?,
+    };
+
+    let mut piano = Piano::new(Piano::DEFAULT_GAIN)?;
+    piano.load_sfont(&path)?;
+    piano.play()?;
+
+    let scale = [Note::C4, Note::D4, Note::E4, Note::F4, Note::G4, Note::A4, Note::B4, Note::C5];
+    let velocity = U7::try_from(100).unwrap();
+    for note in scale {
+        piano.note_on(Channel::Ch1, note, velocity)?;
+        std::thread::sleep(Duration::from_millis(400));
+        piano.note_off(Channel::Ch1, note)?;
+    }
+
+    // Let the last note ring
+    std::thread::sleep(Duration::from_secs(1));
+    Ok(())
+}
@@ -0,0 +1,34 @@
+//! Play the same MIDI input on two pianos, each using its own output device and font
+//!
+//! Usage: `two_pianos DEVICE1 FONT1 DEVICE2 FONT2`
+
+use anyhow::{Context, Result};
+use pianote::{output_device_names, Piano, PianoConfig, PianoMidiInput};
+
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() != 4 {
+        eprintln!("usage: two_pianos DEVICE1 FONT1 DEVICE2 FONT2");
+        eprintln!("available output devices:");
+        for name in output_device_names()? {
+            eprintln!("  {}", name);
+        }
+        std::process::exit(1);
+    }
+
+    let mut pianos = Vec::new();
+    for pair in args.chunks(2) {
+        let (device, font) = (&pair[0], &pair[1]);
+        let mut piano = Piano::with_config(PianoConfig { output_device: Some(device.clone()), ..Default::default() })
+            .with_context(|| format!("failed to create piano on {}", device))?;
+        piano.load_sfont(font)?;
+        piano.set_input(PianoMidiInput::new(None))?;
+        piano.play()?;
+        pianos.push(piano);
+    }
+
+    println!("Playing, press Enter to quit");
+    std::io::stdin().read_line(&mut String::new())?;
+    Ok(())
+}
@@ -1,24 +1,145 @@
-use anyhow::{Context, Result};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use anyhow::Result;
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use crate::error::PianoError;
 
 
 /// Elements used to create an audio output stream
 pub struct AudioOutputConfig {
     device: cpal::Device,
     config: cpal::StreamConfig,
+    /// Device channels receiving left and right samples, 0-based
+    channels: [usize; 2],
+    /// Sample rate to use, highest supported one if not set
+    requested_rate: Option<u32>,
+    /// Whether the audio thread requests real-time scheduling
+    realtime_priority: bool,
 }
 
 /// An audio output stream
 pub struct AudioOutput {
     stream: cpal::Stream,
+    xrun_count: Arc<AtomicUsize>,
+    /// Delay between a callback and the playback of its samples, in microseconds
+    latency_us: Arc<AtomicU64>,
+    /// Set when playback starts, to not detect the gap as a dropout
+    restarted: Arc<AtomicBool>,
+    timing: Arc<CallbackTiming>,
+}
+
+/// Time spent in the audio callback, over the buffers played so far
+#[derive(Copy, Clone, Default, Debug)]
+pub struct TimingStats {
+    /// Number of processed buffers
+    pub count: u64,
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+    /// Duration of the last buffer, time available to process it
+    pub buffer: Duration,
+}
+
+impl std::fmt::Display for TimingStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} buffers, min {:.2?}, avg {:.2?}, max {:.2?}, buffer {:.2?}",
+            self.count, self.min, self.avg, self.max, self.buffer)
+    }
+}
+
+/// Callback timing, updated from the audio thread
+struct CallbackTiming {
+    count: AtomicU64,
+    total_ns: AtomicU64,
+    min_ns: AtomicU64,
+    max_ns: AtomicU64,
+    buffer_ns: AtomicU64,
+}
+
+impl CallbackTiming {
+    fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            total_ns: AtomicU64::new(0),
+            min_ns: AtomicU64::new(u64::MAX),
+            max_ns: AtomicU64::new(0),
+            buffer_ns: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, elapsed: Duration, buffer: Duration) {
+        let elapsed = elapsed.as_nanos() as u64;
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_ns.fetch_add(elapsed, Ordering::Relaxed);
+        self.min_ns.fetch_min(elapsed, Ordering::Relaxed);
+        self.max_ns.fetch_max(elapsed, Ordering::Relaxed);
+        self.buffer_ns.store(buffer.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> TimingStats {
+        let count = self.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return TimingStats::default();
+        }
+        TimingStats {
+            count,
+            min: Duration::from_nanos(self.min_ns.load(Ordering::Relaxed)),
+            avg: Duration::from_nanos(self.total_ns.load(Ordering::Relaxed) / count),
+            max: Duration::from_nanos(self.max_ns.load(Ordering::Relaxed)),
+            buffer: Duration::from_nanos(self.buffer_ns.load(Ordering::Relaxed)),
+        }
+    }
 }
 
 impl AudioOutputConfig {
     pub fn new() -> Result<Self> {
         let host = cpal::default_host();
-        let device = host.default_output_device().context("no audio output device available")?;
-        let config = Self::get_output_config(&device)?;
-        Ok(Self { device, config })
+        let device = host.default_output_device().ok_or(PianoError::DeviceNotFound(None))?;
+        let config = Self::get_output_config(&device, 2, None)?;
+        Ok(Self { device, config, channels: [0, 1], requested_rate: None, realtime_priority: false })
+    }
+
+    /// Use the output device with the given name
+    pub fn with_device(name: &str) -> Result<Self> {
+        let host = cpal::default_host();
+        let device = host.output_devices()?
+            .find(|device| device.name().is_ok_and(|n| n == name))
+            .ok_or_else(|| PianoError::DeviceNotFound(Some(name.into())))?;
+        let config = Self::get_output_config(&device, 2, None)?;
+        Ok(Self { device, config, channels: [0, 1], requested_rate: None, realtime_priority: false })
+    }
+
+    /// Output left and right samples to the given device channels, 0-based
+    ///
+    /// Other device channels are silent.
+    pub fn with_channels(mut self, channels: [usize; 2]) -> Result<Self> {
+        if channels[0] == channels[1] {
+            anyhow::bail!("left and right output channels must differ");
+        }
+        self.config = Self::get_output_config(&self.device, Self::min_channels(channels), self.requested_rate)?;
+        self.channels = channels;
+        Ok(self)
+    }
+
+    /// Use the given sample rate, instead of the highest supported one
+    pub fn with_sample_rate(mut self, rate: u32) -> Result<Self> {
+        self.config = Self::get_output_config(&self.device, Self::min_channels(self.channels), Some(rate))?;
+        self.requested_rate = Some(rate);
+        Ok(self)
+    }
+
+    /// Request real-time scheduling for the audio thread
+    ///
+    /// A warning is printed if it cannot be applied, for instance due to missing
+    /// permissions.
+    pub fn with_realtime_priority(mut self, enabled: bool) -> Self {
+        self.realtime_priority = enabled;
+        self
+    }
+
+    fn min_channels(channels: [usize; 2]) -> u16 {
+        (channels[0].max(channels[1]) + 1) as u16
     }
 
     pub fn sample_rate(&self) -> f64 {
@@ -30,8 +151,64 @@ impl AudioOutputConfig {
     where
         S: FnMut(&mut [f32]) + Send + 'static,
     {
-        let data_fn = move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-            next_samples(data);
+        let xrun_count = Arc::new(AtomicUsize::new(0));
+        let restarted = Arc::new(AtomicBool::new(true));
+        let latency_us = Arc::new(AtomicU64::new(0));
+        let timing = Arc::new(CallbackTiming::new());
+        let data_fn = {
+            let timing = Arc::clone(&timing);
+            let xrun_count = Arc::clone(&xrun_count);
+            let latency_us = Arc::clone(&latency_us);
+            let restarted = Arc::clone(&restarted);
+            let channels = self.config.channels as usize;
+            let mapping = self.channels;
+            let direct = channels == 2 && mapping == [0, 1];
+            // Stereo samples, used when mapped to device channels; only allocated when buffer size grows
+            let mut stereo: Vec<f32> = vec![];
+            let sample_rate = self.sample_rate();
+            // Playback instant expected for the next buffer, and duration of the last one
+            let mut expected_playback: Option<(cpal::StreamInstant, Duration)> = None;
+            // The audio thread is only known from the callback
+            let mut promote_thread = self.realtime_priority;
+            move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+                if promote_thread {
+                    promote_thread = false;
+                    if let Err(err) = set_realtime_priority() {
+                        eprintln!("warning: cannot set audio thread real-time priority: {}", err);
+                    }
+                }
+
+                // A playback later than expected means a gap in the output
+                let timestamp = info.timestamp();
+                let playback = timestamp.playback;
+                if let Some(latency) = playback.duration_since(&timestamp.callback) {
+                    latency_us.store(latency.as_micros() as u64, Ordering::Relaxed);
+                }
+                if restarted.swap(false, Ordering::Relaxed) {
+                    expected_playback = None;
+                }
+                if let Some((expected, duration)) = expected_playback {
+                    if playback.duration_since(&expected).is_some_and(|late| late > duration / 2) {
+                        xrun_count.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                let duration = Duration::from_secs_f64((data.len() / channels) as f64 / sample_rate);
+                expected_playback = playback.add(duration).map(|instant| (instant, duration));
+
+                let start = Instant::now();
+                if direct {
+                    next_samples(data);
+                } else {
+                    stereo.resize(data.len() / channels * 2, 0.0);
+                    next_samples(&mut stereo);
+                    data.fill(0.0);
+                    for (frame, samples) in data.chunks_exact_mut(channels).zip(stereo.chunks_exact(2)) {
+                        frame[mapping[0]] = samples[0];
+                        frame[mapping[1]] = samples[1];
+                    }
+                }
+                timing.record(start.elapsed(), duration);
+            }
         };
         let err_fn = |err| eprintln!("an error occurred on audio stream: {}", err);
 
@@ -41,29 +218,152 @@ impl AudioOutputConfig {
             err_fn,
         )?;
 
-        Ok(AudioOutput { stream })
+        Ok(AudioOutput { stream, xrun_count, latency_us, restarted, timing })
     }
 
     /// Get a suitable output config
-    fn get_output_config(device: &cpal::Device) -> Result<cpal::StreamConfig> {
-        for configs in device.supported_output_configs()? {
-            if configs.channels() == 2 && configs.sample_format() == cpal::SampleFormat::F32 {
-                return Ok(configs.with_max_sample_rate().config());
-            }
+    ///
+    /// Configs with the fewest channels, but at least `min_channels`, are preferred.
+    /// If a sample rate is given, it must be supported.
+    fn get_output_config(device: &cpal::Device, min_channels: u16, sample_rate: Option<u32>) -> Result<cpal::StreamConfig> {
+        let best = device.supported_output_configs()?
+            .filter(|configs| configs.channels() >= min_channels && configs.sample_format() == cpal::SampleFormat::F32)
+            .filter(|configs| sample_rate.is_none_or(|rate| (configs.min_sample_rate().0..=configs.max_sample_rate().0).contains(&rate)))
+            .min_by_key(|configs| configs.channels());
+        if let Some(configs) = best {
+            let configs = match sample_rate {
+                Some(rate) => configs.with_sample_rate(cpal::SampleRate(rate)),
+                None => configs.with_max_sample_rate(),
+            };
+            return Ok(configs.config());
         }
-        anyhow::bail!("no stereo audio output configuration");
+        let supported = describe_configs(device)?.iter()
+            .map(|config| config.to_string())
+            .collect::<Vec<_>>();
+        let rate = sample_rate.map(|rate| format!(" at {} Hz", rate)).unwrap_or_default();
+        anyhow::bail!("no f32 audio output configuration with {} channels or more{}, supported: {}", min_channels, rate, supported.join(", "));
     }
 }
 
+/// Description of an output configuration supported by a device
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ConfigDescription {
+    pub channels: u16,
+    /// Sample format name (e.g. `f32`)
+    pub sample_format: String,
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+}
+
+impl std::fmt::Display for ConfigDescription {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} channels {} {}-{} Hz", self.channels, self.sample_format, self.min_sample_rate, self.max_sample_rate)
+    }
+}
+
+/// Return output configurations supported by the given device, or the default one
+pub fn supported_output_configs(device: Option<&str>) -> Result<Vec<ConfigDescription>, PianoError> {
+    let host = cpal::default_host();
+    let device = match device {
+        Some(name) => host.output_devices().map_err(anyhow::Error::from)?
+            .find(|device| device.name().is_ok_and(|n| n == name))
+            .ok_or_else(|| PianoError::DeviceNotFound(Some(name.into())))?,
+        None => host.default_output_device().ok_or(PianoError::DeviceNotFound(None))?,
+    };
+    Ok(describe_configs(&device)?)
+}
+
+fn describe_configs(device: &cpal::Device) -> Result<Vec<ConfigDescription>> {
+    let configs = device.supported_output_configs()?
+        .map(|config| ConfigDescription {
+            channels: config.channels(),
+            sample_format: match config.sample_format() {
+                cpal::SampleFormat::I16 => "i16",
+                cpal::SampleFormat::U16 => "u16",
+                cpal::SampleFormat::F32 => "f32",
+            }.into(),
+            min_sample_rate: config.min_sample_rate().0,
+            max_sample_rate: config.max_sample_rate().0,
+        })
+        .collect();
+    Ok(configs)
+}
+
 impl AudioOutput {
     pub fn play(&self) -> Result<()> {
+        self.restarted.store(true, Ordering::Relaxed);
         self.stream.play()?;
         Ok(())
     }
 
-    pub fn pause(&self) -> Result<()> {
-        self.stream.pause()?;
-        Ok(())
+    /// Return the number of times the output could not be filled in time
+    pub fn xrun_count(&self) -> usize {
+        self.xrun_count.load(Ordering::Relaxed)
     }
+
+    /// Return the delay between rendering of samples and their playback
+    pub fn latency(&self) -> Duration {
+        Duration::from_micros(self.latency_us.load(Ordering::Relaxed))
+    }
+
+    /// Return the time spent in the audio callback, to check if the buffer size is adequate
+    pub fn timing_stats(&self) -> TimingStats {
+        self.timing.stats()
+    }
+}
+
+
+
+/// Return the names of available audio output devices
+pub fn output_device_names() -> Result<Vec<String>, PianoError> {
+    let host = cpal::default_host();
+    Ok(host.output_devices().map_err(anyhow::Error::from)?.filter_map(|device| device.name().ok()).collect())
 }
 
+
+/// Sine wave generator, used to check audio output
+pub struct TestTone {
+    phase: f32,
+    step: f32,
+}
+
+impl TestTone {
+    const AMPLITUDE: f32 = 0.2;
+
+    pub fn new(sample_rate: f64, frequency: f32) -> Self {
+        let step = frequency * std::f32::consts::TAU / sample_rate as f32;
+        Self { phase: 0.0, step }
+    }
+
+    /// Write the next stereo samples
+    pub fn write_samples(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_mut(2) {
+            frame.fill(self.phase.sin() * Self::AMPLITUDE);
+            self.phase = (self.phase + self.step) % std::f32::consts::TAU;
+        }
+    }
+}
+
+
+/// Real-time priority requested for the audio thread, out of 1-99 on Linux
+#[cfg(all(unix, feature = "rt-priority"))]
+const REALTIME_PRIORITY: u8 = 70;
+
+/// Switch the current thread to real-time scheduling
+#[cfg(all(unix, feature = "rt-priority"))]
+fn set_realtime_priority() -> Result<()> {
+    use thread_priority::{ThreadPriority, ThreadPriorityValue};
+    use thread_priority::unix::{set_thread_priority_and_policy, thread_native_id, RealtimeThreadSchedulePolicy, ThreadSchedulePolicy};
+
+    let policy = ThreadSchedulePolicy::Realtime(RealtimeThreadSchedulePolicy::Fifo);
+    // The range of priorities depends on the system
+    let max = ThreadPriority::max_value_for_policy(policy)?;
+    let priority = ThreadPriorityValue::try_from(REALTIME_PRIORITY.min(max as u8)).map_err(anyhow::Error::msg)?;
+    set_thread_priority_and_policy(thread_native_id(), ThreadPriority::Crossplatform(priority), policy)?;
+    Ok(())
+}
+
+#[cfg(not(all(unix, feature = "rt-priority")))]
+fn set_realtime_priority() -> Result<()> {
+    anyhow::bail!("not supported by this build, the rt-priority feature is required")
+}
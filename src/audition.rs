@@ -0,0 +1,58 @@
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use fluidlite::IsFont;
+use crate::midi::MidiMessage;
+use crate::piano::Preset;
+use crate::synth::Synth;
+use crate::wav::{write_wav, WavFormat};
+use crate::error::PianoError;
+
+
+/// Single note of a preset, rendered offline to inspect it
+///
+/// Rendering uses its own synth, with no audio output and no effects, so
+/// that only the preset samples are heard.
+pub struct Audition {
+    pub preset: Preset,
+    pub note: wmidi::Note,
+    pub velocity: wmidi::U7,
+    /// Time the note is held
+    pub duration: Duration,
+    /// Time rendered after the note is released
+    pub release: Duration,
+    pub sample_rate: u32,
+}
+
+impl Audition {
+    pub const DEFAULT_RELEASE: Duration = Duration::from_secs(1);
+    pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+    /// Render the note, return interleaved stereo samples
+    pub fn render<P: AsRef<Path>>(&self, sfont: P) -> Result<Vec<f32>, PianoError> {
+        let sfont = sfont.as_ref();
+        let synth = Synth::new(self.sample_rate as f64, Synth::DEFAULT_GAIN, false)?;
+        let sfont_id = synth.synth.sfload(sfont, true)
+            .map_err(|err| anyhow::anyhow!("{}", err))
+            .with_context(|| format!("failed to load SoundFont {}", sfont.display()))?;
+        let font = synth.synth.get_sfont_by_id(sfont_id).unwrap();
+        if font.get_preset(self.preset.bank, self.preset.num).is_none() {
+            return Err(PianoError::InvalidPreset(self.preset));
+        }
+        synth.synth.program_select(0, sfont_id, self.preset.bank, self.preset.num)?;
+
+        let frames = |duration: Duration| (duration.as_secs_f64() * self.sample_rate as f64) as usize;
+        let channel = wmidi::Channel::Ch1;
+        synth.send_midi_message(MidiMessage::NoteOn(channel, self.note, self.velocity))?;
+        let mut samples = synth.render(frames(self.duration))?;
+        synth.send_midi_message(MidiMessage::NoteOff(channel, self.note, wmidi::U7::MIN))?;
+        samples.extend(synth.render(frames(self.release))?);
+        Ok(samples)
+    }
+
+    /// Render the note to a WAV file
+    pub fn write_wav<P: AsRef<Path>, Q: AsRef<Path>>(&self, sfont: P, path: Q, format: WavFormat) -> Result<(), PianoError> {
+        let samples = self.render(sfont)?;
+        Ok(write_wav(path.as_ref(), &samples, self.sample_rate, format)?)
+    }
+}
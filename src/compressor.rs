@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+
+/// Parameters of the output compressor
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct CompConfig {
+    /// Level above which the signal is compressed, in dBFS
+    pub threshold_db: f32,
+    /// Input to output level ratio above the threshold
+    pub ratio: f32,
+    /// Time for the envelope to follow a rising level
+    pub attack: Duration,
+    /// Time for the envelope to follow a falling level
+    pub release: Duration,
+}
+
+impl Default for CompConfig {
+    fn default() -> Self {
+        Self {
+            threshold_db: -12.0,
+            ratio: 4.0,
+            attack: Duration::from_millis(5),
+            release: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Peak compressor, applied on interleaved stereo samples
+///
+/// Both channels are compressed by the same gain, to keep the stereo image.
+pub struct Compressor {
+    threshold_db: f32,
+    /// Gain reduction slope, `1 - 1/ratio`
+    slope: f32,
+    attack_coef: f32,
+    release_coef: f32,
+    /// Current peak envelope
+    envelope: f32,
+}
+
+impl Compressor {
+    pub fn new(config: &CompConfig, sample_rate: f64) -> Self {
+        let coef = |time: Duration| {
+            let samples = time.as_secs_f64() * sample_rate;
+            if samples > 0.0 { (-1.0 / samples).exp() as f32 } else { 0.0 }
+        };
+        Self {
+            threshold_db: config.threshold_db,
+            slope: 1.0 - 1.0 / config.ratio.max(1.0),
+            attack_coef: coef(config.attack),
+            release_coef: coef(config.release),
+            envelope: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, data: &mut [f32]) {
+        for frame in data.chunks_exact_mut(2) {
+            let peak = frame[0].abs().max(frame[1].abs());
+            let coef = if peak > self.envelope { self.attack_coef } else { self.release_coef };
+            self.envelope = coef * self.envelope + (1.0 - coef) * peak;
+
+            let over_db = 20.0 * self.envelope.max(1e-10).log10() - self.threshold_db;
+            if over_db > 0.0 {
+                let gain = 10f32.powf(-over_db * self.slope / 20.0);
+                frame[0] *= gain;
+                frame[1] *= gain;
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{CompConfig, Compressor};
+
+    const SAMPLE_RATE: f64 = 48000.0;
+
+    /// Compress a constant stereo signal for a second, return the last frame
+    fn settle(compressor: &mut Compressor, left: f32, right: f32) -> [f32; 2] {
+        let mut data: Vec<f32> = [left, right].repeat(SAMPLE_RATE as usize);
+        compressor.process(&mut data);
+        [data[data.len() - 2], data[data.len() - 1]]
+    }
+
+    #[test]
+    fn below_threshold() {
+        let mut compressor = Compressor::new(&CompConfig::default(), SAMPLE_RATE);
+        assert_eq!(settle(&mut compressor, 0.2, -0.2), [0.2, -0.2]);
+    }
+
+    #[test]
+    fn peak_reduction() {
+        // 12 dB over the threshold, reduced to 3 dB over it
+        let mut compressor = Compressor::new(&CompConfig::default(), SAMPLE_RATE);
+        let [left, right] = settle(&mut compressor, 1.0, 0.5);
+        assert!((left - 10f32.powf(-9.0 / 20.0)).abs() < 1e-3, "left: {}", left);
+        // Same gain on both channels
+        assert!((right - left / 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn unit_ratio() {
+        let config = CompConfig { ratio: 1.0, ..Default::default() };
+        let mut compressor = Compressor::new(&config, SAMPLE_RATE);
+        assert_eq!(settle(&mut compressor, 1.0, 1.0), [1.0, 1.0]);
+    }
+
+    #[test]
+    fn attack_and_release() {
+        let config = CompConfig { attack: Duration::from_millis(10), release: Duration::from_millis(100), ..Default::default() };
+        let mut compressor = Compressor::new(&config, SAMPLE_RATE);
+        // The first frames pass while the envelope rises
+        let mut data = [1.0; 2 * 8];
+        compressor.process(&mut data);
+        assert!(data[0] > 0.99);
+
+        settle(&mut compressor, 1.0, 1.0);
+        // Gain recovers slowly when the level drops below the threshold
+        let mut data = [0.2; 2 * 8];
+        compressor.process(&mut data);
+        assert!(data[0] < 0.1);
+        assert_eq!(settle(&mut compressor, 0.2, 0.2), [0.2, 0.2]);
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use crate::notes::NoteNames;
+use crate::piano::Preset;
+use crate::synth::SettingValue;
+use crate::error::PianoError;
+
+
+/// Settings loaded from a TOML file
+///
+/// All fields are optional, command line options take precedence.
+///
+/// ```toml
+/// input = "0"                   # MIDI input port name or index, "NONE" to disable
+/// output_device = "default"     # audio output device name
+/// output_channels = [1, 2]      # device channels for left and right, 1-based
+/// sample_rate = 48000
+/// sound_font = "FluidR3_GM"     # file, or name searched in SoundFont directories
+/// preset = { bank = 0, num = 0 }
+/// gain = 1.5
+/// effects = true                # reverb and chorus
+/// preroll_ms = 50               # silence rendered when playback starts
+/// fade_in_ms = 50               # gain ramp when playback starts
+/// polyphony = 256
+/// pitch_bend_range = 2          # semitones
+/// mpe = false
+/// transpose = 0                 # semitones, for all channels
+/// middle_c_octave = 4           # from -2 to 8
+/// note_names = "english"        # or "german" (H for B), "solfege" (Do, Re, Mi)
+/// count_in_bars = 1             # metronome bars before playing a MIDI file
+/// keyboard_channel = 1          # channel played from the UI, saved when changed
+/// keymap = "qwerty"             # layout of keys playing notes, or "qwertz"
+///
+/// [settings]                    # FluidSynth settings
+/// "synth.reverb.room-size" = 0.6
+///
+/// [[favorites]]                 # favorite presets, can be repeated
+/// sound_font = "FluidR3_GM.sf2" # SoundFont file name, without directory
+/// bank = 0
+/// num = 4
+///
+/// [[bindings]]                  # controllers bound to UI controls, can be repeated
+/// control = "gain"              # or "stereo_width", "tempo_scale", "effects"
+/// channel = 1
+/// cc = 20
+/// ```
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub input: Option<String>,
+    pub output_device: Option<String>,
+    pub output_channels: Option<[usize; 2]>,
+    pub sample_rate: Option<u32>,
+    pub sound_font: Option<PathBuf>,
+    pub preset: Option<ConfigPreset>,
+    pub gain: Option<f32>,
+    pub effects: Option<bool>,
+    pub preroll_ms: Option<u64>,
+    pub fade_in_ms: Option<u64>,
+    pub polyphony: Option<u32>,
+    pub pitch_bend_range: Option<u8>,
+    pub mpe: Option<bool>,
+    pub transpose: Option<i8>,
+    pub middle_c_octave: Option<i8>,
+    pub note_names: Option<NoteNames>,
+    pub count_in_bars: Option<u32>,
+    /// MIDI channel, from 1 to 16
+    pub keyboard_channel: Option<u8>,
+    pub keymap: Option<KeyLayout>,
+    pub favorites: Vec<ConfigFavorite>,
+    pub bindings: Vec<ConfigBinding>,
+    settings: BTreeMap<String, toml::Value>,
+}
+
+/// Favorite preset of a SoundFont, in a config file
+#[derive(Deserialize, PartialEq, Eq, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFavorite {
+    /// File name of the SoundFont, without directory
+    pub sound_font: String,
+    pub bank: u32,
+    pub num: u32,
+}
+
+/// Favorite presets, saved to a config file when changed
+pub struct Favorites {
+    /// Config file to save favorites to, if any
+    path: Option<PathBuf>,
+    entries: Vec<ConfigFavorite>,
+}
+
+/// Layout of the computer keyboard, for keys playing notes
+#[derive(Deserialize, PartialEq, Eq, Copy, Clone, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyLayout {
+    #[default]
+    Qwerty,
+    /// As QWERTY, with Y and Z swapped
+    Qwertz,
+}
+
+/// State of the UI, saved to a config file when changed
+pub struct UiState {
+    /// Config file to save state to, if any
+    path: Option<PathBuf>,
+    keyboard_channel: wmidi::Channel,
+    /// Only read from the config file
+    key_layout: KeyLayout,
+}
+
+/// Parameter a MIDI controller can be bound to
+#[derive(Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BoundControl {
+    Gain,
+    StereoWidth,
+    TempoScale,
+    /// Reverb and chorus, enabled by values of 64 and above
+    Effects,
+}
+
+/// Controller bound to a parameter, in a config file
+#[derive(Deserialize, PartialEq, Eq, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigBinding {
+    pub control: BoundControl,
+    /// MIDI channel, from 1 to 16
+    pub channel: u8,
+    pub cc: u8,
+}
+
+/// Controllers bound to parameters, saved to a config file when changed
+pub struct Bindings {
+    /// Config file to save bindings to, if any
+    path: Option<PathBuf>,
+    entries: Vec<ConfigBinding>,
+}
+
+/// Preset to select, in a config file
+#[derive(Deserialize, Copy, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigPreset {
+    pub bank: u32,
+    pub num: u32,
+}
+
+impl Config {
+    /// Load a config file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, PianoError> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read config file {}", path.display()))?;
+        let config: Self = toml::from_str(&content)
+            .with_context(|| format!("invalid config file {}", path.display()))?;
+        if let Some(channels) = config.output_channels {
+            if channels.contains(&0) {
+                return Err(anyhow::anyhow!("invalid config file {}: output channel numbers start at 1", path.display()).into());
+            }
+        }
+        if config.middle_c_octave.is_some_and(|octave| !(-2..=8).contains(&octave)) {
+            return Err(anyhow::anyhow!("invalid config file {}: middle C octave must be between -2 and 8", path.display()).into());
+        }
+        if config.keyboard_channel.is_some_and(|channel| !(1..=16).contains(&channel)) {
+            return Err(anyhow::anyhow!("invalid config file {}: keyboard channel must be between 1 and 16", path.display()).into());
+        }
+        Ok(config)
+    }
+
+    /// Return output channels, 0-based
+    pub fn output_channels(&self) -> Option<[usize; 2]> {
+        self.output_channels.map(|[left, right]| [left - 1, right - 1])
+    }
+
+    /// Return FluidSynth settings, in key order
+    pub fn synth_settings(&self) -> Result<Vec<(String, SettingValue)>, PianoError> {
+        self.settings.iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    toml::Value::Integer(v) => SettingValue::Int(i32::try_from(*v).with_context(|| format!("setting value out of range: {}", key))?),
+                    toml::Value::Float(v) => SettingValue::Num(*v),
+                    toml::Value::String(v) => SettingValue::Str(v.clone()),
+                    toml::Value::Boolean(v) => SettingValue::Str(if *v { "yes" } else { "no" }.into()),
+                    _ => return Err(anyhow::anyhow!("invalid value type for setting {}", key).into()),
+                };
+                Ok((key.clone(), value))
+            })
+            .collect()
+    }
+}
+
+impl ConfigFavorite {
+    fn new(sfont: &Path, preset: Preset) -> Option<Self> {
+        let sound_font = sfont.file_name()?.to_str()?.to_owned();
+        Some(Self { sound_font, bank: preset.bank, num: preset.num })
+    }
+}
+
+impl Favorites {
+    /// Create favorites from a loaded config, saved to its file if given
+    pub fn new(path: Option<PathBuf>, config: &Config) -> Self {
+        Self { path, entries: config.favorites.clone() }
+    }
+
+    /// Return favorite presets of a SoundFont, in order
+    ///
+    /// Favorites are matched by file name, so that fonts can be moved.
+    pub fn presets(&self, sfont: &Path) -> Vec<Preset> {
+        let name = sfont.file_name().and_then(|name| name.to_str());
+        self.entries.iter()
+            .filter(|entry| Some(entry.sound_font.as_str()) == name)
+            .map(|entry| Preset { bank: entry.bank, num: entry.num })
+            .collect()
+    }
+
+    pub fn contains(&self, sfont: &Path, preset: Preset) -> bool {
+        self.presets(sfont).contains(&preset)
+    }
+
+    /// Add a preset to favorites, or remove it, then save them
+    ///
+    /// Without a config file, changes only last until exit.
+    pub fn toggle(&mut self, sfont: &Path, preset: Preset) -> Result<(), PianoError> {
+        let entry = ConfigFavorite::new(sfont, preset)
+            .with_context(|| format!("invalid SoundFont file name: {}", sfont.display()))?;
+        match self.entries.iter().position(|e| *e == entry) {
+            Some(pos) => { self.entries.remove(pos); }
+            None => self.entries.push(entry),
+        }
+        match self.path {
+            Some(ref path) => Ok(self.save(path)?),
+            None => Ok(()),
+        }
+    }
+
+    /// Replace favorites in a config file, keep the rest of it unchanged
+    fn save(&self, path: &Path) -> Result<()> {
+        let tables = self.entries.iter().map(|entry| {
+            let mut table = toml_edit::Table::new();
+            table["sound_font"] = toml_edit::value(entry.sound_font.as_str());
+            table["bank"] = toml_edit::value(entry.bank as i64);
+            table["num"] = toml_edit::value(entry.num as i64);
+            table
+        });
+        save_tables(path, "favorites", tables.collect())
+    }
+}
+
+impl UiState {
+    /// Create state from a loaded config, saved to its file if given
+    pub fn new(path: Option<PathBuf>, config: &Config) -> Self {
+        let keyboard_channel = config.keyboard_channel
+            .and_then(|number| wmidi::Channel::from_index(number.wrapping_sub(1)).ok())
+            .unwrap_or(wmidi::Channel::Ch1);
+        Self { path, keyboard_channel, key_layout: config.keymap.unwrap_or_default() }
+    }
+
+    /// Return the layout of keys playing notes
+    pub fn key_layout(&self) -> KeyLayout {
+        self.key_layout
+    }
+
+    /// Return the channel played from the UI
+    pub fn keyboard_channel(&self) -> wmidi::Channel {
+        self.keyboard_channel
+    }
+
+    /// Change the channel played from the UI, then save it
+    ///
+    /// Without a config file, changes only last until exit.
+    pub fn set_keyboard_channel(&mut self, channel: wmidi::Channel) -> Result<(), PianoError> {
+        self.keyboard_channel = channel;
+        match self.path {
+            Some(ref path) => Ok(save_item(path, "keyboard_channel", Some(toml_edit::value(channel.number() as i64)))?),
+            None => Ok(()),
+        }
+    }
+}
+
+impl BoundControl {
+    /// Return the name used in config files
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gain => "gain",
+            Self::StereoWidth => "stereo_width",
+            Self::TempoScale => "tempo_scale",
+            Self::Effects => "effects",
+        }
+    }
+}
+
+impl Bindings {
+    /// Create bindings from a loaded config, saved to its file if given
+    pub fn new(path: Option<PathBuf>, config: &Config) -> Self {
+        Self { path, entries: config.bindings.clone() }
+    }
+
+    /// Return the parameter a controller is bound to, if any
+    pub fn control(&self, channel: wmidi::Channel, cc: wmidi::ControlFunction) -> Option<BoundControl> {
+        self.entries.iter()
+            .find(|entry| entry.channel == channel.number() && entry.cc == u8::from(cc.0))
+            .map(|entry| entry.control)
+    }
+
+    /// Bind a controller to a parameter, then save bindings
+    ///
+    /// Previous bindings of the controller and of the parameter are replaced.
+    /// Without a config file, changes only last until exit.
+    pub fn bind(&mut self, control: BoundControl, channel: wmidi::Channel, cc: wmidi::ControlFunction) -> Result<(), PianoError> {
+        let entry = ConfigBinding { control, channel: channel.number(), cc: cc.0.into() };
+        self.entries.retain(|e| e.control != entry.control && (e.channel, e.cc) != (entry.channel, entry.cc));
+        self.entries.push(entry);
+        match self.path {
+            Some(ref path) => Ok(self.save(path)?),
+            None => Ok(()),
+        }
+    }
+
+    /// Replace bindings in a config file, keep the rest of it unchanged
+    fn save(&self, path: &Path) -> Result<()> {
+        let tables = self.entries.iter().map(|entry| {
+            let mut table = toml_edit::Table::new();
+            table["control"] = toml_edit::value(entry.control.name());
+            table["channel"] = toml_edit::value(entry.channel as i64);
+            table["cc"] = toml_edit::value(entry.cc as i64);
+            table
+        });
+        save_tables(path, "bindings", tables.collect())
+    }
+}
+
+/// Replace an array of tables in a config file, keep the rest of it unchanged
+///
+/// The array is removed if there is no table.
+fn save_tables(path: &Path, key: &str, tables: toml_edit::ArrayOfTables) -> Result<()> {
+    let item = (!tables.is_empty()).then(|| toml_edit::Item::ArrayOfTables(tables));
+    save_item(path, key, item)
+}
+
+/// Replace a top-level item of a config file, keep the rest of it unchanged
+///
+/// The item is removed if `None`.
+fn save_item(path: &Path, key: &str, item: Option<toml_edit::Item>) -> Result<()> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err).with_context(|| format!("cannot read config file {}", path.display())),
+    };
+    let mut doc: toml_edit::DocumentMut = content.parse()
+        .with_context(|| format!("invalid config file {}", path.display()))?;
+    match item {
+        Some(item) => doc[key] = item,
+        None => { doc.remove(key); }
+    }
+    std::fs::write(path, doc.to_string())
+        .with_context(|| format!("cannot write config file {}", path.display()))
+}
+
+impl From<ConfigPreset> for Preset {
+    fn from(o: ConfigPreset) -> Self {
+        Self { bank: o.bank, num: o.num }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::{Config, KeyLayout, UiState};
+
+    /// Write a config file in the temporary directory, return its path
+    fn config_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("pianote-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn keyboard_channel_saved() {
+        let path = config_file("keyboard-channel", "gain = 1.5\n\n[[favorites]]\nsound_font = \"a.sf2\"\nbank = 0\nnum = 4\n");
+        let config = Config::load(&path).unwrap();
+        let mut state = UiState::new(Some(path.clone()), &config);
+        assert_eq!(state.keyboard_channel(), wmidi::Channel::Ch1);
+        state.set_keyboard_channel(wmidi::Channel::Ch3).unwrap();
+
+        let config = Config::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.keyboard_channel, Some(3));
+        assert_eq!(config.gain, Some(1.5));
+        assert_eq!(config.favorites.len(), 1);
+        assert_eq!(UiState::new(None, &config).keyboard_channel(), wmidi::Channel::Ch3);
+    }
+
+    #[test]
+    fn keymap() {
+        let config: Config = toml::from_str("keymap = \"qwertz\"").unwrap();
+        assert_eq!(UiState::new(None, &config).key_layout(), KeyLayout::Qwertz);
+        assert_eq!(UiState::new(None, &Config::default()).key_layout(), KeyLayout::Qwerty);
+        assert!(toml::from_str::<Config>("keymap = \"dvorak\"").is_err());
+    }
+
+    #[test]
+    fn invalid_keyboard_channel() {
+        let path = config_file("invalid-keyboard-channel", "keyboard_channel = 17\n");
+        let result = Config::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn invalid_middle_c_octave() {
+        let path = config_file("invalid-middle-c-octave", "middle_c_octave = 120\n");
+        let result = Config::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
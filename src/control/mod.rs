@@ -0,0 +1,425 @@
+//! Control server, to drive a `Piano` from a local socket
+//!
+//! The protocol is line-based. Each line is a command, answered with a line
+//! starting with `ok` or `error`.
+//!
+//! ```text
+//! note_on <channel> <note> [<velocity>]
+//! note_off <channel> <note>
+//! cc <channel> <controller> <value>
+//! gain <gain>
+//! reverb on|off
+//! chorus on|off
+//! preset <bank> <num>
+//! get gain|reverb|chorus
+//! ```
+//!
+//! Channels are numbered from 1 to 16. Notes are given as MIDI numbers or
+//! names (e.g. `C#4`). The `get` command answers `ok <value>`.
+//!
+//! With the `osc` feature, commands can also be received as OSC messages.
+
+use std::cell::RefCell;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use anyhow::{Context, Result};
+use crate::notes::NoteNaming;
+use crate::piano::{Piano, Preset};
+use crate::error::PianoError;
+
+#[cfg(feature = "osc")]
+mod osc;
+
+
+/// Command sent to the control server
+#[derive(PartialEq, Clone, Debug)]
+pub enum ControlCommand {
+    NoteOn { channel: wmidi::Channel, note: wmidi::Note, velocity: wmidi::U7 },
+    NoteOff { channel: wmidi::Channel, note: wmidi::Note },
+    ControlChange { channel: wmidi::Channel, ctrl: wmidi::ControlFunction, value: wmidi::U7 },
+    Gain(f32),
+    Reverb(bool),
+    Chorus(bool),
+    Preset(Preset),
+    /// Read back a parameter value
+    Get(ControlParam),
+}
+
+/// Parameter which can be read back
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum ControlParam {
+    Gain,
+    Reverb,
+    Chorus,
+}
+
+/// Value of a parameter, returned by a `get` command
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum ControlValue {
+    Float(f32),
+    Bool(bool),
+}
+
+/// Command with the queue to send its result to
+struct ControlRequest {
+    command: ControlCommand,
+    reply: Sender<Result<Option<ControlValue>, PianoError>>,
+}
+
+/// Server receiving control commands from its listeners
+pub struct ControlServer {
+    requests_tx: Sender<ControlRequest>,
+    requests: Receiver<ControlRequest>,
+    naming: NoteNaming,
+    /// Unix socket files created by listeners, removed on drop
+    socket_paths: RefCell<Vec<PathBuf>>,
+}
+
+impl ControlCommand {
+    /// Parse a command line
+    pub fn parse(line: &str, naming: &NoteNaming) -> Result<Self, PianoError> {
+        let mut args = line.split_whitespace();
+        let name = args.next().context("empty command")?;
+        let mut next_arg = |what: &str| args.next().with_context(|| format!("missing {}", what));
+        let command = match name {
+            "note_on" => Self::NoteOn {
+                channel: parse_channel(next_arg("channel")?)?,
+                note: parse_note(next_arg("note")?, naming)?,
+                velocity: match args.next() {
+                    Some(s) => parse_u7(s)?,
+                    None => wmidi::U7::MAX,
+                },
+            },
+            "note_off" => Self::NoteOff {
+                channel: parse_channel(next_arg("channel")?)?,
+                note: parse_note(next_arg("note")?, naming)?,
+            },
+            "cc" => Self::ControlChange {
+                channel: parse_channel(next_arg("channel")?)?,
+                ctrl: parse_u7(next_arg("controller")?)?.into(),
+                value: parse_u7(next_arg("value")?)?,
+            },
+            "gain" => Self::Gain(check_gain(next_arg("gain")?.parse().context("invalid gain")?)?),
+            "reverb" => Self::Reverb(parse_on_off(next_arg("on or off")?)?),
+            "chorus" => Self::Chorus(parse_on_off(next_arg("on or off")?)?),
+            "get" => Self::Get(next_arg("parameter")?.parse()?),
+            "preset" => Self::Preset(Preset {
+                bank: next_arg("bank")?.parse().context("invalid bank")?,
+                num: next_arg("num")?.parse().context("invalid preset number")?,
+            }),
+            _ => return Err(anyhow::anyhow!("unknown command: {}", name).into()),
+        };
+        if args.next().is_some() {
+            return Err(anyhow::anyhow!("too many arguments").into());
+        }
+        Ok(command)
+    }
+
+    /// Apply the command to a piano, return the value of read parameters
+    pub fn apply(&self, piano: &mut Piano) -> Result<Option<ControlValue>, PianoError> {
+        match *self {
+            Self::NoteOn { channel, note, velocity } => piano.note_on(channel, note, velocity)?,
+            Self::NoteOff { channel, note } => piano.note_off(channel, note)?,
+            Self::ControlChange { channel, ctrl, value } => {
+                piano.send_message(wmidi::MidiMessage::ControlChange(channel, ctrl, value))?
+            }
+            Self::Gain(gain) => piano.set_gain(gain),
+            Self::Reverb(enabled) => piano.set_reverb(enabled)?,
+            Self::Chorus(enabled) => piano.set_chorus(enabled)?,
+            Self::Preset(preset) => piano.set_active_preset(preset)?,
+            Self::Get(param) => return Ok(Some(param.get(piano))),
+        }
+        Ok(None)
+    }
+}
+
+impl ControlParam {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gain => "gain",
+            Self::Reverb => "reverb",
+            Self::Chorus => "chorus",
+        }
+    }
+
+    /// Return the current value of the parameter
+    pub fn get(&self, piano: &Piano) -> ControlValue {
+        match self {
+            Self::Gain => ControlValue::Float(piano.gain()),
+            Self::Reverb => ControlValue::Bool(piano.reverb()),
+            Self::Chorus => ControlValue::Bool(piano.chorus()),
+        }
+    }
+}
+
+impl std::str::FromStr for ControlParam {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        [Self::Gain, Self::Reverb, Self::Chorus].into_iter()
+            .find(|param| param.name() == s)
+            .with_context(|| format!("unknown parameter: {}", s))
+    }
+}
+
+impl std::fmt::Display for ControlValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Float(v) => write!(f, "{}", v),
+            Self::Bool(v) => f.write_str(if *v { "on" } else { "off" }),
+        }
+    }
+}
+
+impl ControlServer {
+    pub fn new(naming: NoteNaming) -> Self {
+        let (tx, rx) = mpsc::channel();
+        Self { requests_tx: tx, requests: rx, naming, socket_paths: RefCell::new(vec![]) }
+    }
+
+    /// Listen on a local socket
+    ///
+    /// The address is either a loopback TCP address (e.g. `127.0.0.1:7000`)
+    /// or, on Unix, the path of a Unix socket. A socket file left by a server
+    /// which is no longer running is replaced, the file is removed on drop.
+    pub fn listen(&self, addr: &str) -> Result<(), PianoError> {
+        let tx = self.requests_tx.clone();
+        let naming = self.naming;
+
+        if let Ok(socket_addr) = addr.parse::<SocketAddr>() {
+            if !socket_addr.ip().is_loopback() {
+                return Err(anyhow::anyhow!("control socket must be bound to a loopback address: {}", addr).into());
+            }
+            let listener = TcpListener::bind(socket_addr)
+                .with_context(|| format!("cannot listen on {}", addr))?;
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    if let Ok(writer) = stream.try_clone() {
+                        Self::spawn_client(stream, writer, tx.clone(), naming);
+                    }
+                }
+            });
+        } else {
+            #[cfg(unix)]
+            {
+                Self::remove_stale_socket(std::path::Path::new(addr))?;
+                let listener = std::os::unix::net::UnixListener::bind(addr)
+                    .with_context(|| format!("cannot listen on {}", addr))?;
+                self.socket_paths.borrow_mut().push(addr.into());
+                std::thread::spawn(move || {
+                    for stream in listener.incoming().flatten() {
+                        if let Ok(writer) = stream.try_clone() {
+                            Self::spawn_client(stream, writer, tx.clone(), naming);
+                        }
+                    }
+                });
+            }
+            #[cfg(not(unix))]
+            return Err(anyhow::anyhow!("invalid control socket address: {}", addr).into());
+        }
+
+        Ok(())
+    }
+
+    /// Remove a Unix socket file if no server listens on it anymore
+    #[cfg(unix)]
+    fn remove_stale_socket(path: &std::path::Path) -> Result<()> {
+        use std::os::unix::fs::FileTypeExt;
+
+        let is_socket = std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket());
+        if !is_socket {
+            return Ok(());
+        }
+        match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => anyhow::bail!("control socket already in use: {}", path.display()),
+            Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {
+                std::fs::remove_file(path).with_context(|| format!("cannot remove stale control socket {}", path.display()))
+            }
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Apply received commands to a piano, forever
+    pub fn run(&self, piano: &mut Piano) -> Result<(), PianoError> {
+        for request in self.requests.iter() {
+            // Client may have disconnected, ignore errors
+            let _ = request.reply.send(request.command.apply(piano));
+        }
+        Err(anyhow::anyhow!("control server stopped").into())
+    }
+
+    /// Send a command to be applied, wait for its result
+    fn request(requests: &Sender<ControlRequest>, command: ControlCommand) -> Result<Option<ControlValue>, PianoError> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        requests.send(ControlRequest { command, reply: reply_tx })
+            .map_err(|_| anyhow::anyhow!("control server stopped"))?;
+        reply_rx.recv().context("control server did not reply")?
+    }
+
+    /// Handle commands from a client in a new thread
+    fn spawn_client<R, W>(reader: R, mut writer: W, requests: Sender<ControlRequest>, naming: NoteNaming)
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        std::thread::spawn(move || {
+            for line in BufReader::new(reader).lines() {
+                let Ok(line) = line else { break };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let result = ControlCommand::parse(&line, &naming)
+                    .and_then(|command| Self::request(&requests, command));
+                let response = match result {
+                    Ok(None) => "ok".to_string(),
+                    Ok(Some(value)) => format!("ok {}", value),
+                    Err(err) => format!("error {}", err),
+                };
+                if writeln!(writer, "{}", response).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        for path in self.socket_paths.get_mut().drain(..) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+
+fn parse_channel(s: &str) -> Result<wmidi::Channel> {
+    channel_from_number(s.parse().context("invalid channel")?)
+}
+
+fn parse_note(s: &str, naming: &NoteNaming) -> Result<wmidi::Note> {
+    match s.parse::<i64>() {
+        Ok(value) => note_from_int(value),
+        Err(_) => Ok(naming.parse_note(s)?),
+    }
+}
+
+fn parse_on_off(s: &str) -> Result<bool> {
+    match s {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => anyhow::bail!("expected on or off: {}", s),
+    }
+}
+
+fn parse_u7(s: &str) -> Result<wmidi::U7> {
+    u7_from_int(s.parse().with_context(|| format!("invalid value: {}", s))?)
+}
+
+/// Get a channel from its number, from 1 to 16
+fn channel_from_number(number: i64) -> Result<wmidi::Channel> {
+    number.checked_sub(1)
+        .and_then(|index| u8::try_from(index).ok())
+        .and_then(|index| wmidi::Channel::from_index(index).ok())
+        .with_context(|| format!("invalid channel: {}", number))
+}
+
+/// Check a gain value is finite and not negative
+fn check_gain(gain: f32) -> Result<f32> {
+    anyhow::ensure!(gain.is_finite() && gain >= 0.0, "invalid gain: {}", gain);
+    Ok(gain)
+}
+
+fn note_from_int(value: i64) -> Result<wmidi::Note> {
+    u8::try_from(value).ok()
+        .and_then(|value| wmidi::Note::try_from(value).ok())
+        .with_context(|| format!("invalid note: {}", value))
+}
+
+fn u7_from_int(value: i64) -> Result<wmidi::U7> {
+    u8::try_from(value).ok()
+        .and_then(|value| wmidi::U7::try_from(value).ok())
+        .with_context(|| format!("value out of range: {}", value))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use wmidi::{Channel, ControlFunction, Note, U7};
+    use crate::notes::NoteNaming;
+    use crate::piano::Preset;
+    use super::{ControlCommand, ControlParam, ControlServer};
+
+    fn parse(line: &str) -> Result<ControlCommand, String> {
+        ControlCommand::parse(line, &NoteNaming::default()).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(parse("note_on 1 60 100"), Ok(ControlCommand::NoteOn { channel: Channel::Ch1, note: Note::C4, velocity: U7::from_u8_lossy(100) }));
+        assert_eq!(parse("note_on 16 C#4"), Ok(ControlCommand::NoteOn { channel: Channel::Ch16, note: Note::Db4, velocity: U7::MAX }));
+        assert_eq!(parse("  note_off  2 A0 "), Ok(ControlCommand::NoteOff { channel: Channel::Ch2, note: Note::A0 }));
+        assert_eq!(parse("cc 1 64 127"), Ok(ControlCommand::ControlChange { channel: Channel::Ch1, ctrl: ControlFunction::DAMPER_PEDAL, value: U7::MAX }));
+        assert_eq!(parse("gain 0.5"), Ok(ControlCommand::Gain(0.5)));
+        assert_eq!(parse("gain 0"), Ok(ControlCommand::Gain(0.0)));
+        assert_eq!(parse("reverb on"), Ok(ControlCommand::Reverb(true)));
+        assert_eq!(parse("chorus off"), Ok(ControlCommand::Chorus(false)));
+        assert_eq!(parse("preset 128 3"), Ok(ControlCommand::Preset(Preset { bank: 128, num: 3 })));
+        assert_eq!(parse("get gain"), Ok(ControlCommand::Get(ControlParam::Gain)));
+        assert_eq!(parse("get chorus"), Ok(ControlCommand::Get(ControlParam::Chorus)));
+    }
+
+    #[test]
+    fn parse_invalid_commands() {
+        for line in [
+            "",
+            "play 1 60",
+            "note_on",
+            "note_on 1",
+            "note_on 0 60",
+            "note_on 17 60",
+            "note_on -9223372036854775808 60",
+            "note_on x 60",
+            "note_on 1 128",
+            "note_on 1 -1",
+            "note_on 1 H4",
+            "note_on 1 60 128",
+            "note_on 1 60 100 1",
+            "note_off 1 G#9",
+            "cc 1 128 0",
+            "cc 1 64",
+            "gain nan",
+            "gain inf",
+            "gain -1",
+            "gain loud",
+            "reverb yes",
+            "chorus",
+            "preset 0",
+            "get volume",
+        ] {
+            assert!(parse(line).is_err(), "{:?}", line);
+        }
+        assert_eq!(parse("note_on -9223372036854775808 60"), Err("invalid channel: -9223372036854775808".into()));
+        assert_eq!(parse("gain NaN"), Err("invalid gain: NaN".into()));
+        assert_eq!(parse("reverb 1"), Err("expected on or off: 1".into()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_file() {
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("pianote-control-{}.sock", std::process::id()));
+        // Left by a server which is no longer running
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let server = ControlServer::new(NoteNaming::default());
+        server.listen(path.to_str().unwrap()).unwrap();
+        // In use by the first server
+        assert!(ControlServer::new(NoteNaming::default()).listen(path.to_str().unwrap()).is_err());
+        drop(server);
+        assert!(!path.exists());
+    }
+}
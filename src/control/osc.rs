@@ -0,0 +1,253 @@
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::mpsc::Sender;
+use anyhow::{Context, Result};
+use rosc::{OscMessage, OscPacket, OscType};
+use crate::notes::NoteNaming;
+use crate::piano::Preset;
+use crate::error::PianoError;
+use super::{ControlCommand, ControlRequest, ControlServer, ControlValue, channel_from_number, check_gain, note_from_int, u7_from_int};
+
+
+impl ControlCommand {
+    /// Convert an OSC message to a command
+    ///
+    /// Supported messages:
+    ///
+    /// ```text
+    /// /note <channel> <note> <velocity>  (velocity 0 stops the note)
+    /// /cc <channel> <controller> <value>
+    /// /gain <gain>
+    /// /reverb <on>
+    /// /chorus <on>
+    /// /preset <bank> <num>
+    /// /get <parameter>  (gain, reverb or chorus)
+    /// ```
+    ///
+    /// Notes can be given as MIDI numbers or names. Booleans can also be given
+    /// as integers.
+    pub fn from_osc(message: &OscMessage, naming: &NoteNaming) -> Result<Self, PianoError> {
+        let expected_args = match message.addr.as_str() {
+            "/note" | "/cc" => 3,
+            "/preset" => 2,
+            "/gain" | "/reverb" | "/chorus" | "/get" => 1,
+            _ => return Err(anyhow::anyhow!("unknown OSC address: {}", message.addr).into()),
+        };
+        if message.args.len() != expected_args {
+            return Err(anyhow::anyhow!("invalid argument count for {}: {} instead of {}", message.addr, message.args.len(), expected_args).into());
+        }
+
+        let args = &message.args;
+        let command = match message.addr.as_str() {
+            "/note" => {
+                let channel = channel_from_number(osc_int(&args[0])?)?;
+                let note = match &args[1] {
+                    OscType::String(s) => naming.parse_note(s)?,
+                    arg => note_from_int(osc_int(arg)?)?,
+                };
+                let velocity = u7_from_int(osc_int(&args[2])?)?;
+                if u8::from(velocity) == 0 {
+                    Self::NoteOff { channel, note }
+                } else {
+                    Self::NoteOn { channel, note, velocity }
+                }
+            }
+            "/cc" => Self::ControlChange {
+                channel: channel_from_number(osc_int(&args[0])?)?,
+                ctrl: u7_from_int(osc_int(&args[1])?)?.into(),
+                value: u7_from_int(osc_int(&args[2])?)?,
+            },
+            "/gain" => Self::Gain(check_gain(osc_float(&args[0])?)?),
+            "/reverb" => Self::Reverb(osc_bool(&args[0])?),
+            "/chorus" => Self::Chorus(osc_bool(&args[0])?),
+            "/get" => match &args[0] {
+                OscType::String(s) => Self::Get(s.parse()?),
+                arg => return Err(anyhow::anyhow!("expected a parameter name, got {:?}", arg).into()),
+            },
+            "/preset" => Self::Preset(Preset {
+                bank: u32::try_from(osc_int(&args[0])?).context("invalid bank")?,
+                num: u32::try_from(osc_int(&args[1])?).context("invalid preset number")?,
+            }),
+            _ => unreachable!(),
+        };
+        Ok(command)
+    }
+}
+
+impl ControlServer {
+    /// Listen for OSC messages on an UDP address (e.g. `127.0.0.1:9000`)
+    ///
+    /// A port alone listens on the loopback address. Messages are not
+    /// authenticated: a warning is printed if the address is reachable from
+    /// the network.
+    ///
+    /// Errors are printed. Values read by `/get` are sent back to the sender,
+    /// as a message with the parameter name as address (e.g. `/gain 1.5`).
+    pub fn listen_osc(&self, addr: &str) -> Result<(), PianoError> {
+        let socket = Self::bind_osc(addr)?;
+        let requests = self.requests_tx.clone();
+        let naming = self.naming;
+
+        std::thread::spawn(move || {
+            let mut buf = [0u8; rosc::decoder::MTU];
+            loop {
+                let (size, from) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(err) => {
+                        eprintln!("failed to receive OSC packet: {}", err);
+                        continue;
+                    }
+                };
+                match rosc::decoder::decode_udp(&buf[..size]) {
+                    Ok((_, packet)) => Self::handle_osc_packet(&requests, &naming, packet, &socket, from),
+                    Err(err) => eprintln!("invalid OSC packet: {}", err),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Bind an UDP socket for OSC, on loopback if only a port is given
+    fn bind_osc(addr: &str) -> Result<UdpSocket> {
+        let socket = match addr.parse::<u16>() {
+            Ok(port) => UdpSocket::bind((Ipv4Addr::LOCALHOST, port)),
+            Err(_) => UdpSocket::bind(addr),
+        }.with_context(|| format!("cannot listen for OSC on {}", addr))?;
+        let local_addr = socket.local_addr()?;
+        if !local_addr.ip().is_loopback() {
+            eprintln!("warning: OSC messages are accepted from the network on {}, anyone reaching it can control the piano", local_addr);
+        }
+        Ok(socket)
+    }
+
+    fn handle_osc_packet(requests: &Sender<ControlRequest>, naming: &NoteNaming, packet: OscPacket, socket: &UdpSocket, from: SocketAddr) {
+        match packet {
+            OscPacket::Message(message) => {
+                let result = ControlCommand::from_osc(&message, naming)
+                    .and_then(|command| {
+                        let param = if let ControlCommand::Get(param) = command { Some(param) } else { None };
+                        match (param, Self::request(requests, command)?) {
+                            (Some(param), Some(value)) => Ok(Self::send_osc_value(socket, from, param.name(), value)?),
+                            _ => Ok(()),
+                        }
+                    });
+                if let Err(err) = result {
+                    eprintln!("failed to handle OSC message {}: {}", message.addr, err);
+                }
+            }
+            OscPacket::Bundle(bundle) => {
+                for packet in bundle.content {
+                    Self::handle_osc_packet(requests, naming, packet, socket, from);
+                }
+            }
+        }
+    }
+
+    /// Send a parameter value back to an OSC client
+    fn send_osc_value(socket: &UdpSocket, to: SocketAddr, name: &str, value: ControlValue) -> Result<()> {
+        let arg = match value {
+            ControlValue::Float(v) => OscType::Float(v),
+            ControlValue::Bool(v) => OscType::Bool(v),
+        };
+        let packet = OscPacket::Message(OscMessage { addr: format!("/{}", name), args: vec![arg] });
+        let data = rosc::encoder::encode(&packet)?;
+        socket.send_to(&data, to).context("cannot send OSC reply")?;
+        Ok(())
+    }
+}
+
+
+fn osc_int(arg: &OscType) -> Result<i64> {
+    match *arg {
+        OscType::Int(v) => Ok(v.into()),
+        OscType::Long(v) => Ok(v),
+        _ => anyhow::bail!("expected an integer OSC argument, got {:?}", arg),
+    }
+}
+
+fn osc_bool(arg: &OscType) -> Result<bool> {
+    match *arg {
+        OscType::Bool(v) => Ok(v),
+        OscType::Int(v) => Ok(v != 0),
+        _ => anyhow::bail!("expected a boolean OSC argument, got {:?}", arg),
+    }
+}
+
+fn osc_float(arg: &OscType) -> Result<f32> {
+    match *arg {
+        OscType::Float(v) => Ok(v),
+        OscType::Double(v) => Ok(v as f32),
+        OscType::Int(v) => Ok(v as f32),
+        _ => anyhow::bail!("expected a numeric OSC argument, got {:?}", arg),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rosc::{OscMessage, OscType};
+    use wmidi::{Channel, ControlFunction, Note, U7};
+    use crate::notes::NoteNaming;
+    use crate::piano::Preset;
+    use crate::control::{ControlCommand, ControlParam, ControlServer};
+
+    fn from_osc(addr: &str, args: Vec<OscType>) -> Result<ControlCommand, String> {
+        let message = OscMessage { addr: addr.into(), args };
+        ControlCommand::from_osc(&message, &NoteNaming::default()).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn osc_commands() {
+        use OscType::*;
+        assert_eq!(from_osc("/note", vec![Int(1), Int(60), Int(100)]), Ok(ControlCommand::NoteOn { channel: Channel::Ch1, note: Note::C4, velocity: U7::from_u8_lossy(100) }));
+        assert_eq!(from_osc("/note", vec![Long(16), String("A0".into()), Int(0)]), Ok(ControlCommand::NoteOff { channel: Channel::Ch16, note: Note::A0 }));
+        assert_eq!(from_osc("/cc", vec![Int(2), Int(64), Int(127)]), Ok(ControlCommand::ControlChange { channel: Channel::Ch2, ctrl: ControlFunction::DAMPER_PEDAL, value: U7::MAX }));
+        assert_eq!(from_osc("/gain", vec![Float(0.5)]), Ok(ControlCommand::Gain(0.5)));
+        assert_eq!(from_osc("/gain", vec![Double(2.0)]), Ok(ControlCommand::Gain(2.0)));
+        assert_eq!(from_osc("/gain", vec![Int(1)]), Ok(ControlCommand::Gain(1.0)));
+        assert_eq!(from_osc("/reverb", vec![Bool(true)]), Ok(ControlCommand::Reverb(true)));
+        assert_eq!(from_osc("/chorus", vec![Int(0)]), Ok(ControlCommand::Chorus(false)));
+        assert_eq!(from_osc("/preset", vec![Int(128), Int(3)]), Ok(ControlCommand::Preset(Preset { bank: 128, num: 3 })));
+        assert_eq!(from_osc("/get", vec![String("reverb".into())]), Ok(ControlCommand::Get(ControlParam::Reverb)));
+    }
+
+    #[test]
+    fn invalid_osc_commands() {
+        use OscType::*;
+        assert_eq!(from_osc("/play", vec![]), Err("unknown OSC address: /play".into()));
+        assert_eq!(from_osc("/note", vec![Int(1), Int(60)]), Err("invalid argument count for /note: 2 instead of 3".into()));
+        assert_eq!(from_osc("/gain", vec![]), Err("invalid argument count for /gain: 0 instead of 1".into()));
+        assert_eq!(from_osc("/note", vec![Long(i64::MIN), Int(60), Int(100)]), Err(format!("invalid channel: {}", i64::MIN)));
+        for (addr, args) in [
+            ("/note", vec![Int(0), Int(60), Int(100)]),
+            ("/note", vec![Float(1.0), Int(60), Int(100)]),
+            ("/note", vec![Int(1), Int(128), Int(100)]),
+            ("/note", vec![Int(1), String("X4".into()), Int(100)]),
+            ("/note", vec![Int(1), Int(60), Int(128)]),
+            ("/cc", vec![Int(1), Int(-1), Int(0)]),
+            ("/gain", vec![Float(f32::NAN)]),
+            ("/gain", vec![Double(f64::INFINITY)]),
+            ("/gain", vec![Float(-1.0)]),
+            ("/gain", vec![String("1".into())]),
+            ("/reverb", vec![Float(1.0)]),
+            ("/preset", vec![Int(-1), Int(0)]),
+            ("/get", vec![Int(0)]),
+            ("/get", vec![String("volume".into())]),
+        ] {
+            assert!(from_osc(addr, args.clone()).is_err(), "{} {:?}", addr, args);
+        }
+    }
+
+    #[test]
+    fn bind_port_on_loopback() {
+        let socket = ControlServer::bind_osc("0").unwrap();
+        assert!(socket.local_addr().unwrap().ip().is_loopback());
+    }
+
+    #[test]
+    fn bind_address() {
+        let socket = ControlServer::bind_osc("127.0.0.1:0").unwrap();
+        assert!(socket.local_addr().unwrap().ip().is_loopback());
+        assert!(ControlServer::bind_osc("not an address").is_err());
+    }
+}
@@ -0,0 +1,1086 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender, SyncSender, TrySendError};
+use anyhow::Result;
+use fluidlite::{IsFont, IsPreset};
+use crate::audio::TestTone;
+use crate::compressor::{CompConfig, Compressor};
+use crate::midi::{ControlEvent, MidiMessage, NoteEvent};
+use crate::error::PianoError;
+use crate::piano::{Preset, PresetData};
+use crate::processor::{MidiProcessor, MonoConfig};
+use crate::synth::{SettingValue, Synth, SynthBackend};
+use crate::visualizer::{AudioBlock, BLOCK_SIZE};
+
+
+/// Command sent to the engine
+///
+/// Commands with a `reply` field send back their result.
+pub enum EngineCommand {
+    SetPolyphony { polyphony: u32, reply: Sender<Result<()>> },
+    SetZeroVelocityNoteOff(bool),
+    SetPitchBendRange { semitones: u8, reply: Sender<Result<()>> },
+    SelectPreset { channels: &'static [wmidi::Channel], preset: Preset, release_notes: bool, reply: Sender<Result<()>> },
+    /// Presets of all channels, `None` if not from the loaded font
+    GetPresets { reply: Sender<Result<[Option<Preset>; 16]>> },
+    SetPlaying(bool),
+    ScheduleMessage(Instant, MidiMessage),
+    SetIdleTimeout(Option<Duration>),
+    SetPreroll(Duration),
+    SetFadeIn(Duration),
+    SetCompressor(Option<CompConfig>),
+    SetTestTone(Option<TestTone>),
+    SetInputGain(f32),
+    SetAftertouchRouting(Option<wmidi::ControlFunction>),
+    SetVelocityRouting(Option<(wmidi::ControlFunction, f32)>),
+    SetReleaseVelocityRouting(Option<wmidi::ControlFunction>),
+    SetFreeze(bool),
+    SetReverb(bool),
+    SetChorus(bool),
+    SetMpe(bool),
+    SetTranspose(Option<wmidi::Channel>, i8),
+    SetMonoMode(wmidi::Channel, MonoConfig),
+    SetPortamento(wmidi::Channel, Option<Duration>),
+    SetChannelMute(wmidi::Channel, bool),
+    SetSolo(Option<wmidi::Channel>),
+    AddNoteListener(SyncSender<NoteEvent>),
+    AddControlListener(SyncSender<ControlEvent>),
+    AddAudioListener(SyncSender<AudioBlock>),
+}
+
+/// Engine state shared with other threads, without locking
+pub struct EngineShared {
+    /// Synth gain (`f32` bits)
+    pub gain: AtomicU32,
+    /// Gain compensating the loudness of the active preset (`f32` bits)
+    pub preset_gain: AtomicU32,
+    /// Stereo width (`f32` bits)
+    pub stereo_width: AtomicU32,
+    /// Preset of the first channel, bank in high bits, `u64::MAX` if none
+    pub active_preset: AtomicU64,
+    /// Number of rendered buffers, wrapping
+    pub render_count: AtomicUsize,
+    /// Set on panic, output is silent from then on
+    pub silenced: AtomicBool,
+    /// Held input notes, one bit per note, 128 bits per channel
+    held_notes: [AtomicU64; 32],
+    /// Last controller values sent to the synth, 128 per channel, `u8::MAX` if none
+    cc_values: [AtomicU8; 16 * 128],
+}
+
+/// Audio engine, owned by the audio thread
+///
+/// The engine owns the synth and all state used to render samples.
+/// Other threads drive it by sending commands, applied on next render.
+/// Operations too heavy for the audio thread (loading a font, changing a
+/// setting, rebuilding the synth) are run by the caller with the engine
+/// locked; the audio thread outputs silence while it cannot lock it.
+pub struct Engine {
+    synth: Synth,
+    /// Whether the synth has been created with effects, to rebuild it
+    effects: bool,
+    /// Synth state set by commands, to restore it when the synth is rebuilt
+    synth_state: SynthState,
+    processor: MidiProcessor,
+    /// Queue of input MIDI messages
+    midi_rx: Receiver<MidiMessage>,
+    /// Messages to process at a given time, sorted by time
+    scheduled: VecDeque<(Instant, MidiMessage)>,
+    /// Queue of commands to apply
+    commands: Receiver<EngineCommand>,
+    shared: Arc<EngineShared>,
+    /// Gain currently applied to the synth
+    current_gain: f32,
+    /// Queues receiving a copy of processed note events
+    note_listeners: Vec<SyncSender<NoteEvent>>,
+    /// Queues receiving a copy of input control changes, before processing
+    control_listeners: Vec<SyncSender<ControlEvent>>,
+    /// Queues receiving a copy of output samples, mixed to mono
+    audio_listeners: Vec<SyncSender<AudioBlock>>,
+    /// Block of samples being filled for audio listeners, and its length
+    audio_block: (AudioBlock, usize),
+    /// Test tone to output instead of synth samples, if any
+    test_tone: Option<TestTone>,
+    /// Currently loaded and active FontId
+    sfont_id: Option<fluidlite::FontId>,
+    /// Bank selected on each channel
+    banks: Banks,
+    /// Pitch bend range, restored after synth resets
+    pitch_bend_range: Option<u8>,
+    /// Output sample rate
+    sample_rate: f64,
+    /// Compressor applied on output, if enabled, and its parameters
+    compressor: Option<(CompConfig, Compressor)>,
+    /// Number of silent frames after which synthesis is skipped
+    idle_timeout: Option<usize>,
+    /// Number of frames output since the last sound or input message
+    silent_frames: usize,
+    /// Whether synth samples are output
+    playing: bool,
+    /// Set when pausing, to render a last buffer after stopping voices
+    pausing: bool,
+    /// Number of frames rendered silently when playback starts
+    preroll: usize,
+    /// Number of pre-roll frames left to render
+    preroll_frames: usize,
+    /// Number of frames over which output gain ramps up when playback starts
+    fade_in: usize,
+    /// Number of frames output since the start of the current ramp
+    fade_in_pos: usize,
+}
+
+/// Synth state changed by commands, which is lost when the synth is rebuilt
+#[derive(Default)]
+struct SynthState {
+    sfont_path: Option<PathBuf>,
+    /// Settings, in the order they have been applied
+    settings: Vec<(String, SettingValue)>,
+    polyphony: Option<u32>,
+    reverb: Option<bool>,
+    zero_velocity_note_off: Option<bool>,
+    chorus: Option<bool>,
+}
+
+impl EngineShared {
+    pub fn new(gain: f32) -> Self {
+        Self {
+            gain: AtomicU32::new(gain.to_bits()),
+            preset_gain: AtomicU32::new(1f32.to_bits()),
+            stereo_width: AtomicU32::new(1f32.to_bits()),
+            active_preset: AtomicU64::new(u64::MAX),
+            render_count: AtomicUsize::new(0),
+            silenced: AtomicBool::new(false),
+            held_notes: Default::default(),
+            cc_values: std::array::from_fn(|_| AtomicU8::new(u8::MAX)),
+        }
+    }
+
+    /// Return held input notes, by channel and note
+    ///
+    /// The value is published by the audio thread, it is updated after each rendered buffer.
+    pub fn held_notes(&self) -> Vec<(wmidi::Channel, wmidi::Note)> {
+        let mut notes = vec![];
+        for (i, word) in self.held_notes.iter().enumerate() {
+            let mut bits = word.load(Ordering::Relaxed);
+            while bits != 0 {
+                let index = i * 64 + bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                let channel = wmidi::Channel::from_index((index / 128) as u8).unwrap();
+                let note = wmidi::Note::from_u8_lossy((index % 128) as u8);
+                notes.push((channel, note));
+            }
+        }
+        notes
+    }
+
+    fn set_held_notes(&self, notes: &[(wmidi::Channel, wmidi::Note)]) {
+        let mut words = [0u64; 32];
+        for &(channel, note) in notes {
+            let index = channel.index() as usize * 128 + u8::from(note) as usize;
+            words[index / 64] |= 1 << (index % 64);
+        }
+        for (word, value) in self.held_notes.iter().zip(words) {
+            word.store(value, Ordering::Relaxed);
+        }
+    }
+
+    /// Return the last value of a controller sent to the synth, if any
+    pub fn cc_value(&self, channel: wmidi::Channel, ctrl: wmidi::ControlFunction) -> Option<u8> {
+        match self.cc_values[Self::cc_index(channel, ctrl)].load(Ordering::Relaxed) {
+            u8::MAX => None,
+            value => Some(value),
+        }
+    }
+
+    fn set_cc_value(&self, channel: wmidi::Channel, ctrl: wmidi::ControlFunction, value: Option<u8>) {
+        self.cc_values[Self::cc_index(channel, ctrl)].store(value.unwrap_or(u8::MAX), Ordering::Relaxed);
+    }
+
+    /// Forget controller values, after the synth has been reset
+    fn clear_cc_values(&self) {
+        self.cc_values.iter().for_each(|value| value.store(u8::MAX, Ordering::Relaxed));
+    }
+
+    fn cc_index(channel: wmidi::Channel, ctrl: wmidi::ControlFunction) -> usize {
+        channel.index() as usize * 128 + u8::from(ctrl) as usize
+    }
+
+    /// Return the preset of the first channel, if any
+    pub fn active_preset(&self) -> Option<Preset> {
+        match self.active_preset.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            v => Some(Preset { bank: (v >> 32) as u32, num: v as u32 }),
+        }
+    }
+
+    fn set_active_preset(&self, preset: Option<Preset>) {
+        let value = match preset {
+            Some(preset) => ((preset.bank as u64) << 32) | preset.num as u64,
+            None => u64::MAX,
+        };
+        self.active_preset.store(value, Ordering::Relaxed);
+    }
+}
+
+impl Engine {
+    /// Maximum number of input and scheduled MIDI messages processed per rendered buffer
+    ///
+    /// Remaining messages are kept for the next buffers, so that a burst of
+    /// messages does not make rendering late.
+    const MAX_MESSAGES_PER_RENDER: usize = 256;
+
+    /// Maximum number of pending scheduled messages
+    ///
+    /// The queue is allocated upfront, so that the audio thread never
+    /// reallocates it. Messages scheduled past it are dropped.
+    const MAX_SCHEDULED_MESSAGES: usize = 4096;
+
+    /// Peak sample value under which output is considered silent (-80 dBFS)
+    const SILENCE_THRESHOLD: f32 = 1e-4;
+
+    /// Create an engine, `effects` must be the value used to create the synth
+    pub fn new(synth: Synth, effects: bool, sample_rate: f64, midi_rx: Receiver<MidiMessage>, commands: Receiver<EngineCommand>, shared: Arc<EngineShared>) -> Self {
+        let current_gain = f32::from_bits(shared.gain.load(Ordering::Relaxed));
+        Self {
+            synth,
+            effects,
+            synth_state: SynthState::default(),
+            processor: MidiProcessor::new(),
+            midi_rx,
+            scheduled: VecDeque::with_capacity(Self::MAX_SCHEDULED_MESSAGES),
+            commands,
+            shared,
+            current_gain,
+            note_listeners: vec![],
+            control_listeners: vec![],
+            audio_listeners: vec![],
+            audio_block: ([0.0; BLOCK_SIZE], 0),
+            test_tone: None,
+            sfont_id: None,
+            banks: Default::default(),
+            pitch_bend_range: None,
+            sample_rate,
+            compressor: None,
+            idle_timeout: None,
+            silent_frames: 0,
+            playing: false,
+            pausing: false,
+            preroll: 0,
+            preroll_frames: 0,
+            fade_in: 0,
+            fade_in_pos: 0,
+        }
+    }
+
+    /// Apply commands and input messages, then write the next samples
+    pub fn render(&mut self, data: &mut [f32]) {
+        self.shared.render_count.fetch_add(1, Ordering::Relaxed);
+
+        if self.shared.silenced.load(Ordering::Relaxed) {
+            self.synth.all_sounds_off()
+                .unwrap_or_else(|err| eprintln!("failed to stop voices: {}", err));
+            data.fill(0.0);
+            return;
+        }
+
+        while let Ok(command) = self.commands.try_recv() {
+            self.apply_command(command);
+        }
+
+        if let Some(ref mut test_tone) = self.test_tone {
+            test_tone.write_samples(data);
+            self.tee_samples(data);
+            return;
+        }
+
+        let new_gain = f32::from_bits(self.shared.gain.load(Ordering::Relaxed))
+            * f32::from_bits(self.shared.preset_gain.load(Ordering::Relaxed));
+        if new_gain != self.current_gain {
+            self.synth.synth.set_gain(new_gain);
+            self.current_gain = new_gain;
+        }
+
+        // Let the synth settle after playback starts, input is processed after
+        let frames = data.len() / 2;
+        if self.playing && self.preroll_frames > 0 {
+            self.write_synth_samples(data);
+            data.fill(0.0);
+            self.preroll_frames = self.preroll_frames.saturating_sub(frames);
+            self.tee_samples(data);
+            return;
+        }
+
+        // Convert input MIDI messages
+        let mut processed = 0;
+        while processed < Self::MAX_MESSAGES_PER_RENDER {
+            match self.midi_rx.try_recv() {
+                Ok(message) => {
+                    if let Some(event) = ControlEvent::from_message(&message) {
+                        self.control_listeners.retain(|tx| !matches!(tx.try_send(event), Err(TrySendError::Disconnected(_))));
+                    }
+                    self.process_message(message);
+                }
+                Err(_) => break,
+            }
+            processed += 1;
+        }
+        let mut received = processed > 0;
+        let elapsed = self.frames_duration(frames);
+        let mut output = SynthOutput { synth: &self.synth, shared: &self.shared, banks: &mut self.banks, sfont_id: self.sfont_id };
+        self.processor.update_glides(elapsed, |message| output.send(message));
+
+        // Scheduled messages are processed at their frame, buffers may have any size
+        let start = Instant::now();
+        let end = start + self.frames_duration(frames);
+        if self.scheduled.front().is_some_and(|(time, _)| *time < end) {
+            received = true;
+        }
+
+        // Write the next samples
+        if received {
+            self.silent_frames = 0;
+        }
+        if (self.playing || self.pausing) && !self.is_idle() {
+            self.pausing = false;
+            let mut pos = 0;
+            while processed < Self::MAX_MESSAGES_PER_RENDER {
+                let Some(&(time, _)) = self.scheduled.front() else { break };
+                let offset = self.duration_frames(time.saturating_duration_since(start));
+                if offset >= frames {
+                    break;
+                }
+                if offset > pos {
+                    self.write_synth_samples(&mut data[pos * 2..offset * 2]);
+                    pos = offset;
+                }
+                let (_, message) = self.scheduled.pop_front().unwrap();
+                self.process_message(message);
+                processed += 1;
+            }
+            self.write_synth_samples(&mut data[pos * 2..]);
+
+            let width = f32::from_bits(self.shared.stereo_width.load(Ordering::Relaxed));
+            if width != 1.0 {
+                apply_stereo_width(data, width);
+            }
+            if let Some((_, ref mut compressor)) = self.compressor {
+                compressor.process(data);
+            }
+            self.apply_fade_in(data);
+            if data.iter().all(|v| v.abs() < Self::SILENCE_THRESHOLD) {
+                self.silent_frames = self.silent_frames.saturating_add(frames);
+            } else {
+                self.silent_frames = 0;
+            }
+        } else {
+            while processed < Self::MAX_MESSAGES_PER_RENDER && self.scheduled.front().is_some_and(|(time, _)| *time < end) {
+                let (_, message) = self.scheduled.pop_front().unwrap();
+                self.process_message(message);
+                processed += 1;
+            }
+            data.fill(0.0);
+        }
+        self.update_active_preset();
+        self.shared.set_held_notes(self.processor.held_notes());
+        self.tee_samples(data);
+    }
+
+    /// Process an input or scheduled message
+    fn process_message(&mut self, message: MidiMessage) {
+        let mut output = SynthOutput { synth: &self.synth, shared: &self.shared, banks: &mut self.banks, sfont_id: self.sfont_id };
+        let note_listeners = &mut self.note_listeners;
+        self.processor.process(message, |message| {
+            // Never block the audio thread: drop events if a listener is full
+            if let Some(event) = NoteEvent::from_message(&message) {
+                note_listeners.retain(|tx| !matches!(tx.try_send(event), Err(TrySendError::Disconnected(_))));
+            }
+            output.send(message);
+        });
+    }
+
+    /// Ramp gain up linearly after playback starts, to avoid a pop
+    fn apply_fade_in(&mut self, data: &mut [f32]) {
+        if self.fade_in_pos >= self.fade_in {
+            return;
+        }
+        for frame in data.chunks_exact_mut(2) {
+            if self.fade_in_pos >= self.fade_in {
+                break;
+            }
+            let factor = self.fade_in_pos as f32 / self.fade_in as f32;
+            frame[0] *= factor;
+            frame[1] *= factor;
+            self.fade_in_pos += 1;
+        }
+    }
+
+    fn write_synth_samples(&self, data: &mut [f32]) {
+        self.synth.write_samples(data)
+            .unwrap_or_else(|err| eprintln!("failed to generate samples: {}", err));
+    }
+
+    fn frames_duration(&self, frames: usize) -> Duration {
+        Duration::from_secs_f64(frames as f64 / self.sample_rate)
+    }
+
+    fn duration_frames(&self, duration: Duration) -> usize {
+        (duration.as_secs_f64() * self.sample_rate) as usize
+    }
+
+    /// Send a copy of output samples to audio listeners
+    ///
+    /// Blocks are dropped if a listener is full.
+    fn tee_samples(&mut self, data: &[f32]) {
+        if self.audio_listeners.is_empty() {
+            return;
+        }
+        let (block, len) = &mut self.audio_block;
+        for frame in data.chunks_exact(2) {
+            block[*len] = (frame[0] + frame[1]) / 2.0;
+            *len += 1;
+            if *len == BLOCK_SIZE {
+                *len = 0;
+                self.audio_listeners.retain(|tx| !matches!(tx.try_send(*block), Err(TrySendError::Disconnected(_))));
+            }
+        }
+    }
+
+    fn apply_command(&mut self, command: EngineCommand) {
+        // Result may be ignored if the requester gave up
+        match command {
+            EngineCommand::SetPolyphony { polyphony, reply } => {
+                let result = self.synth.set_polyphony(polyphony).map_err(Into::into);
+                if result.is_ok() {
+                    self.synth_state.polyphony = Some(polyphony);
+                }
+                let _ = reply.send(result);
+            }
+            EngineCommand::SetPitchBendRange { semitones, reply } => {
+                self.pitch_bend_range = Some(semitones);
+                self.processor.set_bend_range(semitones);
+                let _ = reply.send(self.synth.set_pitch_bend_range(semitones).map_err(Into::into));
+            }
+            EngineCommand::SelectPreset { channels, preset, release_notes, reply } => {
+                let _ = reply.send(self.select_preset(channels, preset, release_notes));
+            }
+            EngineCommand::GetPresets { reply } => {
+                let presets = std::array::from_fn(|chan| {
+                    self.synth.synth.get_program(chan as u32).ok()
+                        .filter(|(sfont_id, _, _)| Some(*sfont_id) == self.sfont_id)
+                        .map(|(_, bank, num)| Preset { bank, num })
+                });
+                let _ = reply.send(Ok(presets));
+            }
+            EngineCommand::SetPlaying(playing) => {
+                // Stop voices, so that output is silent instead of being cut
+                if self.playing && !playing {
+                    self.synth.all_sounds_off()
+                        .unwrap_or_else(|err| eprintln!("failed to stop voices: {}", err));
+                    self.pausing = true;
+                }
+                if !self.playing && playing {
+                    self.preroll_frames = self.preroll;
+                    self.fade_in_pos = 0;
+                }
+                self.playing = playing;
+            }
+            EngineCommand::ScheduleMessage(time, message) => {
+                if self.scheduled.len() == Self::MAX_SCHEDULED_MESSAGES {
+                    eprintln!("too many scheduled MIDI messages, message dropped");
+                    return;
+                }
+                // Keep messages with the same time in reception order
+                let index = self.scheduled.partition_point(|(t, _)| *t <= time);
+                self.scheduled.insert(index, (time, message));
+            }
+            EngineCommand::SetCompressor(config) => {
+                self.compressor = config.map(|config| (config, Compressor::new(&config, self.sample_rate)));
+            }
+            EngineCommand::SetIdleTimeout(timeout) => {
+                self.idle_timeout = timeout.map(|timeout| self.duration_frames(timeout));
+            }
+            EngineCommand::SetPreroll(duration) => self.preroll = self.duration_frames(duration),
+            EngineCommand::SetFadeIn(duration) => {
+                self.fade_in = self.duration_frames(duration);
+                self.fade_in_pos = self.fade_in;
+            }
+            EngineCommand::SetTestTone(test_tone) => self.test_tone = test_tone,
+            EngineCommand::SetInputGain(gain) => self.processor.set_input_gain(gain),
+            EngineCommand::SetAftertouchRouting(dest) => self.processor.set_aftertouch_routing(dest),
+            EngineCommand::SetVelocityRouting(dest) => self.processor.set_velocity_routing(dest),
+            EngineCommand::SetReleaseVelocityRouting(dest) => self.processor.set_release_velocity_routing(dest),
+            EngineCommand::SetFreeze(enabled) => {
+                let mut output = SynthOutput { synth: &self.synth, shared: &self.shared, banks: &mut self.banks, sfont_id: self.sfont_id };
+                self.processor.set_freeze(enabled, |message| output.send(message));
+            }
+            EngineCommand::SetZeroVelocityNoteOff(enabled) => {
+                self.synth.set_zero_velocity_note_off(enabled);
+                self.synth_state.zero_velocity_note_off = Some(enabled);
+            }
+            EngineCommand::SetReverb(enabled) => {
+                self.synth.synth.set_reverb_on(enabled);
+                self.synth_state.reverb = Some(enabled);
+            }
+            EngineCommand::SetChorus(enabled) => {
+                self.synth.synth.set_chorus_on(enabled);
+                self.synth_state.chorus = Some(enabled);
+            }
+            EngineCommand::SetMpe(enabled) => self.processor.set_mpe(enabled),
+            EngineCommand::SetTranspose(channel, semitones) => self.processor.set_transpose(channel, semitones),
+            EngineCommand::SetMonoMode(channel, config) => self.processor.set_mono_mode(channel, config),
+            EngineCommand::SetPortamento(channel, time) => {
+                let mut output = SynthOutput { synth: &self.synth, shared: &self.shared, banks: &mut self.banks, sfont_id: self.sfont_id };
+                self.processor.set_portamento(channel, time, |message| output.send(message));
+            }
+            EngineCommand::SetChannelMute(channel, muted) => {
+                let mut output = SynthOutput { synth: &self.synth, shared: &self.shared, banks: &mut self.banks, sfont_id: self.sfont_id };
+                self.processor.set_channel_mute(channel, muted, |message| output.send(message));
+            }
+            EngineCommand::SetSolo(channel) => {
+                let mut output = SynthOutput { synth: &self.synth, shared: &self.shared, banks: &mut self.banks, sfont_id: self.sfont_id };
+                self.processor.set_solo(channel, |message| output.send(message));
+            }
+            EngineCommand::AddNoteListener(tx) => self.note_listeners.push(tx),
+            EngineCommand::AddControlListener(tx) => self.control_listeners.push(tx),
+            EngineCommand::AddAudioListener(tx) => self.audio_listeners.push(tx),
+        }
+    }
+
+    /// Return true if output has been silent long enough to skip synthesis
+    ///
+    /// Synthesis resumes as soon as a message is received, before rendering
+    /// the next buffer, so that the first note is not clipped.
+    fn is_idle(&self) -> bool {
+        self.idle_timeout.is_some_and(|timeout| self.silent_frames >= timeout)
+    }
+
+    /// Load a new SoundFont file, return data of its presets
+    ///
+    /// Progress is reported as scanned preset numbers and their total. If the
+    /// file cannot be loaded, the previous SoundFont is kept.
+    /// Not for the audio thread: the caller locks the engine meanwhile.
+    pub fn load_sfont(&mut self, path: PathBuf, progress: &mut dyn FnMut(usize, usize)) -> Result<Vec<PresetData>> {
+        let synth = &self.synth.synth;
+
+        // Load the new SoundFont file, keep the previous one if it fails
+        let sfont_id = synth.sfload(&path, true).map_err(|err| {
+            let is_sfont = path.extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("sf2") || ext.eq_ignore_ascii_case("sf3"));
+            if is_sfont {
+                anyhow::anyhow!("{} (not a valid SF2 or SF3 file?)", err)
+            } else {
+                anyhow::anyhow!("{} (not a SoundFont? expected a .sf2 or .sf3 file)", err)
+            }
+        })?;
+        if let Some(previous_id) = self.sfont_id.replace(sfont_id) {
+            synth.sfunload(previous_id, true)?;
+        }
+        let sfont = synth.get_sfont_by_id(sfont_id).unwrap();
+
+        // Get presets data, reporting progress after each bank
+        const BANK_SIZE: usize = 128;
+        let mut presets_data = vec![];
+        for bank in 0..BANK_SIZE as u32 {
+            presets_data.extend((0..BANK_SIZE as u32).filter_map(|num| {
+                sfont
+                    .get_preset(bank, num)
+                    .map(|preset| PresetData {
+                        bank,
+                        num,
+                        name: preset.get_name().map(|s| s.into()),
+                    })
+            }));
+            progress((bank as usize + 1) * BANK_SIZE, BANK_SIZE * BANK_SIZE);
+        }
+
+        // Reset synth and processing state, to not keep notes or controllers of the previous font
+        synth.system_reset()?;
+        self.shared.clear_cc_values();
+        if let Some(semitones) = self.pitch_bend_range {
+            self.synth.set_pitch_bend_range(semitones)?;
+        }
+        self.processor.reset();
+        self.banks = Default::default();
+        self.synth_state.sfont_path = Some(path);
+
+        Ok(presets_data)
+    }
+
+    /// Change a FluidSynth setting, keep it to restore it on synth rebuild
+    ///
+    /// Not for the audio thread: the caller locks the engine meanwhile.
+    pub fn set_setting(&mut self, key: &str, value: &SettingValue) -> Result<()> {
+        self.synth.set_setting(key, value)?;
+        self.synth_state.settings.retain(|(k, _)| k != key);
+        self.synth_state.settings.push((key.to_owned(), value.clone()));
+        Ok(())
+    }
+
+    /// Recreate the synth for a new output sample rate
+    ///
+    /// FluidLite cannot change the sample rate of an existing synth. The font,
+    /// presets and state set by commands are restored, playing notes are lost.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) -> Result<()> {
+        if sample_rate == self.sample_rate {
+            return Ok(());
+        }
+        let programs: Vec<_> = (0..16).map(|chan| self.synth.synth.get_program(chan).ok()).collect();
+        self.synth = Synth::new(sample_rate, self.current_gain, self.effects)?;
+        self.sfont_id = None;
+        self.shared.clear_cc_values();
+
+        // Durations are kept, not frame counts
+        let ratio = sample_rate / self.sample_rate;
+        let rescale = |frames: usize| (frames as f64 * ratio) as usize;
+        self.preroll = rescale(self.preroll);
+        self.fade_in = rescale(self.fade_in);
+        self.fade_in_pos = self.fade_in;
+        self.idle_timeout = self.idle_timeout.map(rescale);
+        self.sample_rate = sample_rate;
+        if let Some((config, _)) = self.compressor {
+            self.compressor = Some((config, Compressor::new(&config, sample_rate)));
+        }
+
+        self.restore_synth_state(&programs)
+    }
+
+    /// Apply state set by commands to a new synth, and select given programs
+    fn restore_synth_state(&mut self, programs: &[Option<(fluidlite::FontId, u32, u32)>]) -> Result<()> {
+        for (key, value) in &self.synth_state.settings {
+            self.synth.set_setting(key, value)?;
+        }
+        if let Some(polyphony) = self.synth_state.polyphony {
+            self.synth.set_polyphony(polyphony)?;
+        }
+        if let Some(enabled) = self.synth_state.reverb {
+            self.synth.synth.set_reverb_on(enabled);
+        }
+        if let Some(enabled) = self.synth_state.chorus {
+            self.synth.synth.set_chorus_on(enabled);
+        }
+        if let Some(enabled) = self.synth_state.zero_velocity_note_off {
+            self.synth.set_zero_velocity_note_off(enabled);
+        }
+        if let Some(path) = self.synth_state.sfont_path.clone() {
+            self.load_sfont(path, &mut |_, _| {})?;
+            let sfont_id = self.sfont_id.unwrap();
+            for (chan, program) in programs.iter().enumerate() {
+                if let Some((_, bank, num)) = *program {
+                    self.synth.synth.program_select(chan as u32, sfont_id, bank, num)?;
+                }
+            }
+        } else if let Some(semitones) = self.pitch_bend_range {
+            self.synth.set_pitch_bend_range(semitones)?;
+        }
+        Ok(())
+    }
+
+    fn select_preset(&self, channels: &[wmidi::Channel], preset: Preset, release_notes: bool) -> Result<()> {
+        let sfont_id = self.sfont_id.ok_or(PianoError::NoSoundFont)?;
+        select_preset(&self.synth.synth, sfont_id, channels, preset, release_notes)
+    }
+
+    fn update_active_preset(&self) {
+        let preset = self.synth.synth.get_program(0).ok()
+            .map(|(_, bank, num)| Preset { bank, num });
+        self.shared.set_active_preset(preset);
+    }
+}
+
+
+/// Select a preset on channels, optionally releasing their notes first
+fn select_preset<B: SynthBackend>(backend: &B, sfont_id: fluidlite::FontId, channels: &[wmidi::Channel], preset: Preset, release_notes: bool) -> Result<()> {
+    for chan in channels {
+        if release_notes {
+            backend.cc(chan.index() as u32, u8::from(wmidi::ControlFunction::ALL_NOTES_OFF) as u32, 0)?;
+        }
+        // Only fails if the font has no such preset, channels are valid
+        backend.program_select(chan.index() as u32, sfont_id, preset.bank, preset.num)
+            .map_err(|_| PianoError::InvalidPreset(preset))?;
+    }
+    Ok(())
+}
+
+/// Scale the side component of interleaved stereo samples
+///
+/// A width of 0 mixes to mono, 1 keeps samples unchanged, larger values widen.
+fn apply_stereo_width(data: &mut [f32], width: f32) {
+    for frame in data.chunks_exact_mut(2) {
+        let mid = (frame[0] + frame[1]) * 0.5;
+        let side = (frame[0] - frame[1]) * 0.5 * width;
+        frame[0] = mid + side;
+        frame[1] = mid - side;
+    }
+}
+
+/// Bank selected on a channel, by bank select controllers
+#[derive(Copy, Clone, Default)]
+struct BankSelect {
+    msb: u8,
+    lsb: Option<u8>,
+}
+
+impl BankSelect {
+    /// Return the selected SoundFont bank
+    ///
+    /// The LSB is only used if it has been received after the MSB, so that
+    /// fonts numbering banks with the MSB alone work as expected.
+    fn bank(&self) -> u32 {
+        match self.lsb {
+            Some(lsb) => ((self.msb as u32) << 7) | lsb as u32,
+            None => self.msb as u32,
+        }
+    }
+}
+
+/// Banks selected on each channel, `None` if not selected since last reset
+#[derive(Default)]
+struct Banks([Option<BankSelect>; 16]);
+
+impl Banks {
+    /// Handle bank selection and program changes, return false for other messages
+    ///
+    /// Program changes select presets from the loaded font once a bank has
+    /// been selected, and are left to FluidLite otherwise, which keeps its
+    /// default bank 128 on the drum channel. If the font has no such preset,
+    /// FluidLite picks one itself.
+    fn send<B: SynthBackend>(&mut self, backend: &B, sfont_id: Option<fluidlite::FontId>, message: &MidiMessage) -> Result<bool> {
+        match *message {
+            MidiMessage::ControlChange(chan, wmidi::ControlFunction::BANK_SELECT, value) => {
+                self.0[chan.index() as usize] = Some(BankSelect { msb: value.into(), lsb: None });
+                Ok(true)
+            }
+            MidiMessage::ControlChange(chan, wmidi::ControlFunction::BANK_SELECT_LSB, value) => {
+                self.0[chan.index() as usize].get_or_insert_with(Default::default).lsb = Some(value.into());
+                Ok(true)
+            }
+            MidiMessage::ProgramChange(chan, program) => {
+                let (Some(sfont_id), Some(select)) = (sfont_id, self.0[chan.index() as usize]) else {
+                    return Ok(false);
+                };
+                let bank = select.bank();
+                if chan == wmidi::Channel::Ch10 && bank == 0 {
+                    return Ok(false);
+                }
+                let (chan, program) = (chan.index() as u32, u8::from(program) as u32);
+                if backend.program_select(chan, sfont_id, bank, program).is_err() {
+                    backend.bank_select(chan, bank)
+                        .and_then(|_| backend.program_change(chan, program))
+                        .map_err(|err| anyhow::anyhow!("cannot select preset {}:{}: {}", bank, program, err))?;
+                }
+                Ok(true)
+            }
+            MidiMessage::Reset => {
+                self.0 = Default::default();
+                Ok(false)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Send processed messages to the synth
+///
+/// Bank select and program changes are handled here, so that presets are
+/// always selected from the loaded font.
+struct SynthOutput<'a> {
+    synth: &'a Synth,
+    /// Controller values are published on it
+    shared: &'a EngineShared,
+    banks: &'a mut Banks,
+    sfont_id: Option<fluidlite::FontId>,
+}
+
+impl SynthOutput<'_> {
+    fn send(&mut self, message: MidiMessage) {
+        // Publish controller values, channel mode messages are not values
+        let mut reset_channel = None;
+        match message {
+            MidiMessage::ControlChange(chan, wmidi::ControlFunction::RESET_ALL_CONTROLLERS, _) => reset_channel = Some(chan),
+            MidiMessage::ControlChange(chan, ctrl, value) if u8::from(ctrl) < u8::from(wmidi::ControlFunction::ALL_SOUND_OFF) => {
+                self.shared.set_cc_value(chan, ctrl, Some(value.into()));
+            }
+            MidiMessage::Reset => self.shared.clear_cc_values(),
+            _ => {}
+        }
+        let result = match self.banks.send(&self.synth.synth, self.sfont_id, &message) {
+            Ok(true) => Ok(()),
+            Ok(false) => self.synth.send_midi_message(message).map_err(Into::into),
+            Err(err) => Err(err),
+        };
+        result.unwrap_or_else(|err| eprintln!("failed to process MIDI message: {}", err));
+
+        // Read reset values back, the synth does not reset all controllers
+        if let Some(chan) = reset_channel {
+            for ctrl in (0..120).map(|i| wmidi::ControlFunction::from(wmidi::U7::from_u8_lossy(i))) {
+                if self.shared.cc_value(chan, ctrl).is_some() {
+                    let value = self.synth.synth.get_cc(chan.index() as u32, u8::from(ctrl) as u32).ok();
+                    self.shared.set_cc_value(chan, ctrl, value.map(|v| v as u8));
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::time::{Duration, Instant};
+    use wmidi::{Channel, Note, U7};
+    use crate::midi::{MidiMessage, NoteEvent};
+    use crate::piano::Preset;
+    use crate::synth::Synth;
+    use crate::synth::mock::{Call, MockBackend};
+    use crate::test_sfont;
+    use super::{select_preset, Banks, Engine, EngineCommand, EngineShared};
+
+    const SAMPLE_RATE: f64 = 44100.0;
+
+    /// Create a playing engine, return it with its input queues and note events
+    fn playing_engine() -> (Engine, Sender<MidiMessage>, Sender<EngineCommand>, Receiver<NoteEvent>) {
+        let synth = Synth::new(SAMPLE_RATE, 0.2, false).unwrap();
+        let (input_tx, input_rx) = mpsc::channel();
+        let (commands_tx, commands_rx) = mpsc::channel();
+        let (notes_tx, notes_rx) = mpsc::sync_channel(1024);
+        let engine = Engine::new(synth, false, SAMPLE_RATE, input_rx, commands_rx, Arc::new(EngineShared::new(0.2)));
+        commands_tx.send(EngineCommand::SetPlaying(true)).unwrap();
+        commands_tx.send(EngineCommand::AddNoteListener(notes_tx)).unwrap();
+        (engine, input_tx, commands_tx, notes_rx)
+    }
+
+    fn note_on(note: u8) -> MidiMessage {
+        MidiMessage::NoteOn(Channel::Ch1, Note::from_u8_lossy(note), U7::MAX)
+    }
+
+    fn received_notes(notes: &Receiver<NoteEvent>) -> Vec<u8> {
+        notes.try_iter()
+            .filter_map(|event| match event { NoteEvent::On { note, .. } => Some(note as u8), NoteEvent::Off { .. } => None })
+            .collect()
+    }
+
+    #[test]
+    fn scheduled_messages_with_alternating_buffer_sizes() {
+        let (mut engine, _input, commands, notes) = playing_engine();
+        let now = Instant::now();
+        let later = now + Duration::from_secs(3600);
+        // Scheduled out of order, due messages are processed in time order
+        for (i, note) in [64u8, 62, 60, 63, 61].into_iter().enumerate() {
+            let time = now - Duration::from_millis(u64::from(65 - note) * 10);
+            commands.send(EngineCommand::ScheduleMessage(time, note_on(note))).unwrap();
+            commands.send(EngineCommand::ScheduleMessage(later, note_on(70 + i as u8))).unwrap();
+        }
+        let mut data = [0.0; 2 * 512];
+        for size in [64, 512, 64, 512] {
+            engine.render(&mut data[..2 * size]);
+        }
+        assert_eq!(received_notes(&notes), [60, 61, 62, 63, 64]);
+        assert_eq!(engine.scheduled.len(), 5);
+    }
+
+    #[test]
+    fn input_messages_per_render() {
+        let (mut engine, input, _commands, notes) = playing_engine();
+        for i in 0..Engine::MAX_MESSAGES_PER_RENDER + 10 {
+            input.send(note_on(i as u8)).unwrap();
+        }
+        let mut data = [0.0; 2 * 64];
+        engine.render(&mut data);
+        assert_eq!(received_notes(&notes).len(), Engine::MAX_MESSAGES_PER_RENDER);
+        engine.render(&mut data);
+        assert_eq!(received_notes(&notes).len(), 10);
+        engine.render(&mut data);
+        assert_eq!(received_notes(&notes).len(), 0);
+    }
+
+    #[test]
+    fn scheduled_messages_per_render() {
+        let (mut engine, _input, commands, notes) = playing_engine();
+        let time = Instant::now() - Duration::from_secs(1);
+        for i in 0..Engine::MAX_MESSAGES_PER_RENDER + 10 {
+            commands.send(EngineCommand::ScheduleMessage(time, note_on(i as u8))).unwrap();
+        }
+        let mut data = [0.0; 2 * 64];
+        engine.render(&mut data);
+        assert_eq!(received_notes(&notes).len(), Engine::MAX_MESSAGES_PER_RENDER);
+        engine.render(&mut data);
+        assert_eq!(received_notes(&notes).len(), 10);
+    }
+
+    #[test]
+    fn scheduled_messages_capacity() {
+        let (mut engine, _input, commands, _notes) = playing_engine();
+        let capacity = engine.scheduled.capacity();
+        let time = Instant::now() + Duration::from_secs(3600);
+        for _ in 0..Engine::MAX_SCHEDULED_MESSAGES + 10 {
+            commands.send(EngineCommand::ScheduleMessage(time, note_on(60))).unwrap();
+        }
+        engine.render(&mut [0.0; 2 * 64]);
+        assert_eq!(engine.scheduled.len(), Engine::MAX_SCHEDULED_MESSAGES);
+        assert_eq!(engine.scheduled.capacity(), capacity);
+    }
+
+    fn cc(channel: Channel, control: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange(channel, U7::from_u8_lossy(control).into(), U7::from_u8_lossy(value))
+    }
+
+    fn program(channel: Channel, program: u8) -> MidiMessage {
+        MidiMessage::ProgramChange(channel, U7::from_u8_lossy(program))
+    }
+
+    fn peak(data: &[f32]) -> f32 {
+        data.iter().fold(0.0, |peak, v| peak.max(v.abs()))
+    }
+
+    #[test]
+    fn swap_font_with_held_note() {
+        let path = test_sfont::temp_path("swap.sf2");
+        test_sfont::write(&path, &[(0, 0)]);
+        let (mut engine, input, _commands, _notes) = playing_engine();
+        engine.load_sfont(path.clone(), &mut |_, _| {}).unwrap();
+        input.send(note_on(60)).unwrap();
+        let mut data = [0.0; 2 * 512];
+        for _ in 0..4 {
+            engine.render(&mut data);
+        }
+        assert!(peak(&data) > 0.01);
+        assert_eq!(engine.shared.held_notes().len(), 1);
+
+        engine.load_sfont(path.clone(), &mut |_, _| {}).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        for _ in 0..4 {
+            engine.render(&mut data);
+        }
+        assert_eq!(peak(&data), 0.0);
+        assert!(engine.shared.held_notes().is_empty());
+    }
+
+    #[test]
+    fn load_invalid_font() {
+        let (mut engine, input, _commands, _notes) = playing_engine();
+        let valid_path = test_sfont::temp_path("valid.sf2");
+        test_sfont::write(&valid_path, &[(0, 0)]);
+        engine.load_sfont(valid_path.clone(), &mut |_, _| {}).unwrap();
+        std::fs::remove_file(&valid_path).unwrap();
+        let sfont_id = engine.sfont_id;
+
+        for (name, hint) in [("garbage.sf2", "(not a valid SF2 or SF3 file?)"), ("garbage.txt", "(not a SoundFont? expected a .sf2 or .sf3 file)")] {
+            let path = test_sfont::temp_path(name);
+            std::fs::write(&path, b"not a SoundFont").unwrap();
+            let result = engine.load_sfont(path.clone(), &mut |_, _| {});
+            std::fs::remove_file(&path).unwrap();
+            let message = result.err().expect("invalid font loaded").to_string();
+            assert!(message.ends_with(hint), "{}", message);
+        }
+
+        // The previous font is still loaded and playing
+        assert_eq!(engine.sfont_id, sfont_id);
+        assert_eq!(engine.synth.synth.sfcount(), 1);
+        assert_eq!(engine.synth_state.sfont_path.as_ref(), Some(&valid_path));
+        input.send(note_on(60)).unwrap();
+        let mut data = [0.0; 2 * 512];
+        for _ in 0..4 {
+            engine.render(&mut data);
+        }
+        assert!(peak(&data) > 0.01);
+    }
+
+    #[test]
+    fn program_change_without_bank_select() {
+        let backend = MockBackend::default();
+        let mut banks = Banks::default();
+        assert!(!banks.send(&backend, Some(1), &program(Channel::Ch1, 3)).unwrap());
+        assert!(!banks.send(&backend, Some(1), &program(Channel::Ch10, 3)).unwrap());
+        assert_eq!(backend.take_calls(), []);
+    }
+
+    #[test]
+    fn bank_select() {
+        let backend = MockBackend::default();
+        let mut banks = Banks::default();
+        assert!(banks.send(&backend, Some(1), &cc(Channel::Ch1, 0, 2)).unwrap());
+        assert!(banks.send(&backend, Some(1), &program(Channel::Ch1, 3)).unwrap());
+        assert!(banks.send(&backend, Some(1), &cc(Channel::Ch1, 32, 1)).unwrap());
+        assert!(banks.send(&backend, Some(1), &program(Channel::Ch1, 4)).unwrap());
+        // MSB alone resets the LSB
+        assert!(banks.send(&backend, Some(1), &cc(Channel::Ch1, 0, 2)).unwrap());
+        assert!(banks.send(&backend, Some(1), &program(Channel::Ch1, 5)).unwrap());
+        assert_eq!(backend.take_calls(), [
+            Call::ProgramSelect(0, 1, 2, 3),
+            Call::ProgramSelect(0, 1, 2 << 7 | 1, 4),
+            Call::ProgramSelect(0, 1, 2, 5),
+        ]);
+    }
+
+    #[test]
+    fn bank_select_lsb_only() {
+        let backend = MockBackend::default();
+        let mut banks = Banks::default();
+        assert!(banks.send(&backend, Some(1), &cc(Channel::Ch2, 32, 8)).unwrap());
+        assert!(banks.send(&backend, Some(1), &program(Channel::Ch2, 0)).unwrap());
+        assert_eq!(backend.take_calls(), [Call::ProgramSelect(1, 1, 8, 0)]);
+    }
+
+    #[test]
+    fn bank_select_drum_channel() {
+        let backend = MockBackend::default();
+        let mut banks = Banks::default();
+        banks.send(&backend, Some(1), &cc(Channel::Ch10, 0, 0)).unwrap();
+        assert!(!banks.send(&backend, Some(1), &program(Channel::Ch10, 3)).unwrap());
+        banks.send(&backend, Some(1), &cc(Channel::Ch10, 0, 1)).unwrap();
+        assert!(banks.send(&backend, Some(1), &program(Channel::Ch10, 3)).unwrap());
+        assert_eq!(backend.take_calls(), [Call::ProgramSelect(9, 1, 1, 3)]);
+    }
+
+    #[test]
+    fn bank_select_without_font() {
+        let backend = MockBackend::default();
+        let mut banks = Banks::default();
+        banks.send(&backend, None, &cc(Channel::Ch1, 0, 2)).unwrap();
+        assert!(!banks.send(&backend, None, &program(Channel::Ch1, 3)).unwrap());
+        assert_eq!(backend.take_calls(), []);
+    }
+
+    #[test]
+    fn bank_select_missing_preset() {
+        let backend = MockBackend { missing_presets: vec![(2, 3)], ..Default::default() };
+        let mut banks = Banks::default();
+        banks.send(&backend, Some(1), &cc(Channel::Ch1, 0, 2)).unwrap();
+        assert!(banks.send(&backend, Some(1), &program(Channel::Ch1, 3)).unwrap());
+        assert_eq!(backend.take_calls(), [Call::ProgramSelect(0, 1, 2, 3), Call::BankSelect(0, 2), Call::ProgramChange(0, 3)]);
+    }
+
+    #[test]
+    fn reset_clears_bank_select() {
+        let backend = MockBackend::default();
+        let mut banks = Banks::default();
+        banks.send(&backend, Some(1), &cc(Channel::Ch1, 0, 2)).unwrap();
+        assert!(!banks.send(&backend, Some(1), &MidiMessage::Reset).unwrap());
+        assert!(!banks.send(&backend, Some(1), &program(Channel::Ch1, 3)).unwrap());
+        assert_eq!(backend.take_calls(), []);
+    }
+
+    #[test]
+    fn switch_preset_with_held_note() {
+        let backend = MockBackend::default();
+        let preset = Preset { bank: 0, num: 5 };
+        crate::synth::send_message(&backend, MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::MAX), true).unwrap();
+
+        // Held notes are kept by default
+        select_preset(&backend, 1, &[Channel::Ch1], preset, false).unwrap();
+        assert_eq!(backend.take_calls(), [Call::NoteOn(0, 60, 127), Call::ProgramSelect(0, 1, 0, 5)]);
+
+        // Notes are released before switching
+        select_preset(&backend, 1, &[Channel::Ch1, Channel::Ch2], preset, true).unwrap();
+        assert_eq!(backend.take_calls(), [
+            Call::Cc(0, 123, 0),
+            Call::ProgramSelect(0, 1, 0, 5),
+            Call::Cc(1, 123, 0),
+            Call::ProgramSelect(1, 1, 0, 5),
+        ]);
+    }
+}
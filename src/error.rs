@@ -0,0 +1,84 @@
+use crate::piano::Preset;
+
+
+/// Error returned by the public API
+///
+/// Failures embedders may want to handle have their own variant. Other ones
+/// are kept as `anyhow` errors, with their context.
+#[derive(Debug, thiserror::Error)]
+pub enum PianoError {
+    /// Audio output device not found, by name, or no default one if `None`
+    #[error("{}", match .0 {
+        Some(name) => format!("audio output device not found: {}", name),
+        None => "no audio output device available".into(),
+    })]
+    DeviceNotFound(Option<String>),
+    /// MIDI input port not found, by name or index, or no default one if `None`
+    #[error("{}", match .0 {
+        Some(name) => format!("MIDI input port not found, expected a port name or index: {}", name),
+        None => "no MIDI input port".into(),
+    })]
+    PortNotFound(Option<String>),
+    /// No SoundFont is loaded, or none has been found
+    #[error("no active SoundFont")]
+    NoSoundFont,
+    /// The SoundFont does not provide the preset
+    #[error("preset not found: {}:{}", .0.bank, .0.num)]
+    InvalidPreset(Preset),
+    /// Error reported by FluidLite
+    #[error(transparent)]
+    Synth(anyhow::Error),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for PianoError {
+    /// Recover typed errors raised internally, possibly with added context
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<PianoError>() {
+            Ok(err) => err,
+            Err(err) if err.chain().any(|cause| cause.is::<fluidlite::Error>()) => Self::Synth(err),
+            Err(err) => Self::Other(err),
+        }
+    }
+}
+
+impl From<fluidlite::Error> for PianoError {
+    fn from(err: fluidlite::Error) -> Self {
+        Self::Synth(err.into())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use anyhow::Context;
+    use super::PianoError;
+
+    #[test]
+    fn display() {
+        assert_eq!(PianoError::DeviceNotFound(Some("hw:1".into())).to_string(), "audio output device not found: hw:1");
+        assert_eq!(PianoError::DeviceNotFound(None).to_string(), "no audio output device available");
+        assert_eq!(PianoError::NoSoundFont.to_string(), "no active SoundFont");
+    }
+
+    #[test]
+    fn source_chain() {
+        let io_err = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file");
+        let err: PianoError = Err::<(), _>(io_err).context("cannot read config file").unwrap_err().into();
+        assert!(matches!(err, PianoError::Other(_)));
+        assert_eq!(err.to_string(), "cannot read config file");
+        assert_eq!(err.source().unwrap().to_string(), "no such file");
+
+        let err: PianoError = fluidlite::Error::Path.into();
+        assert!(matches!(err, PianoError::Synth(_)));
+        assert!(err.source().is_none());
+    }
+
+    #[test]
+    fn typed_error_recovered() {
+        let err = anyhow::Error::from(PianoError::NoSoundFont).context("cannot select preset");
+        assert!(matches!(PianoError::from(err), PianoError::NoSoundFont));
+    }
+}
@@ -0,0 +1,384 @@
+use std::io::Write;
+use std::time::Duration;
+use anyhow::Result;
+use wmidi::{Channel, Note, U7};
+use crate::midi::MidiMessage;
+
+
+/// Note played from its start to its end
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct NoteSpan {
+    pub start: Duration,
+    pub duration: Duration,
+    pub channel: Channel,
+    pub note: Note,
+    pub velocity: U7,
+}
+
+/// Extract notes from timed events, sorted by time
+///
+/// Notes still held after the last event end with it.
+pub fn note_spans(events: &[(Duration, MidiMessage)]) -> Vec<NoteSpan> {
+    let mut spans: Vec<NoteSpan> = vec![];
+    // Indexes in `spans` of notes not ended yet
+    let mut held: Vec<usize> = vec![];
+    fn end(spans: &mut [NoteSpan], held: &mut Vec<usize>, time: Duration, channel: Channel, note: Note) {
+        if let Some(pos) = held.iter().position(|i| spans[*i].channel == channel && spans[*i].note == note) {
+            let span = &mut spans[held.swap_remove(pos)];
+            span.duration = time - span.start;
+        }
+    }
+
+    for (time, message) in events {
+        match *message {
+            MidiMessage::NoteOn(channel, note, velocity) if velocity != U7::MIN => {
+                // Restart the note if it is already playing
+                end(&mut spans, &mut held, *time, channel, note);
+                held.push(spans.len());
+                spans.push(NoteSpan { start: *time, duration: Duration::ZERO, channel, note, velocity });
+            }
+            MidiMessage::NoteOn(channel, note, _) | MidiMessage::NoteOff(channel, note, _) => {
+                end(&mut spans, &mut held, *time, channel, note);
+            }
+            _ => {}
+        }
+    }
+    let last_time = events.last().map(|(time, _)| *time).unwrap_or_default();
+    for i in held {
+        spans[i].duration = last_time - spans[i].start;
+    }
+    spans
+}
+
+/// Write notes as CSV, one note per line
+///
+/// Columns are start and duration in seconds, MIDI note number, velocity and
+/// channel number (1-based).
+pub fn write_csv<W: Write>(out: &mut W, events: &[(Duration, MidiMessage)]) -> Result<()> {
+    writeln!(out, "start,duration,note,velocity,channel")?;
+    for span in note_spans(events) {
+        writeln!(out, "{:.3},{:.3},{},{},{}",
+            span.start.as_secs_f64(), span.duration.as_secs_f64(),
+            u8::from(span.note), u8::from(span.velocity), span.channel.number())?;
+    }
+    Ok(())
+}
+
+
+/// Write notes as a single-part MusicXML score
+///
+/// Notes are quantized to sixteenth notes, using the given quarter note
+/// duration. Notes starting together are written as chords, lasting until the
+/// next chord at most. Channels are merged in a single voice.
+pub fn write_musicxml<W: Write>(
+    out: &mut W,
+    events: &[(Duration, MidiMessage)],
+    quarter: Duration,
+    time_signature: (u8, u8),
+) -> Result<()> {
+    let (beats, beat_type) = time_signature;
+    anyhow::ensure!(beat_type <= 16, "unsupported time signature: {}/{}", beats, beat_type);
+    anyhow::ensure!(!quarter.is_zero(), "invalid quarter note duration: 0");
+    let measure_len = beats as u32 * 4 * XML_DIVISIONS / beat_type as u32;
+
+    // Quantize, group notes by start
+    let grid = quarter.as_secs_f64() / XML_DIVISIONS as f64;
+    let quantize = |time: Duration| (time.as_secs_f64() / grid).round() as u32;
+    let spans = note_spans(events);
+    let mut chords: Vec<(u32, u32, Vec<Note>)> = vec![];
+    for span in &spans {
+        let start = quantize(span.start);
+        let duration = quantize(span.start + span.duration).saturating_sub(start).max(1);
+        match chords.last_mut() {
+            Some((chord_start, chord_duration, notes)) if *chord_start == start => {
+                *chord_duration = (*chord_duration).max(duration);
+                if !notes.contains(&span.note) {
+                    notes.push(span.note);
+                }
+            }
+            _ => chords.push((start, duration, vec![span.note])),
+        }
+    }
+    for i in 1..chords.len() {
+        let next_start = chords[i].0;
+        let chord = &mut chords[i - 1];
+        chord.1 = chord.1.min(next_start - chord.0);
+    }
+
+    // Bass clef for low parts
+    let average_note = spans.iter().map(|span| u8::from(span.note) as f32).sum::<f32>() / spans.len().max(1) as f32;
+    let clef = if !spans.is_empty() && average_note < 60.0 { ("F", 4) } else { ("G", 2) };
+
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out, r#"<!DOCTYPE score-partwise PUBLIC "-//Recordare//DTD MusicXML 4.0 Partwise//EN" "http://www.musicxml.org/dtds/partwise.dtd">"#)?;
+    writeln!(out, r#"<score-partwise version="4.0">"#)?;
+    writeln!(out, r#"  <part-list><score-part id="P1"><part-name>Piano</part-name></score-part></part-list>"#)?;
+    writeln!(out, r#"  <part id="P1">"#)?;
+    let mut writer = MeasureWriter { out, measure_len, measure: 0, pos: 0 };
+    writer.start_measure()?;
+    writeln!(writer.out, "      <attributes><divisions>{}</divisions><key><fifths>0</fifths></key>\
+        <time><beats>{}</beats><beat-type>{}</beat-type></time>\
+        <clef><sign>{}</sign><line>{}</line></clef></attributes>",
+        XML_DIVISIONS, beats, beat_type, clef.0, clef.1)?;
+
+    let mut pos = 0;
+    for (start, duration, notes) in &chords {
+        if *start > pos {
+            writer.write(start - pos, &[])?;
+        }
+        writer.write(*duration, notes)?;
+        pos = start + duration;
+    }
+    // Complete the last measure
+    if writer.pos > 0 || writer.measure == 1 {
+        writer.write(measure_len - writer.pos, &[])?;
+    }
+    writeln!(writer.out, "    </measure>")?;
+    writeln!(writer.out, "  </part>")?;
+    writeln!(writer.out, "</score-partwise>")?;
+    Ok(())
+}
+
+/// MusicXML duration units per quarter note
+const XML_DIVISIONS: u32 = 4;
+
+/// Note durations which can be written without ties
+///
+/// Durations are in `XML_DIVISIONS`, with their type and whether they are dotted.
+const XML_NOTE_TYPES: [(u32, &str, bool); 8] = [
+    (16, "whole", false),
+    (12, "half", true),
+    (8, "half", false),
+    (6, "quarter", true),
+    (4, "quarter", false),
+    (3, "eighth", true),
+    (2, "eighth", false),
+    (1, "16th", false),
+];
+
+/// Write notes and rests in measures, splitting them as needed
+struct MeasureWriter<'a, W: Write> {
+    out: &'a mut W,
+    /// Length of a measure, in divisions
+    measure_len: u32,
+    /// Current measure number
+    measure: u32,
+    /// Position in the current measure
+    pos: u32,
+}
+
+impl<'a, W: Write> MeasureWriter<'a, W> {
+    fn start_measure(&mut self) -> Result<()> {
+        if self.measure > 0 {
+            writeln!(self.out, "    </measure>")?;
+        }
+        self.measure += 1;
+        self.pos = 0;
+        writeln!(self.out, r#"    <measure number="{}">"#, self.measure)?;
+        Ok(())
+    }
+
+    /// Write a chord, or a rest if there are no notes
+    ///
+    /// Chords crossing bar lines or with unwritable durations are split in
+    /// tied chords.
+    fn write(&mut self, mut duration: u32, notes: &[Note]) -> Result<()> {
+        let mut tied = false;
+        while duration > 0 {
+            if self.pos == self.measure_len {
+                self.start_measure()?;
+            }
+            let available = duration.min(self.measure_len - self.pos);
+            let (length, kind, dotted) = XML_NOTE_TYPES.iter().copied()
+                .find(|(length, ..)| *length <= available)
+                .expect("no note type for duration");
+            duration -= length;
+            self.pos += length;
+            let ties = (tied, duration > 0 && !notes.is_empty());
+            if notes.is_empty() {
+                self.write_note(None, false, length, kind, dotted, (false, false))?;
+            }
+            for (i, note) in notes.iter().enumerate() {
+                self.write_note(Some(*note), i > 0, length, kind, dotted, ties)?;
+            }
+            tied = ties.1;
+        }
+        Ok(())
+    }
+
+    /// Write a note, or a rest
+    ///
+    /// `ties` tells whether the note is tied to the previous and next ones.
+    fn write_note(&mut self, note: Option<Note>, chord: bool, length: u32, kind: &str, dotted: bool, ties: (bool, bool)) -> Result<()> {
+        const STEPS: [(&str, i8); 12] = [
+            ("C", 0), ("C", 1), ("D", 0), ("D", 1), ("E", 0), ("F", 0),
+            ("F", 1), ("G", 0), ("G", 1), ("A", 0), ("A", 1), ("B", 0),
+        ];
+        let mut xml = String::from("      <note>");
+        if chord {
+            xml.push_str("<chord/>");
+        }
+        match note {
+            Some(note) => {
+                let num = u8::from(note);
+                let (step, alter) = STEPS[(num % 12) as usize];
+                xml.push_str(&format!("<pitch><step>{}</step>", step));
+                if alter != 0 {
+                    xml.push_str(&format!("<alter>{}</alter>", alter));
+                }
+                xml.push_str(&format!("<octave>{}</octave></pitch>", num as i32 / 12 - 1));
+            }
+            None => xml.push_str("<rest/>"),
+        }
+        xml.push_str(&format!("<duration>{}</duration>", length));
+        let tie_types = [(ties.0, "stop"), (ties.1, "start")];
+        for (_, kind) in tie_types.iter().filter(|(tie, _)| *tie) {
+            xml.push_str(&format!(r#"<tie type="{}"/>"#, kind));
+        }
+        xml.push_str(&format!("<type>{}</type>", kind));
+        if dotted {
+            xml.push_str("<dot/>");
+        }
+        if ties.0 || ties.1 {
+            xml.push_str("<notations>");
+            for (_, kind) in tie_types.iter().filter(|(tie, _)| *tie) {
+                xml.push_str(&format!(r#"<tied type="{}"/>"#, kind));
+            }
+            xml.push_str("</notations>");
+        }
+        xml.push_str("</note>");
+        writeln!(self.out, "{}", xml)?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use wmidi::{Channel, Note, U7};
+    use crate::midi::MidiMessage;
+    use super::*;
+
+    fn on(ms: u64, note: Note) -> (Duration, MidiMessage) {
+        (Duration::from_millis(ms), MidiMessage::NoteOn(Channel::Ch1, note, U7::from_u8_lossy(100)))
+    }
+
+    fn off(ms: u64, note: Note) -> (Duration, MidiMessage) {
+        (Duration::from_millis(ms), MidiMessage::NoteOff(Channel::Ch1, note, U7::MIN))
+    }
+
+    fn span(start_ms: u64, duration_ms: u64, note: Note) -> NoteSpan {
+        NoteSpan {
+            start: Duration::from_millis(start_ms),
+            duration: Duration::from_millis(duration_ms),
+            channel: Channel::Ch1,
+            note,
+            velocity: U7::from_u8_lossy(100),
+        }
+    }
+
+    /// Return written note elements, with measure numbers
+    fn musicxml(events: &[(Duration, MidiMessage)], time_signature: (u8, u8)) -> Vec<String> {
+        let mut out = vec![];
+        write_musicxml(&mut out, events, Duration::from_millis(500), time_signature).unwrap();
+        String::from_utf8(out).unwrap().lines()
+            .map(str::trim)
+            .filter(|line| line.starts_with("<note>") || line.starts_with("<measure "))
+            .map(String::from)
+            .collect()
+    }
+
+    fn xml_note(step: &str, chord: bool, duration: u32, ties: &str, kind: &str) -> String {
+        let chord = if chord { "<chord/>" } else { "" };
+        let (kind, dot) = kind.split_at(kind.find('.').unwrap_or(kind.len()));
+        let dot = if dot.is_empty() { "" } else { "<dot/>" };
+        let (tie, tied) = match ties {
+            "start" | "stop" => (format!(r#"<tie type="{}"/>"#, ties), format!(r#"<notations><tied type="{}"/></notations>"#, ties)),
+            _ => (String::new(), String::new()),
+        };
+        let pitch = match step {
+            "" => "<rest/>".to_owned(),
+            step => format!("<pitch><step>{}</step><octave>4</octave></pitch>", step),
+        };
+        format!("<note>{}{}<duration>{}</duration>{}<type>{}</type>{}{}</note>", chord, pitch, duration, tie, kind, dot, tied)
+    }
+
+    #[test]
+    fn retriggered_note() {
+        let events = [on(0, Note::C4), on(100, Note::C4), off(300, Note::C4)];
+        assert_eq!(note_spans(&events), [span(0, 100, Note::C4), span(100, 200, Note::C4)]);
+    }
+
+    #[test]
+    fn note_held_past_last_event() {
+        let events = [on(0, Note::C4), on(100, Note::E4), off(250, Note::E4)];
+        assert_eq!(note_spans(&events), [span(0, 250, Note::C4), span(100, 150, Note::E4)]);
+    }
+
+    #[test]
+    fn csv() {
+        let events = [
+            on(0, Note::C4),
+            (Duration::from_millis(500), MidiMessage::NoteOn(Channel::Ch10, Note::E4, U7::MAX)),
+            off(1250, Note::C4),
+            (Duration::from_millis(1500), MidiMessage::NoteOn(Channel::Ch10, Note::E4, U7::MIN)),
+        ];
+        let mut out = vec![];
+        write_csv(&mut out, &events).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "start,duration,note,velocity,channel\n0.000,1.250,60,100,1\n0.500,1.000,64,127,10\n");
+    }
+
+    #[test]
+    fn musicxml_chord() {
+        let events = [on(0, Note::C4), on(10, Note::E4), off(480, Note::C4), off(520, Note::E4)];
+        assert_eq!(musicxml(&events, (4, 4)), [
+            r#"<measure number="1">"#.to_owned(),
+            xml_note("C", false, 4, "", "quarter"),
+            xml_note("E", true, 4, "", "quarter"),
+            xml_note("", false, 12, "", "half."),
+        ]);
+    }
+
+    #[test]
+    fn musicxml_rest() {
+        let events = [on(0, Note::C4), off(500, Note::C4), on(1000, Note::D4), off(1500, Note::D4)];
+        assert_eq!(musicxml(&events, (4, 4))[1..], [
+            xml_note("C", false, 4, "", "quarter"),
+            xml_note("", false, 4, "", "quarter"),
+            xml_note("D", false, 4, "", "quarter"),
+            xml_note("", false, 4, "", "quarter"),
+        ]);
+    }
+
+    #[test]
+    fn musicxml_tie_across_bar_line() {
+        let events = [on(1500, Note::C4), off(2500, Note::C4)];
+        assert_eq!(musicxml(&events, (4, 4)), [
+            r#"<measure number="1">"#.to_owned(),
+            xml_note("", false, 12, "", "half."),
+            xml_note("C", false, 4, "start", "quarter"),
+            r#"<measure number="2">"#.to_owned(),
+            xml_note("C", false, 4, "stop", "quarter"),
+            xml_note("", false, 12, "", "half."),
+        ]);
+    }
+
+    #[test]
+    fn musicxml_three_four() {
+        let events = [on(0, Note::C4), off(1500, Note::C4), on(1500, Note::E4), off(2000, Note::E4)];
+        assert_eq!(musicxml(&events, (3, 4)), [
+            r#"<measure number="1">"#.to_owned(),
+            xml_note("C", false, 12, "", "half."),
+            r#"<measure number="2">"#.to_owned(),
+            xml_note("E", false, 4, "", "quarter"),
+            xml_note("", false, 8, "", "half"),
+        ]);
+    }
+
+    #[test]
+    fn musicxml_zero_quarter() {
+        let mut out = vec![];
+        assert!(write_musicxml(&mut out, &[on(0, Note::C4)], Duration::ZERO, (4, 4)).is_err());
+    }
+}
@@ -0,0 +1,9 @@
+/// Cargo features of the build, and whether they are enabled
+pub const FEATURES: [(&str, bool); 6] = [
+    ("ui", cfg!(feature = "ui")),
+    ("control", cfg!(feature = "control")),
+    ("osc", cfg!(feature = "osc")),
+    ("sf3", cfg!(feature = "sf3")),
+    ("rt-priority", cfg!(feature = "rt-priority")),
+    ("async", cfg!(feature = "async")),
+];
@@ -1,13 +1,65 @@
 mod audio;
+mod audition;
+mod compressor;
+mod config;
+mod engine;
+mod error;
+mod export;
+mod features;
+mod gm;
+mod loudness;
 mod midi;
+mod normalize;
+mod mpe;
+mod notes;
+#[cfg(feature = "async")]
+mod note_stream;
 mod piano;
+mod player;
+mod processor;
+mod render;
+mod sfont;
+mod smf;
+mod snapshot;
+mod stats;
 mod synth;
+#[cfg(test)]
+mod test_sfont;
+mod visualizer;
+mod wav;
 #[cfg(feature = "ui")]
 pub mod ui;
+#[cfg(feature = "control")]
+pub mod control;
 
-pub use midi::MidiInput;
+pub use audio::{output_device_names, supported_output_configs, ConfigDescription, TimingStats};
+pub use audition::Audition;
+pub use compressor::CompConfig;
+pub use error::PianoError;
+pub use config::{Bindings, BoundControl, Config, ConfigBinding, ConfigFavorite, ConfigPreset, Favorites, KeyLayout, UiState};
+pub use features::FEATURES;
+pub use gm::{gm_program, GM_PROGRAM_NAMES};
+pub use loudness::Normalization;
+pub use midi::{ControlEvent, MidiInput, MidiMessage, MidiParser, NoteEvent, RawMidiData};
+pub use notes::{NoteNames, NoteNaming};
+#[cfg(feature = "async")]
+pub use note_stream::NoteEventStream;
 pub use piano::{
+    InputId,
     Piano,
+    PianoConfig,
     PianoInput,
+    Preset,
     PianoMidiInput,
+    PianoStdinInput,
 };
+pub use player::MidiFilePlayer;
+pub use processor::MonoConfig;
+pub use render::OfflineRender;
+pub use sfont::{find_default_sfont, resolve_sfont, sfont_search_paths};
+pub use smf::MidiFile;
+pub use snapshot::{ChannelSnapshot, StateSnapshot};
+pub use stats::NoteStats;
+pub use synth::{BackendInfo, SettingValue, Synth};
+pub use visualizer::Visualizer;
+pub use wav::WavFormat;
@@ -0,0 +1,199 @@
+/// Level rendered samples are scaled to, after rendering
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Normalization {
+    /// Peak sample level, in dBFS (e.g. `-1.0`)
+    Peak(f32),
+    /// Integrated loudness, in LUFS (e.g. `-16.0`)
+    ///
+    /// Loudness is measured as in ITU-R BS.1770. Peaks above full scale are
+    /// kept, they clip in PCM formats.
+    Loudness(f32),
+}
+
+impl Normalization {
+    /// Scale interleaved stereo samples to the target level
+    ///
+    /// Silent samples are left unchanged.
+    pub fn apply(&self, samples: &mut [f32], sample_rate: u32) {
+        let gain_db = match *self {
+            Self::Peak(target) => {
+                let peak = samples.iter().fold(0f32, |peak, sample| peak.max(sample.abs()));
+                if peak == 0.0 {
+                    return;
+                }
+                target - 20.0 * peak.log10()
+            }
+            Self::Loudness(target) => match integrated_loudness(samples, sample_rate) {
+                Some(loudness) => target - loudness,
+                None => return,
+            },
+        };
+        let gain = 10f32.powf(gain_db / 20.0);
+        samples.iter_mut().for_each(|sample| *sample *= gain);
+    }
+}
+
+/// Measure integrated loudness of interleaved stereo samples, in LUFS
+///
+/// Return `None` if samples are too short or too quiet to be measured.
+pub fn integrated_loudness(samples: &[f32], sample_rate: u32) -> Option<f32> {
+    const ABSOLUTE_GATE: f64 = -70.0;
+    const RELATIVE_GATE: f64 = -10.0;
+
+    // Mean square of K-weighted samples, per 100 ms step
+    let step = sample_rate as usize / 10;
+    if step == 0 {
+        return None;
+    }
+    let mut filters = [KWeighting::new(sample_rate as f64), KWeighting::new(sample_rate as f64)];
+    let steps: Vec<f64> = samples.chunks(step * 2)
+        .filter(|chunk| chunk.len() == step * 2)
+        .map(|chunk| {
+            let sum: f64 = chunk.chunks_exact(2)
+                .map(|frame| {
+                    let left = filters[0].process(frame[0] as f64);
+                    let right = filters[1].process(frame[1] as f64);
+                    left * left + right * right
+                })
+                .sum();
+            sum / step as f64
+        })
+        .collect();
+
+    // Gating blocks of 400 ms, overlapping by 75%
+    let blocks: Vec<f64> = steps.windows(4).map(|w| w.iter().sum::<f64>() / 4.0).collect();
+    let loudness = |power: f64| -0.691 + 10.0 * power.log10();
+    let gated_mean = |threshold: f64| {
+        let gated: Vec<f64> = blocks.iter().copied().filter(|power| loudness(*power) > threshold).collect();
+        (!gated.is_empty()).then(|| gated.iter().sum::<f64>() / gated.len() as f64)
+    };
+    let mean = gated_mean(ABSOLUTE_GATE)?;
+    let mean = gated_mean(loudness(mean) + RELATIVE_GATE)?;
+    Some(loudness(mean) as f32)
+}
+
+/// K-weighting filter of BS.1770: a high shelf followed by a high-pass
+struct KWeighting {
+    stages: [Biquad; 2],
+}
+
+impl KWeighting {
+    fn new(sample_rate: f64) -> Self {
+        use std::f64::consts::PI;
+
+        // Coefficients derived for any sample rate, as in libebur128
+        let (f0, gain_db, q) = (1_681.974_450_955_533, 3.999_843_853_973_347, 0.707_175_236_955_419_6);
+        let k = (PI * f0 / sample_rate).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.499_666_774_154_541_6);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let (f0, q) = (38.135_470_876_024_44, 0.500_327_037_323_877_3);
+        let k = (PI * f0 / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        Self { stages: [shelf, high_pass] }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        self.stages.iter_mut().fold(sample, |sample, stage| stage.process(sample))
+    }
+}
+
+/// Second-order IIR filter, direct form II transposed
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self { b, a, state: [0.0; 2] }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.state[0];
+        self.state[0] = self.b[1] * input - self.a[0] * output + self.state[1];
+        self.state[1] = self.b[2] * input - self.a[1] * output;
+        output
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{integrated_loudness, Normalization};
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    /// 997 Hz sine on the left channel, the reference signal of BS.1770
+    fn sine(amplitude: f32, duration_ms: u32) -> Vec<f32> {
+        (0..SAMPLE_RATE * duration_ms / 1000)
+            .flat_map(|i| {
+                let sample = amplitude * (2.0 * std::f32::consts::PI * 997.0 * i as f32 / SAMPLE_RATE as f32).sin();
+                [sample, 0.0]
+            })
+            .collect()
+    }
+
+    fn peak_db(samples: &[f32]) -> f32 {
+        20.0 * samples.iter().fold(0f32, |peak, sample| peak.max(sample.abs())).log10()
+    }
+
+    #[test]
+    fn sine_loudness() {
+        // A full-scale sine on one channel is at -3.01 LUFS
+        let loudness = integrated_loudness(&sine(1.0, 2000), SAMPLE_RATE).unwrap();
+        assert!((loudness + 3.01).abs() < 0.02, "{}", loudness);
+    }
+
+    #[test]
+    fn normalize_loudness() {
+        let mut samples = sine(0.1, 2000);
+        Normalization::Loudness(-16.0).apply(&mut samples, SAMPLE_RATE);
+        let peak = peak_db(&samples);
+        assert!((peak + 12.99).abs() < 0.02, "{}", peak);
+    }
+
+    #[test]
+    fn normalize_peak() {
+        let mut samples = sine(0.1, 500);
+        Normalization::Peak(-1.0).apply(&mut samples, SAMPLE_RATE);
+        let peak = peak_db(&samples);
+        assert!((peak + 1.0).abs() < 0.001, "{}", peak);
+    }
+
+    #[test]
+    fn silent_input() {
+        for normalization in [Normalization::Peak(-1.0), Normalization::Loudness(-16.0)] {
+            let mut samples = vec![0.0; 2 * SAMPLE_RATE as usize];
+            normalization.apply(&mut samples, SAMPLE_RATE);
+            assert!(samples.iter().all(|sample| *sample == 0.0));
+        }
+        assert_eq!(integrated_loudness(&[0.0; 2 * SAMPLE_RATE as usize], SAMPLE_RATE), None);
+    }
+
+    #[test]
+    fn short_input() {
+        assert_eq!(integrated_loudness(&sine(1.0, 390), SAMPLE_RATE), None);
+        assert!(integrated_loudness(&sine(1.0, 400), SAMPLE_RATE).is_some());
+        // Unchanged when it cannot be measured
+        let mut samples = sine(0.1, 390);
+        Normalization::Loudness(-16.0).apply(&mut samples, SAMPLE_RATE);
+        assert_eq!(samples, sine(0.1, 390));
+    }
+
+    #[test]
+    fn low_sample_rate() {
+        assert_eq!(integrated_loudness(&[0.5; 64], 5), None);
+    }
+}
@@ -1,7 +1,8 @@
+use std::io::IsTerminal;
 use std::path::PathBuf;
-use clap::Parser;
-use anyhow::Result;
-use pianote::{MidiInput, Piano, PianoMidiInput};
+use clap::{Parser, Subcommand};
+use anyhow::{Context, Result};
+use pianote::{Audition, Config, Favorites, MidiFile, MidiInput, NoteNaming, Piano, MonoConfig, PianoConfig, PianoMidiInput, PianoStdinInput, Preset, SettingValue, WavFormat};
 
 
 fn list_ports() -> Result<()> {
@@ -11,33 +12,326 @@ fn list_ports() -> Result<()> {
         println!("No input ports");
     } else {
         println!("Input ports");
-        for port in ports {
-            println!("  {}", port.name());
+        for (index, port) in ports.iter().enumerate() {
+            println!("  {}: {}", index, port.name());
         }
     }
     Ok(())
 }
 
+fn list_outputs() -> Result<()> {
+    let names = pianote::output_device_names()?;
+    if names.is_empty() {
+        println!("No output devices");
+    } else {
+        println!("Output devices");
+        for name in names {
+            println!("  {}", name);
+            match pianote::supported_output_configs(Some(&name)) {
+                Ok(configs) => configs.iter().for_each(|config| println!("    {}", config)),
+                Err(err) => println!("    cannot get configurations: {}", err),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print build information, known at compile time
+fn capabilities() {
+    println!("pianote {}", env!("CARGO_PKG_VERSION"));
+    let yes_no = |v| if v { "yes" } else { "no" };
+    for (name, enabled) in pianote::FEATURES {
+        println!("  {}: {}", name, yes_no(enabled));
+    }
+    println!("{}", pianote::Synth::backend_info());
+}
+
+/// Print information useful to diagnose issues
+///
+/// Failures are printed, so that remaining information is still shown.
+fn diagnose() {
+    capabilities();
+    list_ports().unwrap_or_else(|err| println!("Cannot list input ports: {}", err));
+    list_outputs().unwrap_or_else(|err| println!("Cannot list output devices: {}", err));
+    audio_timing().unwrap_or_else(|err| println!("Cannot measure audio callback timing: {}", err));
+}
+
+/// Measure time spent in the audio callback, on the default output
+fn audio_timing() -> Result<()> {
+    let piano = Piano::with_config(PianoConfig::default())?;
+    piano.play()?;
+    std::thread::sleep(std::time::Duration::from_secs(1));
+    println!("Audio callback timing: {}", piano.timing_stats());
+    Ok(())
+}
+
+/// Parse `left,right` output channels, 1-based
+fn parse_output_channels(s: &str) -> Result<[usize; 2]> {
+    let (left, right) = s.split_once(',').context("expected LEFT,RIGHT")?;
+    let parse = |v: &str| -> Result<usize> {
+        match v.trim().parse::<usize>()? {
+            0 => anyhow::bail!("channel numbers start at 1"),
+            n => Ok(n - 1),
+        }
+    };
+    Ok([parse(left)?, parse(right)?])
+}
+
+/// Parse a `bank:num` preset
+fn parse_preset(s: &str) -> Result<Preset> {
+    let (bank, num) = s.split_once(':').context("expected BANK:NUM")?;
+    Ok(Preset {
+        bank: bank.trim().parse().context("invalid bank")?,
+        num: num.trim().parse().context("invalid preset number")?,
+    })
+}
+
+/// Parse a `key=value` synth setting
+fn parse_setting(s: &str) -> Result<(String, SettingValue)> {
+    let (key, value) = s.split_once('=').context("expected KEY=VALUE")?;
+    Ok((key.trim().into(), value.trim().parse()?))
+}
+
 
 #[derive(Parser)]
 struct Cli {
-    /// Input port to use, `NONE` to disable input (default: first input)
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Load settings from a TOML file, overridden by command line options
+    #[arg(long, name = "CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Input port name or index to use, `NONE` to disable input, `STDIN` to read raw MIDI bytes (default: first input)
     #[arg(short, long, name = "NAME")]
     input: Option<String>,
 
-    /// SoundFont file to use
-    #[arg(short, long, name = "FILE")]
+    /// SoundFont file to use, or name to search in SoundFont directories (default: system GM SoundFont)
+    #[arg(short, long, name = "FILE", global = true)]
     sound_font: Option<PathBuf>,
 
+    /// Synth gain [default: 1.5]
+    #[arg(long)]
+    gain: Option<f32>,
+
+    /// Audio output device name (default: system default device)
+    #[arg(long, name = "DEVICE")]
+    output_device: Option<String>,
+
+    /// Device channels receiving left and right output, 1-based [default: 1,2]
+    #[arg(long, name = "LEFT,RIGHT", value_parser = parse_output_channels)]
+    output_channels: Option<[usize; 2]>,
+
+    /// Output sample rate (default: highest supported one)
+    #[arg(long, name = "RATE")]
+    sample_rate: Option<u32>,
+
+    /// Maximum number of voices played at once, voices are stopped past it [default: 256]
+    #[arg(long)]
+    polyphony: Option<u32>,
+
+    /// Pitch change of a full pitch bend, in semitones [default: 2]
+    #[arg(long, name = "SEMITONES")]
+    pitch_bend_range: Option<u8>,
+
+    /// MIDI file to play
+    #[arg(long, name = "MIDI_FILE")]
+    play: Option<PathBuf>,
+
+    /// Select a favorite preset of the SoundFont, 1 for the first one
+    #[arg(long, name = "N")]
+    favorite: Option<usize>,
+
+    /// Compensate the loudness of presets, measured when they are selected
+    #[arg(long)]
+    normalize_presets: bool,
+
+    /// Select a General MIDI instrument by name (e.g. "Acoustic Grand Piano"), on bank 0
+    #[arg(long, name = "INSTRUMENT")]
+    instrument: Option<String>,
+
+    /// Play a built-in tune with the first presets of the SoundFont, then exit
+    #[arg(long)]
+    demo: bool,
+
+    /// Number of metronome bars played before the MIDI file [default: 0]
+    #[arg(long, name = "BARS")]
+    count_in: Option<u32>,
+
+    /// Playback speed of the MIDI file, 0.5 for half speed [default: 1.0]
+    #[arg(long, name = "SCALE")]
+    tempo_scale: Option<f32>,
+
+    /// Transpose notes of all channels, in semitones [default: 0]
+    #[arg(long, value_name = "SEMITONES", allow_negative_numbers = true)]
+    transpose: Option<i8>,
+
+    /// Enable MPE mode: route each note to its own channel
+    #[arg(long)]
+    mpe: bool,
+
+    /// Play one note at a time on each channel, resuming held notes on release
+    #[arg(long)]
+    mono: bool,
+
+    /// With --mono, glide between successive notes
+    #[arg(long, value_name = "MS", requires = "mono")]
+    portamento: Option<u64>,
+
+    /// Octave number of middle C, used to name notes [default: 4]
+    #[arg(long, name = "OCTAVE", allow_negative_numbers = true, value_parser = clap::value_parser!(i8).range(-2..=8))]
+    middle_c_octave: Option<i8>,
+
+    /// Change a FluidSynth setting (e.g. `synth.reverb.active=no`), can be repeated
+    #[arg(long = "set", name = "KEY=VALUE", value_parser = parse_setting)]
+    settings: Vec<(String, SettingValue)>,
+
     /// List ports and exit
     #[arg(long)]
     list_ports: bool,
 
+    /// Print messages received on the MIDI input, without playing them
+    #[arg(long)]
+    monitor: bool,
+
+    /// Log manufacturer ID and length of dropped SysEx messages
+    #[arg(long)]
+    log_sysex: bool,
+
+    /// With --monitor, also print raw bytes in hexadecimal
+    #[arg(long, requires = "monitor")]
+    raw: bool,
+
+    /// List audio output devices with their supported configurations, and exit
+    #[arg(long)]
+    list_outputs: bool,
+
+    /// Print enabled features and synth backend information, and exit
+    #[arg(long)]
+    capabilities: bool,
+
+    /// Print synth backend information, ports and audio outputs, and exit
+    #[arg(long)]
+    diagnose: bool,
+
+    /// Play a test tone to check audio output, then exit
+    #[arg(long)]
+    test_tone: bool,
+
+    /// Note of the test tone (e.g. `A4`, `C#5`)
+    #[arg(long, name = "NOTE", default_value = "A4")]
+    test_note: String,
+
+    /// Listen for control commands on a loopback TCP address or Unix socket, implies headless
+    #[cfg(feature = "control")]
+    #[arg(long, name = "ADDR")]
+    control_socket: Option<String>,
+
+    /// Listen for OSC messages on an UDP address, or a port on loopback, implies headless
+    #[cfg(feature = "osc")]
+    #[arg(long, name = "UDP_ADDR")]
+    osc: Option<String>,
+
+    /// Request real-time scheduling for the audio thread, to reduce dropouts (requires the rt-priority feature)
+    #[arg(long)]
+    rt_priority: bool,
+
+    /// Silence audio output if the program crashes
+    #[arg(long)]
+    silence_on_panic: bool,
+
+    /// Duration rendered silently when playback starts, to avoid clipping the first note [default: 50]
+    #[arg(long, name = "MS")]
+    preroll: Option<u64>,
+
+    /// Duration of the gain ramp when playback starts or resumes, to avoid a pop [default: 50]
+    #[arg(long, value_name = "MS")]
+    fade_in: Option<u64>,
+
+    /// Disable reverb and chorus, to reduce CPU usage
+    #[arg(long)]
+    no_effects: bool,
+
     /// Run headless (no UI), implied if compiled without it
     #[arg(long)]
     headless: bool,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Render a single note of a preset to a WAV file, without effects
+    Audition {
+        /// Preset to play
+        #[arg(long, name = "BANK:NUM", value_parser = parse_preset)]
+        preset: Preset,
+
+        /// Note to play (e.g. `C4`)
+        #[arg(long, default_value = "C4")]
+        note: String,
+
+        /// Note-on velocity
+        #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u8).range(1..=127))]
+        velocity: u8,
+
+        /// Time the note is held, in seconds, followed by one second of release
+        #[arg(long, name = "SECONDS", default_value_t = 2.0)]
+        duration: f32,
+
+        /// Output sample rate
+        #[arg(long, name = "RATE", default_value_t = Audition::DEFAULT_SAMPLE_RATE)]
+        sample_rate: u32,
+
+        /// WAV file to write
+        #[arg(long, name = "WAV_FILE")]
+        out: PathBuf,
+
+        /// WAV sample format: 16, 24 or float
+        #[arg(long, default_value = "16")]
+        format: WavFormat,
+    },
+}
+
+/// Print messages received on a MIDI input port, forever
+fn monitor(port: Option<&str>, raw: bool) -> Result<()> {
+    let midi = MidiInput::new()?;
+    let port = midi.find_port(port)?;
+    println!("Monitoring {}", port.name());
+    let (tx, rx) = std::sync::mpsc::channel();
+    let _source = midi.connect_raw_queue(port, tx)?;
+    for data in rx {
+        let messages = data.messages.iter().map(|message| format!("{:?}", message)).collect::<Vec<_>>().join(", ");
+        if raw {
+            println!("{:<12} {}", data.hex(), messages);
+        } else if !messages.is_empty() {
+            println!("{}", messages);
+        }
+    }
+    Ok(())
+}
+
+/// Play the built-in demo tune with the first presets
+fn run_demo(piano: &Piano) -> Result<()> {
+    const DEMO_PRESETS: usize = 4;
+    /// Time left for the last notes to ring
+    const RELEASE: std::time::Duration = std::time::Duration::from_secs(1);
+
+    let presets: Vec<_> = piano.presets_data().iter()
+        .take(DEMO_PRESETS)
+        .map(|data| (Preset { bank: data.bank, num: data.num }, data.name.clone()))
+        .collect();
+    anyhow::ensure!(!presets.is_empty(), "--demo requires a SoundFont with presets");
+    for (preset, name) in presets {
+        println!("Playing demo with preset {}:{} {}", preset.bank, preset.num, name.as_deref().unwrap_or(""));
+        piano.set_active_preset(preset)?;
+        let player = piano.play_midi_file(MidiFile::demo(), 0)?;
+        while !player.is_finished() {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        std::thread::sleep(RELEASE);
+    }
+    Ok(())
+}
+
 /// Run without UI
 fn run_headless() {
     println!("Playing...");
@@ -54,26 +348,186 @@ fn main() -> Result<()> {
         list_ports()?;
         return Ok(());
     }
+    if cli.list_outputs {
+        list_outputs()?;
+        return Ok(());
+    }
+    if cli.capabilities {
+        capabilities();
+        return Ok(());
+    }
+    if cli.diagnose {
+        diagnose();
+        return Ok(());
+    }
+
+    let config = match cli.config {
+        Some(ref path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if let Some(Command::Audition { preset, note, velocity, duration, sample_rate, out, format }) = cli.command {
+        let naming = NoteNaming {
+            middle_c_octave: cli.middle_c_octave.or(config.middle_c_octave).unwrap_or(NoteNaming::default().middle_c_octave),
+            names: config.note_names.unwrap_or_default(),
+        };
+        let sfont = match cli.sound_font.or(config.sound_font) {
+            Some(name) => pianote::resolve_sfont(name)?,
+            None => pianote::find_default_sfont().context("no SoundFont provided or found")?,
+        };
+        let audition = Audition {
+            preset,
+            note: naming.parse_note(&note)?,
+            velocity: wmidi::U7::from_u8_lossy(velocity),
+            duration: std::time::Duration::try_from_secs_f32(duration).context("invalid duration")?,
+            release: Audition::DEFAULT_RELEASE,
+            sample_rate,
+        };
+        audition.write_wav(&sfont, &out, format)?;
+        println!("Rendered preset {}:{} from {} to {}", preset.bank, preset.num, sfont.display(), out.display());
+        return Ok(());
+    }
+    if cli.monitor {
+        return monitor(cli.input.as_deref().or(config.input.as_deref()), cli.raw);
+    }
+    let favorites = Favorites::new(cli.config.clone(), &config);
+    #[cfg(feature = "ui")]
+    let bindings = pianote::Bindings::new(cli.config.clone(), &config);
+    #[cfg(feature = "ui")]
+    let ui_state = pianote::UiState::new(cli.config.clone(), &config);
+
+    let mut piano = Piano::with_config(PianoConfig {
+        gain: cli.gain.or(config.gain).unwrap_or(Piano::DEFAULT_GAIN),
+        output_device: cli.output_device.or(config.output_device.clone()),
+        output_channels: cli.output_channels.or(config.output_channels()).unwrap_or([0, 1]),
+        sample_rate: cli.sample_rate.or(config.sample_rate),
+        effects: !cli.no_effects && config.effects.unwrap_or(true),
+        realtime_priority: cli.rt_priority,
+        preroll: cli.preroll.or(config.preroll_ms).map_or(Piano::DEFAULT_PREROLL, std::time::Duration::from_millis),
+        fade_in: cli.fade_in.or(config.fade_in_ms).map_or(Piano::DEFAULT_FADE_IN, std::time::Duration::from_millis),
+        silence_on_panic: cli.silence_on_panic,
+    })?;
+    let middle_c_octave = cli.middle_c_octave.or(config.middle_c_octave).unwrap_or(NoteNaming::default().middle_c_octave);
+    let names = config.note_names.unwrap_or_default();
+    piano.set_note_naming(NoteNaming { middle_c_octave, names });
+    piano.set_polyphony(cli.polyphony.or(config.polyphony).unwrap_or(pianote::Synth::DEFAULT_POLYPHONY))?;
+    if let Some(semitones) = cli.pitch_bend_range.or(config.pitch_bend_range) {
+        piano.set_pitch_bend_range(semitones)?;
+    }
+    // Settings from the command line are applied last, to override config ones
+    for (key, value) in config.synth_settings()?.iter().chain(&cli.settings) {
+        piano.set_synth_setting(key, value)?;
+    }
+
+    if cli.test_tone {
+        let note = piano.note_naming().parse_note(&cli.test_note)?;
+        println!("Playing test tone...");
+        piano.set_test_tone(Some(note))?;
+        piano.play()?;
+        std::thread::sleep(std::time::Duration::from_secs(2));
+        return Ok(());
+    }
 
-    let mut piano = Piano::new()?;
-    match cli.input.as_deref() {
+    match cli.input.as_deref().or(config.input.as_deref()) {
         Some("NONE") => {}
-        input => piano.set_input(PianoMidiInput(input))?,
+        Some("STDIN") => piano.set_input(PianoStdinInput { log_sysex: cli.log_sysex })?,
+        input => piano.set_input(PianoMidiInput { port: input, log_sysex: cli.log_sysex })?,
     };
 
-    if let Some(path) = cli.sound_font {
-        piano.load_sfont(path)?;
+    let sfont_path = match cli.sound_font.or(config.sound_font) {
+        Some(name) => Some(pianote::resolve_sfont(name)?),
+        None => pianote::find_default_sfont(),
+    };
+    if let Some(path) = sfont_path {
+        println!("Using SoundFont {}", path.display());
+        piano.set_normalize_presets(cli.normalize_presets)?;
+        // Enumeration of large fonts can take a while, show its progress
+        let show_progress = std::io::stderr().is_terminal();
+        piano.load_sfont_with_progress(&path, |scanned, total| {
+            if show_progress {
+                eprint!("\rScanning presets {:3}%", scanned * 100 / total);
+                if scanned == total {
+                    eprintln!();
+                }
+            }
+        })?;
+        if let Some(preset) = config.preset {
+            piano.set_active_preset(preset.into())?;
+        }
+        if let Some(n) = cli.favorite {
+            let presets = favorites.presets(&path);
+            let preset = n.checked_sub(1).and_then(|i| presets.get(i))
+                .with_context(|| format!("no favorite {} for this SoundFont, {} available", n, presets.len()))?;
+            piano.set_active_preset(*preset)?;
+        }
+        if let Some(ref name) = cli.instrument {
+            piano.set_gm_instrument(name)?;
+        }
     } else {
-        println!("No SoundFont provided, using system default (if any)");
+        println!("No SoundFont provided or found, no sound will be produced");
+    }
+    if let Some(semitones) = cli.transpose.or(config.transpose) {
+        piano.set_transpose(None, semitones)?;
+    }
+    if cli.mpe || config.mpe.unwrap_or(false) {
+        piano.set_mpe(true)?;
+    }
+    if cli.mono {
+        for channel in (0..16).map(|i| wmidi::Channel::from_index(i).unwrap()) {
+            piano.set_mono_mode(channel, MonoConfig::Legato)?;
+            if let Some(time) = cli.portamento {
+                piano.set_portamento(channel, Some(std::time::Duration::from_millis(time)))?;
+            }
+        }
     }
     piano.play()?;
+    if cli.demo {
+        return run_demo(&piano);
+    }
+    let count_in_bars = cli.count_in.or(config.count_in_bars).unwrap_or(0);
+    let player = cli.play
+        .map(|path| piano.play_file_with_count_in(path, count_in_bars))
+        .transpose()?;
+    if let Some(scale) = cli.tempo_scale {
+        player.as_ref().context("--tempo-scale requires --play")?.set_tempo_scale(scale)?;
+    }
 
-    if cli.headless || !cfg!(feature = "ui") {
-        run_headless();
-    } else {
-        pianote::ui::run(piano)?;
+    #[cfg(feature = "control")]
+    {
+        let server = pianote::control::ControlServer::new(piano.note_naming());
+        let mut listening = false;
+        if let Some(addr) = cli.control_socket {
+            server.listen(&addr)?;
+            println!("Listening for control commands on {}", addr);
+            listening = true;
+        }
+        #[cfg(feature = "osc")]
+        if let Some(addr) = cli.osc {
+            server.listen_osc(&addr)?;
+            println!("Listening for OSC messages on {}", addr);
+            listening = true;
+        }
+        if listening {
+            return Ok(server.run(&mut piano)?);
+        }
     }
 
+    #[cfg(feature = "ui")]
+    let (piano, player) = if cli.headless {
+        (piano, player)
+    } else {
+        match pianote::ui::run(piano, player, favorites, bindings, ui_state) {
+            Err(pianote::ui::UiError::NoDisplay(piano, player)) => {
+                println!("No display available, running headless");
+                (*piano, player)
+            }
+            Err(pianote::ui::UiError::Iced(err)) => return Err(err.into()),
+            Ok(()) => return Ok(()),
+        }
+    };
+    run_headless();
+    drop(player);
+    drop(piano);
+
     Ok(())
 }
 
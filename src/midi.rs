@@ -1,68 +1,395 @@
 use std::sync::mpsc::Sender;
 use anyhow::{Context, Result};
+use crate::error::PianoError;
 
 pub type MidiMessage = wmidi::MidiMessage<'static>;
 
+/// Note event, extracted from MIDI messages
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum NoteEvent {
+    On { channel: wmidi::Channel, note: wmidi::Note, velocity: u8 },
+    Off { channel: wmidi::Channel, note: wmidi::Note },
+}
+
+/// Control change, extracted from input MIDI messages
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct ControlEvent {
+    pub channel: wmidi::Channel,
+    pub control: wmidi::ControlFunction,
+    pub value: u8,
+}
+
 pub struct MidiInput {
     midi: midir::MidiInput,
+    /// Name of the input port created when connecting
+    connection_name: String,
+    /// Whether dropped SysEx messages are logged
+    log_sysex: bool,
 }
 
-pub struct MidiInputPort(String);
+/// MIDI input port, with its position in the port list
+///
+/// The position distinguishes ports with the same name.
+pub struct MidiInputPort {
+    name: String,
+    index: usize,
+}
 
-pub struct MidiSource(midir::MidiInputConnection<()>);
+/// Connected MIDI source, disconnected when dropped
+pub struct MidiSource(#[allow(dead_code)] midir::MidiInputConnection<()>);
 
 impl MidiInput {
-    pub fn new() -> Result<Self> {
-        let midi = midir::MidiInput::new("midi-input")?;
-        Ok(Self { midi })
+    pub fn new() -> Result<Self, PianoError> {
+        Self::with_name("midi-input")
+    }
+
+    /// Create an input with the given client name, shown in port lists
+    pub fn with_name(name: &str) -> Result<Self, PianoError> {
+        let midi = midir::MidiInput::new(name).map_err(anyhow::Error::from)?;
+        Ok(Self { midi, connection_name: "input".into(), log_sysex: false })
+    }
+
+    /// Change the name of the input port created when connecting
+    pub fn with_connection_name(mut self, name: &str) -> Self {
+        self.connection_name = name.into();
+        self
+    }
+
+    /// Log SysEx messages dropped by `connect_queue()`, instead of silently ignoring them
+    pub fn with_sysex_log(mut self, enabled: bool) -> Self {
+        self.log_sysex = enabled;
+        self
     }
 
     pub fn default_port(&self) -> Option<MidiInputPort> {
         self.ports().ok().and_then(|ports| ports.into_iter().next())
     }
 
-    pub fn ports(&self) -> Result<Vec<MidiInputPort>> {
+    pub fn ports(&self) -> Result<Vec<MidiInputPort>, PianoError> {
         let ports = self.midi
             .ports()
             .into_iter()
             // 'port_name()' fails if port is not available anymore, ignore error
             .filter_map(move |p| self.midi.port_name(&p).ok())
-            .map(MidiInputPort)
+            .enumerate()
+            .map(|(index, name)| MidiInputPort { name, index })
             .collect();
         Ok(ports)
     }
 
-    pub fn connect_callback<F>(self, port: MidiInputPort, mut callback: F) -> Result<MidiSource>
+    pub fn connect_callback<F>(self, port: MidiInputPort, mut callback: F) -> Result<MidiSource, PianoError>
     where
         F: FnMut(&[u8]) + Send + 'static,
     {
-        let port_impl = self.midi
-            .ports()
-            .into_iter()
-            .find(|p| self.midi.port_name(p).ok().as_ref() == Some(&port.0))
+        // Prefer the port at the same position, in case several ones have the same name
+        let ports = self.midi.ports();
+        let port_impl = ports.get(port.index)
+            .filter(|p| self.midi.port_name(p).ok().as_ref() == Some(&port.name))
+            .or_else(|| ports.iter().find(|p| self.midi.port_name(p).ok().as_ref() == Some(&port.name)))
             .context("cannot find port")?;
         let connection = self.midi.connect(
-            &port_impl,
-            "input",
+            port_impl,
+            &self.connection_name,
             move |_, data, ()| { callback(data); },
             (),
-        )?;
+        ).map_err(|err| anyhow::anyhow!("failed to connect MIDI input: {}", err))?;
         Ok(MidiSource(connection))
     }
 
-    pub fn connect_queue(self, port: MidiInputPort, queue: Sender<MidiMessage>) -> Result<MidiSource> {
+    /// Find a port by name or index, or return the default one
+    ///
+    /// Names take precedence over indexes. Names shared by several ports are
+    /// rejected, as the port to use would be arbitrary.
+    pub fn find_port(&self, name: Option<&str>) -> Result<MidiInputPort, PianoError> {
+        let Some(name) = name else {
+            return self.default_port().ok_or(PianoError::PortNotFound(None));
+        };
+        let mut ports = self.ports()?;
+        let matching: Vec<usize> = ports.iter().filter(|p| p.name() == name).map(|p| p.index).collect();
+        if matching.len() > 1 {
+            let indexes = matching.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", ");
+            Err(anyhow::anyhow!("several MIDI input ports are named {:?}, select one by index: {}", name, indexes).into())
+        } else if let Some(&index) = matching.first() {
+            Ok(ports.swap_remove(index))
+        } else if let Ok(index) = name.parse::<usize>() {
+            let count = ports.len();
+            let port = ports.into_iter().nth(index)
+                .with_context(|| format!("MIDI input port index out of range: {} ({} ports)", index, count))?;
+            Ok(port)
+        } else {
+            Err(PianoError::PortNotFound(Some(name.into())))
+        }
+    }
+
+    pub fn connect_queue(self, port: MidiInputPort, queue: Sender<MidiMessage>) -> Result<MidiSource, PianoError> {
+        let mut parser = MidiParser::new();
+        parser.set_log_sysex(self.log_sysex);
         self.connect_callback(port, move |data| {
-            let message = wmidi::MidiMessage::try_from(data).expect("failed to parse MIDI message");
-            if let Some(message) = message.drop_unowned_sysex() {
+            // Some drivers send several messages at once
+            parser.parse(data, |message| {
                 queue.send(message).expect("failed to send MIDI message to the queue");
-            }
+            });
+        })
+    }
+
+    /// Connect to a port, queue received data along with its parsed messages
+    ///
+    /// This is intended to debug controllers: dropped data, such as SysEx,
+    /// is still queued.
+    pub fn connect_raw_queue(self, port: MidiInputPort, queue: Sender<RawMidiData>) -> Result<MidiSource, PianoError> {
+        let mut parser = MidiParser::new();
+        self.connect_callback(port, move |data| {
+            let mut messages = vec![];
+            parser.parse(data, |message| messages.push(message));
+            let _ = queue.send(RawMidiData { bytes: data.to_vec(), messages });
         })
     }
 }
 
+/// MIDI data received at once, with the messages parsed from it
+///
+/// Messages split over several buffers are returned with the last one.
+#[derive(Clone, Debug)]
+pub struct RawMidiData {
+    pub bytes: Vec<u8>,
+    pub messages: Vec<MidiMessage>,
+}
+
+impl RawMidiData {
+    /// Return bytes as space-separated hexadecimal values
+    pub fn hex(&self) -> String {
+        self.bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ")
+    }
+}
+
+/// Split raw MIDI data in messages
+///
+/// Data may contain several messages, use running status, or interleave
+/// real-time messages. State is kept between calls, for messages split over
+/// several buffers.
+pub struct MidiParser {
+    /// Status of the message being received, kept for running status
+    status: Option<u8>,
+    /// Data bytes of the message being received
+    data: [u8; 2],
+    data_len: usize,
+    /// SysEx message being received, its data is skipped
+    sysex: Option<SysexInfo>,
+    /// Whether dropped SysEx messages are logged
+    log_sysex: bool,
+}
+
+/// Summary of a SysEx message, its data is not kept
+struct SysexInfo {
+    /// Manufacturer ID, 1 or 3 bytes
+    id: Vec<u8>,
+    /// Length of the message, including start and end bytes
+    len: usize,
+}
+
+impl SysexInfo {
+    fn push(&mut self, byte: u8) {
+        self.len += 1;
+        // Extended IDs start with a zero byte
+        let id_len = if self.id.first() == Some(&0) { 3 } else { 1 };
+        if self.id.len() < id_len {
+            self.id.push(byte);
+        }
+    }
+}
+
+impl MidiParser {
+    pub fn new() -> Self {
+        Self { status: None, data: [0; 2], data_len: 0, sysex: None, log_sysex: false }
+    }
+
+    /// Print manufacturer ID and length of dropped SysEx messages
+    pub fn set_log_sysex(&mut self, enabled: bool) {
+        self.log_sysex = enabled;
+    }
+
+    /// Parse MIDI data, call `emit` for each complete message
+    ///
+    /// SysEx messages, undefined messages and stray data bytes are dropped.
+    /// SysEx messages interrupted by another status byte are dropped too.
+    pub fn parse<E>(&mut self, bytes: &[u8], mut emit: E)
+    where
+        E: FnMut(MidiMessage),
+    {
+        let mut emit_bytes = |bytes: &[u8]| {
+            if let Some(message) = wmidi::MidiMessage::try_from(bytes).ok().and_then(|m| m.drop_unowned_sysex()) {
+                emit(message);
+            }
+        };
+        for &byte in bytes {
+            match byte {
+                // Real-time messages may appear anywhere, and do not change the state
+                0xf8..=0xff => emit_bytes(&[byte]),
+                0xf0 => {
+                    self.end_sysex();
+                    self.sysex = Some(SysexInfo { id: vec![], len: 1 });
+                    self.status = None;
+                }
+                0xf7 => {
+                    if let Some(ref mut sysex) = self.sysex {
+                        sysex.len += 1;
+                    }
+                    self.end_sysex();
+                    self.status = None;
+                }
+                0x80..=0xf6 => {
+                    self.end_sysex();
+                    self.data_len = 0;
+                    if Self::data_len(byte) == 0 {
+                        emit_bytes(&[byte]);
+                        self.status = None;
+                    } else {
+                        self.status = Some(byte);
+                    }
+                }
+                _ => if let Some(ref mut sysex) = self.sysex {
+                    sysex.push(byte);
+                } else if let Some(status) = self.status {
+                    self.data[self.data_len] = byte;
+                    self.data_len += 1;
+                    if self.data_len == Self::data_len(status) {
+                        let mut message = [status, 0, 0];
+                        message[1..=self.data_len].copy_from_slice(&self.data[..self.data_len]);
+                        emit_bytes(&message[..=self.data_len]);
+                        self.data_len = 0;
+                        // Running status only applies to channel messages
+                        if status >= 0xf0 {
+                            self.status = None;
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    /// Drop the SysEx message being received, if any
+    fn end_sysex(&mut self) {
+        if let Some(sysex) = self.sysex.take() {
+            if self.log_sysex {
+                let id = sysex.id.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ");
+                eprintln!("dropped SysEx message: manufacturer ID {}, {} bytes", if id.is_empty() { "none" } else { &id }, sysex.len);
+            }
+        }
+    }
+
+    /// Return the number of data bytes of a message
+    fn data_len(status: u8) -> usize {
+        match status {
+            0xc0..=0xdf | 0xf1 | 0xf3 => 1,
+            0x80..=0xef | 0xf2 => 2,
+            _ => 0,
+        }
+    }
+}
+
+impl Default for MidiParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MidiInputPort {
     pub fn name(&self) -> &str {
-        &self.0
+        &self.name
+    }
+
+    /// Return the position of the port in `MidiInput::ports()`
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+
+impl ControlEvent {
+    /// Get the control event of a MIDI message, if any
+    pub fn from_message(message: &MidiMessage) -> Option<Self> {
+        match *message {
+            MidiMessage::ControlChange(channel, control, value) => Some(Self { channel, control, value: value.into() }),
+            _ => None,
+        }
     }
 }
 
+impl NoteEvent {
+    /// Get the note event of a MIDI message, if any
+    ///
+    /// Note-on messages with a zero velocity are handled as note-off.
+    pub fn from_message(message: &MidiMessage) -> Option<Self> {
+        match *message {
+            MidiMessage::NoteOn(channel, note, vel) if u8::from(vel) > 0 => {
+                Some(Self::On { channel, note, velocity: vel.into() })
+            }
+            MidiMessage::NoteOn(channel, note, _) | MidiMessage::NoteOff(channel, note, _) => {
+                Some(Self::Off { channel, note })
+            }
+            _ => None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use wmidi::{Channel, ControlFunction, Note, U7};
+    use super::{MidiMessage, MidiParser};
+
+    fn parse(parser: &mut MidiParser, bytes: &[u8]) -> Vec<MidiMessage> {
+        let mut messages = vec![];
+        parser.parse(bytes, |message| messages.push(message));
+        messages
+    }
+
+    fn note_on(note: Note, velocity: u8) -> MidiMessage {
+        MidiMessage::NoteOn(Channel::Ch1, note, U7::from_u8_lossy(velocity))
+    }
+
+    #[test]
+    fn running_status() {
+        let mut parser = MidiParser::new();
+        assert_eq!(parse(&mut parser, &[0x90, 60, 100, 62, 90, 60, 0]), [
+            note_on(Note::C4, 100),
+            note_on(Note::D4, 90),
+            // Converted by wmidi
+            MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::MIN),
+        ]);
+        // Kept between calls
+        assert_eq!(parse(&mut parser, &[64, 80]), [note_on(Note::E4, 80)]);
+        // Cancelled by system common messages
+        assert_eq!(parse(&mut parser, &[0xf6, 64, 80]), [MidiMessage::TuneRequest]);
+    }
+
+    #[test]
+    fn real_time_in_message() {
+        let mut parser = MidiParser::new();
+        assert_eq!(parse(&mut parser, &[0xb0, 0xf8, 7, 0xfe, 100]), [
+            MidiMessage::TimingClock,
+            MidiMessage::ActiveSensing,
+            MidiMessage::ControlChange(Channel::Ch1, ControlFunction::CHANNEL_VOLUME, U7::from_u8_lossy(100)),
+        ]);
+    }
+
+    #[test]
+    fn real_time_in_sysex() {
+        let mut parser = MidiParser::new();
+        assert_eq!(parse(&mut parser, &[0xf0, 0x43, 0x10, 0xf8, 0x4c, 0xf7, 0x90, 60, 100]), [
+            MidiMessage::TimingClock,
+            note_on(Note::C4, 100),
+        ]);
+        // SysEx data is dropped, not parsed with running status
+        assert_eq!(parse(&mut parser, &[0xf0, 60, 100, 0xf7, 62, 90]), []);
+    }
+
+    #[test]
+    fn message_split_over_calls() {
+        let mut parser = MidiParser::new();
+        assert_eq!(parse(&mut parser, &[0x90]), []);
+        assert_eq!(parse(&mut parser, &[60]), []);
+        assert_eq!(parse(&mut parser, &[100, 0x80, 60]), [note_on(Note::C4, 100)]);
+        assert_eq!(parse(&mut parser, &[64]), [MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::from_u8_lossy(64))]);
+    }
+}
@@ -0,0 +1,215 @@
+use wmidi::Channel;
+use crate::midi::MidiMessage;
+
+
+/// Route each note to its own channel, so per-note pitch bends can be applied
+///
+/// Channel-wide messages of an input member channel are sent to each output
+/// channel playing one of its notes. Controllers and program changes of the
+/// master channel are sent to all output channels.
+pub struct MpeRouter {
+    /// Active notes: input channel, note, output channel
+    notes: Vec<(Channel, wmidi::Note, Channel)>,
+    /// Last pitch bend value of each input channel
+    pitch_bends: [wmidi::U14; 16],
+    /// Index of the next output channel to try
+    next_channel: usize,
+}
+
+impl MpeRouter {
+    /// Output channels notes are routed to
+    ///
+    /// The drum channel is excluded.
+    pub const CHANNELS: [Channel; 15] = [
+        Channel::Ch1, Channel::Ch2, Channel::Ch3, Channel::Ch4, Channel::Ch5,
+        Channel::Ch6, Channel::Ch7, Channel::Ch8, Channel::Ch9, Channel::Ch11,
+        Channel::Ch12, Channel::Ch13, Channel::Ch14, Channel::Ch15, Channel::Ch16,
+    ];
+
+    /// Input channel of zone-wide messages, as in the MPE lower zone
+    pub const MASTER_CHANNEL: Channel = Channel::Ch1;
+
+    pub fn new() -> Self {
+        Self {
+            notes: vec![],
+            pitch_bends: [wmidi::U14::try_from(0x2000).unwrap(); 16],
+            next_channel: 0,
+        }
+    }
+
+    /// Route a message, call `emit` with resulting messages
+    pub fn route<E>(&mut self, message: MidiMessage, mut emit: E)
+    where
+        E: FnMut(MidiMessage),
+    {
+        match message {
+            MidiMessage::NoteOn(chan, note, vel) if u8::from(vel) > 0 => {
+                // Retrigger on the same channel if the note is already playing
+                let out_chan = match self.find_note(chan, note) {
+                    Some(index) => self.notes.remove(index).2,
+                    None => self.allocate_channel(&mut emit),
+                };
+                self.notes.push((chan, note, out_chan));
+                emit(MidiMessage::PitchBendChange(out_chan, self.pitch_bends[chan.index() as usize]));
+                emit(MidiMessage::NoteOn(out_chan, note, vel));
+            }
+            MidiMessage::NoteOn(chan, note, vel) | MidiMessage::NoteOff(chan, note, vel) => {
+                if let Some(index) = self.find_note(chan, note) {
+                    let (_, _, out_chan) = self.notes.remove(index);
+                    emit(MidiMessage::NoteOff(out_chan, note, vel));
+                }
+            }
+            MidiMessage::PolyphonicKeyPressure(chan, note, vel) => {
+                if let Some(index) = self.find_note(chan, note) {
+                    emit(MidiMessage::PolyphonicKeyPressure(self.notes[index].2, note, vel));
+                }
+            }
+            MidiMessage::PitchBendChange(chan, value) => {
+                self.pitch_bends[chan.index() as usize] = value;
+                self.for_each_output_channel(chan, |out_chan| emit(MidiMessage::PitchBendChange(out_chan, value)));
+            }
+            MidiMessage::ChannelPressure(chan, vel) => {
+                self.for_each_output_channel(chan, |out_chan| emit(MidiMessage::ChannelPressure(out_chan, vel)));
+            }
+            MidiMessage::ControlChange(Self::MASTER_CHANNEL, ctrl, val) => {
+                for out_chan in Self::CHANNELS {
+                    emit(MidiMessage::ControlChange(out_chan, ctrl, val));
+                }
+            }
+            MidiMessage::ControlChange(chan, ctrl, val) => {
+                self.for_each_output_channel(chan, |out_chan| emit(MidiMessage::ControlChange(out_chan, ctrl, val)));
+            }
+            MidiMessage::ProgramChange(Self::MASTER_CHANNEL, prog) => {
+                for out_chan in Self::CHANNELS {
+                    emit(MidiMessage::ProgramChange(out_chan, prog));
+                }
+            }
+            MidiMessage::ProgramChange(chan, prog) => {
+                self.for_each_output_channel(chan, |out_chan| emit(MidiMessage::ProgramChange(out_chan, prog)));
+            }
+            message => emit(message),
+        }
+    }
+
+    fn find_note(&self, chan: Channel, note: wmidi::Note) -> Option<usize> {
+        self.notes.iter().position(|&(c, n, _)| c == chan && n == note)
+    }
+
+    fn for_each_output_channel<F: FnMut(Channel)>(&self, chan: Channel, mut f: F) {
+        self.notes.iter()
+            .filter(|(c, _, _)| *c == chan)
+            .for_each(|(_, _, out_chan)| f(*out_chan))
+    }
+
+    /// Get a free output channel, rotating among them
+    ///
+    /// If all channels are used, the oldest note of the next channel is stopped.
+    fn allocate_channel<E: FnMut(MidiMessage)>(&mut self, emit: &mut E) -> Channel {
+        let count = Self::CHANNELS.len();
+        let index = (0..count)
+            .map(|i| (self.next_channel + i) % count)
+            .find(|&i| self.notes.iter().all(|&(_, _, c)| c != Self::CHANNELS[i]))
+            .unwrap_or(self.next_channel);
+        self.next_channel = (index + 1) % count;
+
+        let out_chan = Self::CHANNELS[index];
+        if let Some(pos) = self.notes.iter().position(|&(_, _, c)| c == out_chan) {
+            let (_, note, _) = self.notes.remove(pos);
+            emit(MidiMessage::NoteOff(out_chan, note, wmidi::U7::MIN));
+        }
+        out_chan
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use wmidi::{Channel, ControlFunction, Note, U7, U14};
+    use crate::midi::MidiMessage;
+    use super::MpeRouter;
+
+    fn route(router: &mut MpeRouter, message: MidiMessage) -> Vec<MidiMessage> {
+        let mut messages = vec![];
+        router.route(message, |m| messages.push(m.to_owned()));
+        messages
+    }
+
+    #[test]
+    fn member_channel_control_change() {
+        let mut router = MpeRouter::new();
+        route(&mut router, MidiMessage::NoteOn(Channel::Ch2, Note::C4, U7::MAX));
+        route(&mut router, MidiMessage::NoteOn(Channel::Ch3, Note::D4, U7::MAX));
+        let out_chan = match route(&mut router, MidiMessage::NoteOn(Channel::Ch4, Note::E4, U7::MAX))[..] {
+            [_, MidiMessage::NoteOn(chan, _, _)] => chan,
+            ref messages => panic!("unexpected messages: {:?}", messages),
+        };
+        let messages = route(&mut router, MidiMessage::ControlChange(Channel::Ch4, ControlFunction::MODULATION_WHEEL, U7::MAX));
+        assert_eq!(messages, [MidiMessage::ControlChange(out_chan, ControlFunction::MODULATION_WHEEL, U7::MAX)]);
+        // No note on the channel
+        assert_eq!(route(&mut router, MidiMessage::ControlChange(Channel::Ch5, ControlFunction::MODULATION_WHEEL, U7::MAX)), []);
+    }
+
+    #[test]
+    fn master_channel_control_change() {
+        let mut router = MpeRouter::new();
+        route(&mut router, MidiMessage::NoteOn(Channel::Ch2, Note::C4, U7::MAX));
+        let messages = route(&mut router, MidiMessage::ControlChange(Channel::Ch1, ControlFunction::DAMPER_PEDAL, U7::MAX));
+        let channels: Vec<_> = messages.iter().map(|m| m.channel().unwrap()).collect();
+        assert_eq!(channels, MpeRouter::CHANNELS);
+    }
+
+    fn note_on(chan: Channel, num: u8) -> MidiMessage {
+        MidiMessage::NoteOn(chan, Note::from_u8_lossy(num), U7::MAX)
+    }
+
+    fn center(chan: Channel) -> MidiMessage {
+        MidiMessage::PitchBendChange(chan, U14::try_from(0x2000).unwrap())
+    }
+
+    #[test]
+    fn channel_rotation() {
+        let mut router = MpeRouter::new();
+        assert_eq!(route(&mut router, note_on(Channel::Ch2, 60)), [center(Channel::Ch1), note_on(Channel::Ch1, 60)]);
+        assert_eq!(route(&mut router, note_on(Channel::Ch2, 62)), [center(Channel::Ch2), note_on(Channel::Ch2, 62)]);
+        assert_eq!(route(&mut router, MidiMessage::NoteOff(Channel::Ch2, Note::from_u8_lossy(62), U7::MIN)),
+            [MidiMessage::NoteOff(Channel::Ch2, Note::from_u8_lossy(62), U7::MIN)]);
+        // The released channel is not reused right away
+        assert_eq!(route(&mut router, note_on(Channel::Ch2, 64)), [center(Channel::Ch3), note_on(Channel::Ch3, 64)]);
+    }
+
+    #[test]
+    fn drum_channel_skipped() {
+        let mut router = MpeRouter::new();
+        let out_channels: Vec<_> = (0..15)
+            .map(|i| route(&mut router, note_on(Channel::Ch2, 60 + i))[1].channel().unwrap())
+            .collect();
+        assert_eq!(out_channels, MpeRouter::CHANNELS);
+    }
+
+    #[test]
+    fn oldest_note_stolen() {
+        let mut router = MpeRouter::new();
+        for i in 0..15 {
+            route(&mut router, note_on(Channel::Ch2, 60 + i));
+        }
+        assert_eq!(route(&mut router, note_on(Channel::Ch2, 80)), [
+            MidiMessage::NoteOff(Channel::Ch1, Note::from_u8_lossy(60), U7::MIN),
+            center(Channel::Ch1),
+            note_on(Channel::Ch1, 80),
+        ]);
+        // Already stopped
+        assert_eq!(route(&mut router, MidiMessage::NoteOff(Channel::Ch2, Note::from_u8_lossy(60), U7::MIN)), []);
+    }
+
+    #[test]
+    fn pitch_bend_per_note() {
+        let mut router = MpeRouter::new();
+        let bend = U14::try_from(0x3000).unwrap();
+        route(&mut router, note_on(Channel::Ch2, 60));
+        route(&mut router, note_on(Channel::Ch3, 64));
+        assert_eq!(route(&mut router, MidiMessage::PitchBendChange(Channel::Ch3, bend)), [MidiMessage::PitchBendChange(Channel::Ch2, bend)]);
+        // New notes of the input channel start with its bend
+        assert_eq!(route(&mut router, note_on(Channel::Ch3, 67)), [MidiMessage::PitchBendChange(Channel::Ch3, bend), note_on(Channel::Ch3, 67)]);
+        assert_eq!(route(&mut router, MidiMessage::PitchBendChange(Channel::Ch3, U14::try_from(0x2000).unwrap())), [center(Channel::Ch2), center(Channel::Ch3)]);
+    }
+}
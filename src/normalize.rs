@@ -0,0 +1,68 @@
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use crate::midi::MidiMessage;
+use crate::piano::Preset;
+use crate::synth::Synth;
+
+
+/// Measure loudness of presets, to compensate it when switching presets
+///
+/// A probe note is rendered offline for each preset, with its own synth.
+/// The font is loaded a second time, and results are cached.
+pub struct PresetProbe {
+    sfont_path: PathBuf,
+    synth: Synth,
+    sfont_id: fluidlite::FontId,
+    /// Gain computed for each probed preset
+    gains: Vec<(Preset, f32)>,
+}
+
+impl PresetProbe {
+    /// Low sample rate, enough to measure loudness and faster to render
+    const SAMPLE_RATE: u32 = 22050;
+    /// Duration of the probe note, enough to skip most attacks
+    const NOTE_FRAMES: usize = Self::SAMPLE_RATE as usize * 3 / 10;
+    const NOTE: wmidi::Note = wmidi::Note::C4;
+    const VELOCITY: u8 = 100;
+    /// RMS level of the probe note reached after compensation, at unit gain (-26 dBFS)
+    const TARGET_RMS: f32 = 0.05;
+    /// Limits of the compensation gain, to not amplify near-silent presets
+    const MIN_GAIN: f32 = 0.25;
+    const MAX_GAIN: f32 = 4.0;
+
+    pub fn new(sfont_path: &Path) -> Result<Self> {
+        let synth = Synth::new(Self::SAMPLE_RATE as f64, 1.0, false)?;
+        let sfont_id = synth.synth.sfload(sfont_path, true)
+            .map_err(|err| anyhow::anyhow!("{}", err))
+            .with_context(|| format!("failed to load SoundFont {}", sfont_path.display()))?;
+        Ok(Self { sfont_path: sfont_path.to_owned(), synth, sfont_id, gains: vec![] })
+    }
+
+    /// Return the path of the probed font
+    pub fn sfont_path(&self) -> &Path {
+        &self.sfont_path
+    }
+
+    /// Return the gain making a preset as loud as the target level
+    pub fn gain(&mut self, preset: Preset) -> Result<f32> {
+        if let Some(&(_, gain)) = self.gains.iter().find(|(p, _)| *p == preset) {
+            return Ok(gain);
+        }
+        let rms = self.probe(preset)?;
+        let gain = if rms > 0.0 { (Self::TARGET_RMS / rms).clamp(Self::MIN_GAIN, Self::MAX_GAIN) } else { 1.0 };
+        self.gains.push((preset, gain));
+        Ok(gain)
+    }
+
+    /// Render the probe note, return its RMS level
+    fn probe(&self, preset: Preset) -> Result<f32> {
+        let channel = wmidi::Channel::Ch1;
+        self.synth.synth.program_select(0, self.sfont_id, preset.bank, preset.num)?;
+        self.synth.send_midi_message(MidiMessage::NoteOn(channel, Self::NOTE, wmidi::U7::from_u8_lossy(Self::VELOCITY)))?;
+        let samples = self.synth.render(Self::NOTE_FRAMES);
+        self.synth.all_sounds_off()?;
+        let samples = samples?;
+        let sum: f32 = samples.iter().map(|v| v * v).sum();
+        Ok((sum / samples.len() as f32).sqrt())
+    }
+}
@@ -0,0 +1,38 @@
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::task::{Context, Poll};
+use futures_channel::mpsc::{unbounded, UnboundedReceiver};
+use futures_core::Stream;
+use crate::midi::NoteEvent;
+
+
+/// Asynchronous stream of processed note events
+///
+/// Events are forwarded from the engine queue by a dedicated thread, so that
+/// the audio thread never wakes up tasks itself. The thread stops on the next
+/// event after the stream is dropped.
+pub struct NoteEventStream {
+    rx: UnboundedReceiver<NoteEvent>,
+}
+
+impl NoteEventStream {
+    pub(crate) fn new(events: mpsc::Receiver<NoteEvent>) -> Self {
+        let (tx, rx) = unbounded();
+        std::thread::spawn(move || {
+            for event in events {
+                if tx.unbounded_send(event).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { rx }
+    }
+}
+
+impl Stream for NoteEventStream {
+    type Item = NoteEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<NoteEvent>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
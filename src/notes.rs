@@ -0,0 +1,194 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use wmidi::Note;
+use crate::error::PianoError;
+
+
+/// Note naming convention
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct NoteNaming {
+    /// Octave number of middle C (MIDI note 60), usually 3 or 4
+    pub middle_c_octave: i8,
+    /// Names of notes in an octave
+    pub names: NoteNames,
+}
+
+/// Names of notes in an octave
+#[derive(Deserialize, PartialEq, Eq, Copy, Clone, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum NoteNames {
+    /// C, D, E... with sharps (e.g. `C#`)
+    #[default]
+    English,
+    /// C, D, E... with H instead of B, and `-is` sharps (e.g. `Cis`, `B` for A#)
+    German,
+    /// Do, Re, Mi... with sharps (e.g. `Do#`)
+    Solfege,
+}
+
+impl NoteNames {
+    /// Names of the notes of an octave, starting from C
+    fn names(&self) -> [&'static str; 12] {
+        match self {
+            Self::English => ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"],
+            Self::German => ["C", "Cis", "D", "Dis", "E", "F", "Fis", "G", "Gis", "A", "B", "H"],
+            Self::Solfege => ["Do", "Do#", "Re", "Re#", "Mi", "Fa", "Fa#", "Sol", "Sol#", "La", "La#", "Si"],
+        }
+    }
+
+    /// Names of natural notes, and their semitone, longest names first
+    fn naturals(&self) -> &'static [(&'static str, i32)] {
+        match self {
+            Self::English => &[("C", 0), ("D", 2), ("E", 4), ("F", 5), ("G", 7), ("A", 9), ("B", 11)],
+            Self::German => &[("C", 0), ("D", 2), ("E", 4), ("F", 5), ("G", 7), ("A", 9), ("B", 10), ("H", 11)],
+            Self::Solfege => &[("Sol", 7), ("Do", 0), ("Re", 2), ("Mi", 4), ("Fa", 5), ("La", 9), ("Si", 11)],
+        }
+    }
+}
+
+impl NoteNaming {
+
+    /// Return the octave number of a note
+    pub fn octave(&self, note: Note) -> i8 {
+        ((u8::from(note) / 12) as i8 - 5).saturating_add(self.middle_c_octave)
+    }
+
+    /// Return the name of a note, with its octave (e.g. `C#4`)
+    pub fn name(&self, note: Note) -> String {
+        format!("{}{}", self.names.names()[(u8::from(note) % 12) as usize], self.octave(note))
+    }
+
+    /// Parse a note name, with its octave (e.g. `C#4`, `Eb3`, `a-1`)
+    ///
+    /// Names follow `names`. Flats are written `b`, except for German names
+    /// which use `-es` flats (e.g. `Es3`, `Ges3`) and also accept `#` sharps.
+    pub fn parse_note(&self, s: &str) -> Result<Note, PianoError> {
+        let s = s.trim();
+        let (name, mut semitone) = self.names.naturals().iter()
+            .find(|(name, _)| s.get(..name.len()).is_some_and(|prefix| prefix.eq_ignore_ascii_case(name)))
+            .copied()
+            .with_context(|| format!("invalid note name: {:?}", s))?;
+        let mut rest = &s[name.len()..];
+        let german = self.names == NoteNames::German;
+        loop {
+            if let Some(r) = rest.strip_prefix('#').or_else(|| rest.strip_prefix("is").filter(|_| german)) {
+                semitone += 1;
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix('b').filter(|_| !german) {
+                semitone -= 1;
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix("es").filter(|_| german) {
+                semitone -= 1;
+                rest = r;
+            // Es and As, instead of Ees and Aes
+            } else if let Some(r) = rest.strip_prefix('s').filter(|_| german && matches!(name, "E" | "A")) {
+                semitone -= 1;
+                rest = r;
+            } else {
+                break;
+            }
+        }
+        let octave: i32 = rest.trim().parse()
+            .with_context(|| format!("invalid octave in note name: {:?}", s))?;
+        let value = octave.checked_sub(self.middle_c_octave as i32 - 5)
+            .and_then(|octave| octave.checked_mul(12))
+            .and_then(|value| value.checked_add(semitone));
+        let note = value.and_then(|value| u8::try_from(value).ok()).filter(|v| *v <= 127)
+            .map(Note::from_u8_lossy)
+            .with_context(|| format!("note out of range: {:?}", s))?;
+        Ok(note)
+    }
+}
+
+impl Default for NoteNaming {
+    fn default() -> Self {
+        Self { middle_c_octave: 4, names: NoteNames::default() }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use wmidi::Note;
+    use super::{NoteNames, NoteNaming};
+
+    #[test]
+    fn parse_note() {
+        let naming = NoteNaming::default();
+        for (s, note) in [
+            ("C#4", Some(Note::Db4)),
+            ("Eb3", Some(Note::Eb3)),
+            (" a-1 ", Some(Note::AMinus1)),
+            ("c-1", Some(Note::CMinus1)),
+            ("Cb-1", None),
+            ("G9", Some(Note::G9)),
+            ("G#9", None),
+            ("C2147483647", None),
+            ("C-2147483648", None),
+            ("", None),
+            ("C", None),
+            ("X4", None),
+        ] {
+            assert_eq!(naming.parse_note(s).ok(), note, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn parse_note_errors() {
+        let naming = NoteNaming::default();
+        let error = |s| naming.parse_note(s).unwrap_err().to_string();
+        assert_eq!(error(""), "invalid note name: \"\"");
+        assert_eq!(error("C"), "invalid octave in note name: \"C\"");
+        assert_eq!(error("G#9"), "note out of range: \"G#9\"");
+        assert_eq!(error("C2147483647"), "note out of range: \"C2147483647\"");
+    }
+
+    #[test]
+    fn parse_german_note() {
+        let naming = NoteNaming { middle_c_octave: 4, names: NoteNames::German };
+        for (s, note) in [
+            ("Cis4", Some(Note::Db4)),
+            ("C#4", Some(Note::Db4)),
+            ("Des4", Some(Note::Db4)),
+            ("Es3", Some(Note::Eb3)),
+            ("As3", Some(Note::Ab3)),
+            ("Ges3", Some(Note::Gb3)),
+            ("B3", Some(Note::Bb3)),
+            ("H3", Some(Note::B3)),
+            ("h3", Some(Note::B3)),
+            ("Ds3", None),
+            ("Eb3", None),
+        ] {
+            assert_eq!(naming.parse_note(s).ok(), note, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn parse_solfege_note() {
+        let naming = NoteNaming { middle_c_octave: 4, names: NoteNames::Solfege };
+        for (s, note) in [
+            ("Do4", Some(Note::C4)),
+            ("Sol4", Some(Note::G4)),
+            ("sol#4", Some(Note::Ab4)),
+            ("Solb4", Some(Note::Gb4)),
+            ("Si3", Some(Note::B3)),
+            ("So4", None),
+            ("C4", None),
+        ] {
+            assert_eq!(naming.parse_note(s).ok(), note, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn name_round_trip() {
+        for names in [NoteNames::English, NoteNames::German, NoteNames::Solfege] {
+            for middle_c_octave in [-2, 3, 4, 8] {
+                let naming = NoteNaming { middle_c_octave, names };
+                for num in 0..=127 {
+                    let note = Note::from_u8_lossy(num);
+                    assert_eq!(naming.parse_note(&naming.name(note)).ok(), Some(note), "{:?} {}", naming, naming.name(note));
+                }
+            }
+        }
+    }
+}
@@ -1,29 +1,132 @@
-use std::sync::{Arc, Mutex};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 use std::sync::mpsc::{self, Sender};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use anyhow::{Context, Result};
-use fluidlite::{IsFont, IsPreset};
-use crate::audio::{AudioOutput, AudioOutputConfig};
-use crate::midi::{MidiInput, MidiMessage};
-use crate::synth::Synth;
+use serde::{Deserialize, Serialize};
+use crate::audio::{AudioOutput, AudioOutputConfig, TestTone, TimingStats};
+use crate::compressor::CompConfig;
+use crate::engine::{Engine, EngineCommand, EngineShared};
+use crate::midi::{ControlEvent, MidiInput, MidiMessage, MidiParser, NoteEvent};
+use crate::mpe::MpeRouter;
+use crate::normalize::PresetProbe;
+use crate::notes::NoteNaming;
+use crate::player::MidiFilePlayer;
+use crate::processor::MonoConfig;
+use crate::smf::MidiFile;
+use crate::snapshot::{ChannelSnapshot, StateSnapshot};
+use crate::synth::{SettingValue, Synth};
+use crate::visualizer::Visualizer;
+use crate::error::PianoError;
 
 
 pub struct Piano {
     /// Output audio stream
     output: AudioOutput,
+    /// Options of the output stream, kept when the device changes
+    output_options: OutputOptions,
+    /// Engine rendering the stream
+    ///
+    /// Locked by the audio thread on each render, and by heavy operations
+    /// from the caller thread, see `Engine`.
+    engine: Arc<Mutex<Engine>>,
     /// Queue to be used by inputs
     input_tx: Sender<MidiMessage>,
-    /// Currently active input
-    input: Option<Box<dyn std::any::Any>>,
-    /// Synth used to generate output samples
-    synth: Arc<Mutex<Synth>>,
-    /// Currently loaded and active FontId
-    sfont_id: Option<fluidlite::FontId>,
+    /// Currently active inputs
+    inputs: Vec<(InputId, Box<dyn std::any::Any>)>,
+    /// Identifier of the next added input
+    next_input_id: usize,
+    /// Queue of commands sent to the engine
+    commands: Sender<EngineCommand>,
+    /// State published by the engine
+    shared: Arc<EngineShared>,
+    /// Path of the loaded SoundFont, if any
+    sfont_path: Option<PathBuf>,
     /// Data of currently available presets
     presets_data: Vec<PresetData>,
+    /// Output sample rate
+    sample_rate: f64,
+    /// Whether synth samples are output
+    playing: Cell<bool>,
+    /// Whether MPE mode is enabled
+    mpe: Cell<bool>,
+    /// Whether reverb is enabled, as last set
+    reverb: Cell<bool>,
+    /// Whether chorus is enabled, as last set
+    chorus: Cell<bool>,
+    /// Whether each channel is muted, as last set
+    muted_channels: Cell<[bool; 16]>,
+    /// Soloed channel, as last set
+    solo: Cell<Option<wmidi::Channel>>,
+    /// Transposition of each channel, as last set
+    transpose: Cell<[i8; 16]>,
+    /// Whether playing notes are released when the active preset changes
+    release_on_preset_change: Cell<bool>,
+    /// Whether gain compensates the loudness of the active preset
+    normalize_presets: Cell<bool>,
+    /// Probe of the loaded font, created when normalizing presets
+    preset_probe: RefCell<Option<PresetProbe>>,
+    /// Whether scheduled messages are sent early, to compensate output latency
+    latency_compensation: Cell<bool>,
+    /// Convention used to name notes
+    note_naming: NoteNaming,
+}
+
+/// Options used to create a piano
+pub struct PianoConfig {
+    /// Synth gain
+    pub gain: f32,
+    /// Name of the audio output device, default one if not set
+    pub output_device: Option<String>,
+    /// Device channels receiving left and right samples, 0-based
+    pub output_channels: [usize; 2],
+    /// Output sample rate, highest supported one if not set
+    pub sample_rate: Option<u32>,
+    /// Enable reverb and chorus
+    pub effects: bool,
+    /// Request real-time scheduling for the audio thread
+    ///
+    /// Requires the `rt-priority` feature and permissions, a warning is printed
+    /// if it cannot be applied.
+    pub realtime_priority: bool,
+    /// Duration rendered silently when playback starts, to let the synth settle
+    ///
+    /// This avoids clipping the first note. Input received meanwhile is
+    /// delayed, not dropped.
+    pub preroll: Duration,
+    /// Duration over which output gain ramps up when playback starts, to avoid a pop
+    pub fade_in: Duration,
+    /// Silence output and stop all voices if any thread panics
+    ///
+    /// A panic hook is installed, the previous hook is still called. Output
+    /// stays silent afterwards, even if the process keeps running.
+    pub silence_on_panic: bool,
+}
+
+impl Default for PianoConfig {
+    fn default() -> Self {
+        Self {
+            gain: Piano::DEFAULT_GAIN,
+            output_device: None,
+            output_channels: [0, 1],
+            sample_rate: None,
+            effects: true,
+            realtime_priority: false,
+            preroll: Piano::DEFAULT_PREROLL,
+            fade_in: Piano::DEFAULT_FADE_IN,
+            silence_on_panic: false,
+        }
+    }
 }
 
+/// Identifier of an input connected to a piano
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct InputId(usize);
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct Preset {
     pub bank: u32,
     pub num: u32,
@@ -35,116 +138,859 @@ pub struct PresetData {
     pub name: Option<String>,
 }
 
+/// Options of the audio output, other than the device
+struct OutputOptions {
+    channels: [usize; 2],
+    sample_rate: Option<u32>,
+    realtime_priority: bool,
+}
+
+impl OutputOptions {
+    fn output_config(&self, device: Option<&str>) -> Result<AudioOutputConfig> {
+        let output_config = match device {
+            Some(name) => AudioOutputConfig::with_device(name)?,
+            None => AudioOutputConfig::new()?,
+        };
+        let output_config = if self.channels != [0, 1] {
+            output_config.with_channels(self.channels)?
+        } else {
+            output_config
+        };
+        let output_config = match self.sample_rate {
+            Some(rate) => output_config.with_sample_rate(rate)?,
+            None => output_config,
+        };
+        Ok(output_config.with_realtime_priority(self.realtime_priority))
+    }
+
+    /// Create a stream rendered by the engine
+    ///
+    /// The engine is locked by other threads for heavy operations only,
+    /// silence is output meanwhile rather than blocking the callback.
+    fn stream(output_config: AudioOutputConfig, engine: &Arc<Mutex<Engine>>) -> Result<AudioOutput> {
+        let engine = Arc::clone(engine);
+        output_config.stream(move |data: &mut [f32]| match engine.try_lock() {
+            Ok(mut engine) => engine.render(data),
+            Err(_) => data.fill(0.0),
+        })
+    }
+}
+
+/// All channels, in order, for commands requiring static channels
+static ALL_CHANNELS: [wmidi::Channel; 16] = [
+    wmidi::Channel::Ch1, wmidi::Channel::Ch2, wmidi::Channel::Ch3, wmidi::Channel::Ch4,
+    wmidi::Channel::Ch5, wmidi::Channel::Ch6, wmidi::Channel::Ch7, wmidi::Channel::Ch8,
+    wmidi::Channel::Ch9, wmidi::Channel::Ch10, wmidi::Channel::Ch11, wmidi::Channel::Ch12,
+    wmidi::Channel::Ch13, wmidi::Channel::Ch14, wmidi::Channel::Ch15, wmidi::Channel::Ch16,
+];
+
 impl Piano {
-    pub fn new() -> Result<Self> {
+    const NOTE_EVENTS_CAPACITY: usize = 256;
+    const AUDIO_BLOCKS_CAPACITY: usize = 64;
+
+    /// Maximum time to wait for the engine to handle a request
+    ///
+    /// Requests are handled on next render, heavy operations lock the engine instead.
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Maximum time the panic hook waits for output to be silenced
+    const PANIC_SILENCE_TIMEOUT: Duration = Duration::from_millis(200);
+
+    pub const DEFAULT_GAIN: f32 = Synth::DEFAULT_GAIN;
+    pub const DEFAULT_PREROLL: Duration = Duration::from_millis(50);
+    pub const DEFAULT_FADE_IN: Duration = Duration::from_millis(50);
+
+    /// Create a new piano, start the audio stream
+    ///
+    /// The synth is owned by the audio engine, rendered by the audio thread
+    /// which always runs so that commands are processed. Output is silent
+    /// until `play()` is called.
+    pub fn new(gain: f32) -> Result<Self, PianoError> {
+        Self::with_config(PianoConfig { gain, ..Default::default() })
+    }
+
+    /// Create a new piano with the given options
+    ///
+    /// Pianos share no state, several ones can be used at the same time.
+    pub fn with_config(config: PianoConfig) -> Result<Self, PianoError> {
         let (tx, rx) = mpsc::channel();
+        let (commands_tx, commands_rx) = mpsc::channel();
 
-        let output_config = AudioOutputConfig::new()?;
-        let synth = Synth::new(output_config.sample_rate())?;
-        let synth = Arc::new(Mutex::new(synth));
-
-        let output = {
-            let synth = Arc::clone(&synth);
-            output_config.stream(move |data: &mut [f32]| {
-                let synth = synth.lock().unwrap();
-                // Convert input MIDI messages
-                for message in rx.try_iter() {
-                    synth.send_midi_message(message)
-                        .unwrap_or_else(|err| eprintln!("failed to process MIDI message: {}", err));
-                }
-                // Write the next samples
-                synth.write_samples(data)
-                    .unwrap_or_else(|err| eprintln!("failed to generate samples: {}", err));
-            })
-        }?;
+        let output_options = OutputOptions {
+            channels: config.output_channels,
+            sample_rate: config.sample_rate,
+            realtime_priority: config.realtime_priority,
+        };
+        let output_config = output_options.output_config(config.output_device.as_deref())?;
+        let sample_rate = output_config.sample_rate();
+        let synth = Synth::new(sample_rate, config.gain, config.effects)?;
+        let shared = Arc::new(EngineShared::new(config.gain));
+        let engine = Engine::new(synth, config.effects, sample_rate, rx, commands_rx, Arc::clone(&shared));
+        commands_tx.send(EngineCommand::SetPreroll(config.preroll)).map_err(anyhow::Error::from)?;
+        commands_tx.send(EngineCommand::SetFadeIn(config.fade_in)).map_err(anyhow::Error::from)?;
+        let engine = Arc::new(Mutex::new(engine));
+        let output = OutputOptions::stream(output_config, &engine)?;
+        output.play()?;
+        if config.silence_on_panic {
+            Self::install_panic_hook(&shared);
+        }
 
         Ok(Self {
             output,
+            output_options,
+            engine,
             input_tx: tx,
-            input: None,
-            synth,
-            sfont_id: None,
+            inputs: vec![],
+            next_input_id: 0,
+            commands: commands_tx,
+            shared,
+            sfont_path: None,
             presets_data: vec![],
+            sample_rate,
+            playing: Cell::new(false),
+            mpe: Cell::new(false),
+            reverb: Cell::new(config.effects),
+            chorus: Cell::new(config.effects),
+            muted_channels: Cell::new([false; 16]),
+            solo: Cell::new(None),
+            transpose: Cell::new([0; 16]),
+            release_on_preset_change: Cell::new(false),
+            normalize_presets: Cell::new(false),
+            preset_probe: RefCell::new(None),
+            latency_compensation: Cell::new(false),
+            note_naming: NoteNaming::default(),
         })
     }
 
-    pub fn set_input<I: PianoInput>(&mut self, input: I) -> Result<()> {
-        self.input.replace(input.connect_input(self.input_tx.clone())?);
+    /// Switch audio output to another device, or to the default one
+    ///
+    /// Output channels and sample rate given on creation still apply. If the
+    /// sample rate of the new device differs, the synth is rebuilt for it: the
+    /// font, presets and synth settings are restored, but playing notes are
+    /// stopped. Visualizers created before should be recreated.
+    pub fn set_output_device(&mut self, device: Option<&str>) -> Result<(), PianoError> {
+        let output_config = self.output_options.output_config(device)?;
+        let sample_rate = output_config.sample_rate();
+        let output = OutputOptions::stream(output_config, &self.engine)?;
+        if sample_rate != self.sample_rate {
+            self.lock_engine()?.set_sample_rate(sample_rate)
+                .context("failed to rebuild synth for the new sample rate")?;
+            self.sample_rate = sample_rate;
+        }
+        // Close the previous stream first, so that the engine is not rendered by both
+        drop(std::mem::replace(&mut self.output, output));
+        Ok(self.output.play()?)
+    }
+
+    /// Replace all inputs by the given one
+    pub fn set_input<I: PianoInput>(&mut self, input: I) -> Result<(), PianoError> {
+        let connected = input.connect_input(self.input_tx.clone())?;
+        self.inputs.clear();
+        self.push_input(connected);
         Ok(())
     }
 
+    /// Add an input, used along already connected ones
+    pub fn add_input<I: PianoInput>(&mut self, input: I) -> Result<InputId, PianoError> {
+        let connected = input.connect_input(self.input_tx.clone())?;
+        Ok(self.push_input(connected))
+    }
+
+    /// Disconnect an input, return false if not found
+    pub fn remove_input(&mut self, id: InputId) -> bool {
+        let count = self.inputs.len();
+        self.inputs.retain(|(input_id, _)| *input_id != id);
+        self.inputs.len() != count
+    }
+
     pub fn has_input(&self) -> bool {
-        self.input.is_some()
+        !self.inputs.is_empty()
+    }
+
+    fn push_input(&mut self, connected: Box<dyn std::any::Any>) -> InputId {
+        let id = InputId(self.next_input_id);
+        self.next_input_id += 1;
+        self.inputs.push((id, connected));
+        id
+    }
+
+    pub fn play(&self) -> Result<(), PianoError> {
+        self.send_command(EngineCommand::SetPlaying(true))?;
+        self.playing.set(true);
+        Ok(())
+    }
+
+    /// Pause audio output
+    ///
+    /// Playing voices are stopped first, so that the output is silent instead
+    /// of holding the last buffer. The audio stream itself is not paused: it
+    /// keeps running and outputs silence, because commands are applied by its
+    /// callback. Input messages are still processed meanwhile, notes held when
+    /// resuming are heard.
+    pub fn pause(&self) -> Result<(), PianoError> {
+        self.send_command(EngineCommand::SetPlaying(false))?;
+        self.playing.set(false);
+        Ok(())
     }
 
-    pub fn play(&self) -> Result<()> {
-        self.output.play()
+    pub fn is_playing(&self) -> bool {
+        self.playing.get()
     }
 
-    pub fn pause(&self) -> Result<()> {
-        self.output.pause()
+    /// Stop synthesis after output has been silent for the given time, or never
+    ///
+    /// This saves power while the piano is not played. The audio stream keeps
+    /// running, outputting silence, and synthesis resumes on the next MIDI message.
+    pub fn set_idle_timeout(&self, timeout: Option<Duration>) -> Result<(), PianoError> {
+        self.send_command(EngineCommand::SetIdleTimeout(timeout))
+    }
+
+    /// Return the number of audio dropouts, due to buffer underruns
+    pub fn xrun_count(&self) -> usize {
+        self.output.xrun_count()
+    }
+
+    /// Return the time spent in the audio callback, compared to the buffer duration
+    pub fn timing_stats(&self) -> TimingStats {
+        self.output.timing_stats()
+    }
+
+    /// Output a test tone for the given note instead of synth samples, bypassing the synth
+    pub fn set_test_tone(&self, note: Option<wmidi::Note>) -> Result<(), PianoError> {
+        let test_tone = note.map(|note| TestTone::new(self.sample_rate, note.to_freq_f32()));
+        self.send_command(EngineCommand::SetTestTone(test_tone))
+    }
+
+    /// Send a MIDI message, processed like input messages
+    pub fn send_message(&self, message: MidiMessage) -> Result<(), PianoError> {
+        self.send_input(message)
+    }
+
+    /// Send several MIDI messages, processed like input messages
+    ///
+    /// Messages are processed in slice order, but messages of other inputs may
+    /// be processed in between. They are processed as soon as possible, use
+    /// `send_message_at()` to time them.
+    pub fn send_messages(&self, messages: &[MidiMessage]) -> Result<(), PianoError> {
+        for message in messages {
+            self.send_input(message.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Send a MIDI message, to be processed at the given time
+    ///
+    /// Messages are processed at their frame in the rendered buffer, whatever
+    /// the buffer size. With latency compensation, they are processed earlier
+    /// so that they are heard at the given time. At most 4096 messages can be
+    /// pending, further ones are dropped.
+    pub fn send_message_at(&self, message: MidiMessage, time: Instant) -> Result<(), PianoError> {
+        let time = if self.latency_compensation.get() {
+            time.checked_sub(self.output.latency()).unwrap_or(time)
+        } else {
+            time
+        };
+        self.send_command(EngineCommand::ScheduleMessage(time, message))
+    }
+
+    /// Enable or disable compensation of output latency for scheduled messages
+    ///
+    /// This allows internal events to be heard in time with live play.
+    pub fn set_latency_compensation(&self, enabled: bool) {
+        self.latency_compensation.set(enabled);
+    }
+
+    /// Return the delay between rendering of samples and their playback
+    pub fn latency(&self) -> Duration {
+        self.output.latency()
+    }
+
+    /// Play a MIDI file, return a handle to control playback
+    ///
+    /// Events are processed like input messages. Playback stops when the
+    /// returned handle is dropped.
+    pub fn play_file<P: AsRef<Path>>(&self, path: P) -> Result<MidiFilePlayer, PianoError> {
+        self.play_file_with_count_in(path, 0)
+    }
+
+    /// Play a MIDI file, after metronome clicks for the given number of bars
+    ///
+    /// Clicks follow the initial tempo and time signature of the file. Player
+    /// position zero is the first beat after the count-in.
+    pub fn play_file_with_count_in<P: AsRef<Path>>(&self, path: P, count_in_bars: u32) -> Result<MidiFilePlayer, PianoError> {
+        let file = MidiFile::load(path)?;
+        self.play_midi_file(file, count_in_bars)
+    }
+
+    /// Play an already loaded MIDI file, see `play_file_with_count_in()`
+    pub fn play_midi_file(&self, file: MidiFile, count_in_bars: u32) -> Result<MidiFilePlayer, PianoError> {
+        Ok(MidiFilePlayer::start(file, self.input_tx.clone(), count_in_bars)?)
+    }
+
+    /// Start playing a note
+    pub fn note_on(&self, channel: wmidi::Channel, note: wmidi::Note, velocity: wmidi::U7) -> Result<(), PianoError> {
+        self.send_message(MidiMessage::NoteOn(channel, note, velocity))
+    }
+
+    /// Stop playing a note
+    pub fn note_off(&self, channel: wmidi::Channel, note: wmidi::Note) -> Result<(), PianoError> {
+        self.send_message(MidiMessage::NoteOff(channel, note, wmidi::U7::MIN))
+    }
+
+    /// Return a queue receiving processed note events
+    ///
+    /// Events are dropped if the queue is full. Listening stops when the
+    /// receiver is dropped.
+    pub fn note_events(&self) -> Result<mpsc::Receiver<NoteEvent>, PianoError> {
+        let (tx, rx) = mpsc::sync_channel(Self::NOTE_EVENTS_CAPACITY);
+        self.send_command(EngineCommand::AddNoteListener(tx))?;
+        Ok(rx)
+    }
+
+    /// Return a queue receiving control changes, as received from inputs
+    ///
+    /// Events are captured before processing, so that controllers are the
+    /// ones sent by the hardware. Messages of played MIDI files are included,
+    /// but not the ones sent with `send_message_at()`. Events are dropped if
+    /// the queue is full.
+    /// Listening stops when the receiver is dropped.
+    pub fn control_events(&self) -> Result<mpsc::Receiver<ControlEvent>, PianoError> {
+        let (tx, rx) = mpsc::sync_channel(Self::NOTE_EVENTS_CAPACITY);
+        self.send_command(EngineCommand::AddControlListener(tx))?;
+        Ok(rx)
+    }
+
+    /// Return an asynchronous stream of processed note events
+    ///
+    /// Events are buffered until the stream is polled. Listening stops when the
+    /// stream is dropped.
+    #[cfg(feature = "async")]
+    pub fn note_event_stream(&self) -> Result<crate::note_stream::NoteEventStream, PianoError> {
+        Ok(crate::note_stream::NoteEventStream::new(self.note_events()?))
+    }
+
+    /// Return currently held input notes, by channel and note
+    pub fn held_notes(&self) -> Vec<(wmidi::Channel, wmidi::Note)> {
+        self.shared.held_notes()
+    }
+
+    /// Return the current value of a controller, if it has been set
+    ///
+    /// Values are the ones received by the synth, after processing (e.g.
+    /// velocity routing). They are forgotten when the synth is reset, on reset
+    /// messages and font loads. Unlike held notes, they are published as
+    /// soon as messages are processed.
+    pub fn channel_cc(&self, channel: wmidi::Channel, cc: wmidi::ControlFunction) -> Option<u8> {
+        self.shared.cc_value(channel, cc)
+    }
+
+    /// Return a visualizer analyzing output samples
+    ///
+    /// Samples are dropped if the visualizer is not used often enough.
+    pub fn visualizer(&self) -> Result<Visualizer, PianoError> {
+        let (tx, rx) = mpsc::sync_channel(Self::AUDIO_BLOCKS_CAPACITY);
+        self.send_command(EngineCommand::AddAudioListener(tx))?;
+        Ok(Visualizer::new(rx))
+    }
+
+    /// Wait for the next processed note event
+    ///
+    /// Only events received after the call are returned.
+    pub fn next_note_event(&self) -> Result<NoteEvent, PianoError> {
+        let rx = self.note_events()?;
+        Ok(rx.recv().context("audio engine stopped")?)
+    }
+
+    /// Return the convention used to name notes
+    pub fn note_naming(&self) -> NoteNaming {
+        self.note_naming
+    }
+
+    /// Change the convention used to name notes
+    pub fn set_note_naming(&mut self, naming: NoteNaming) {
+        self.note_naming = naming;
+    }
+
+    /// Return synth gain
+    pub fn gain(&self) -> f32 {
+        f32::from_bits(self.shared.gain.load(Ordering::Relaxed))
     }
 
     /// Change synth gain
+    ///
+    /// Gain is applied by the audio thread, without sending a command.
     pub fn set_gain(&self, gain: f32) {
-        let synth = &self.synth.lock().unwrap().synth;
-        synth.set_gain(gain);
+        self.shared.gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Return whether reverb is enabled
+    ///
+    /// FluidLite cannot be queried, the last set value is returned.
+    pub fn reverb(&self) -> bool {
+        self.reverb.get()
+    }
+
+    /// Enable or disable reverb
+    pub fn set_reverb(&self, enabled: bool) -> Result<(), PianoError> {
+        self.send_command(EngineCommand::SetReverb(enabled))?;
+        self.reverb.set(enabled);
+        Ok(())
+    }
+
+    /// Return whether chorus is enabled
+    ///
+    /// FluidLite cannot be queried, the last set value is returned.
+    pub fn chorus(&self) -> bool {
+        self.chorus.get()
+    }
+
+    /// Enable or disable chorus
+    pub fn set_chorus(&self, enabled: bool) -> Result<(), PianoError> {
+        self.send_command(EngineCommand::SetChorus(enabled))?;
+        self.chorus.set(enabled);
+        Ok(())
+    }
+
+    /// Return output stereo width
+    pub fn stereo_width(&self) -> f32 {
+        f32::from_bits(self.shared.stereo_width.load(Ordering::Relaxed))
+    }
+
+    /// Change output stereo width: 0 for mono, 1 for normal, more to widen
+    ///
+    /// Like gain, width is applied by the audio thread, without sending a command.
+    pub fn set_stereo_width(&self, width: f32) {
+        self.shared.stereo_width.store(width.max(0.0).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Stop notes on zero-velocity note-ons (default), or send them unchanged
+    ///
+    /// Running status often encodes note-offs as zero-velocity note-ons.
+    /// Processing (held notes, freeze, mono mode) always handles them as
+    /// note-offs, this only changes what the synth receives.
+    pub fn set_zero_velocity_note_off(&self, enabled: bool) -> Result<(), PianoError> {
+        self.send_command(EngineCommand::SetZeroVelocityNoteOff(enabled))
+    }
+
+    /// Change the maximum number of voices played at once
+    ///
+    /// See `Synth::set_polyphony()` for how voices are stopped past the limit.
+    pub fn set_polyphony(&self, polyphony: u32) -> Result<(), PianoError> {
+        self.request(|reply| EngineCommand::SetPolyphony { polyphony, reply })
+    }
+
+    /// Change the pitch change of a full pitch bend, in semitones
+    ///
+    /// The range is kept when a new SoundFont is loaded.
+    pub fn set_pitch_bend_range(&self, semitones: u8) -> Result<(), PianoError> {
+        self.request(|reply| EngineCommand::SetPitchBendRange { semitones, reply })
+    }
+
+    /// Enable or disable the compressor applied on output
+    ///
+    /// The compressor is disabled by default.
+    pub fn set_compressor(&self, config: Option<CompConfig>) -> Result<(), PianoError> {
+        self.send_command(EngineCommand::SetCompressor(config))
+    }
+
+    /// Change a FluidSynth setting
+    pub fn set_synth_setting(&self, key: &str, value: &SettingValue) -> Result<(), PianoError> {
+        Ok(self.lock_engine()?.set_setting(key, value)?)
+    }
+
+    /// Change the multiplier applied to note-on velocities
+    ///
+    /// Unlike synth gain, this only changes how hard notes are hit.
+    pub fn set_input_gain(&self, gain: f32) -> Result<(), PianoError> {
+        self.send_command(EngineCommand::SetInputGain(gain))
     }
 
     /// Load a new SoundFont file
-    pub fn load_sfont<P: AsRef<Path>>(&mut self, filename: P) -> Result<()> {
-        let synth = &self.synth.lock().unwrap().synth;
+    ///
+    /// The file is loaded from the calling thread, silence is output meanwhile.
+    /// On error, the previous SoundFont is kept.
+    pub fn load_sfont<P: AsRef<Path>>(&mut self, filename: P) -> Result<(), PianoError> {
+        self.load_sfont_with_progress(filename, |_, _| {})
+    }
 
-        // Load the new SoundFont file
-        if let Some(sfont_id) = self.sfont_id {
-            synth.sfunload(sfont_id, true)?;
-            self.sfont_id = None;
-        }
-        let sfont_id = synth.sfload(filename, true)?;
-        let sfont = synth.get_sfont_by_id(sfont_id).unwrap();
-
-        // Get presets data
-        let presets_data = (0..=127)
-            .flat_map(|bank| (0..=127).map(move |num| (bank, num)))
-            .filter_map(move |(bank, num)| {
-                sfont
-                    .get_preset(bank, num)
-                    .map(|preset| PresetData {
-                        bank,
-                        num,
-                        name: preset.get_name().map(|s| s.into()),
-                    })
-            })
-            .collect();
+    /// Load a new SoundFont file, reporting progress of preset enumeration
+    ///
+    /// The callback is called from the calling thread with the number of
+    /// scanned preset numbers and their total, for all 128 banks. It is only
+    /// called once the file itself has been read.
+    pub fn load_sfont_with_progress<P, F>(&mut self, filename: P, mut progress: F) -> Result<(), PianoError>
+    where
+        P: AsRef<Path>,
+        F: FnMut(usize, usize),
+    {
+        let path = filename.as_ref().to_owned();
+        crate::sfont::check_sfont_file(&path)?;
+        let presets_data = self.lock_engine()?.load_sfont(path.clone(), &mut progress)
+            .with_context(|| format!("failed to load SoundFont {}", path.display()))?;
+        self.sfont_path = Some(path);
 
-        // Update instance fields
-        self.sfont_id = Some(sfont_id);
+        // Select the first available preset, the font may not provide the default one
+        if let Some(preset) = presets_data.first() {
+            self.set_active_preset(preset.into())?;
+        }
         self.presets_data = presets_data;
 
         Ok(())
     }
 
+    /// Return the path of the loaded SoundFont, if any
+    pub fn sfont_path(&self) -> Option<&Path> {
+        self.sfont_path.as_deref()
+    }
+
     /// Return the current preset
-    pub fn get_active_preset(&self) -> Result<Preset> {
-        let synth = &self.synth.lock().unwrap().synth;
-        let (_, bank, num) = synth.get_program(0)?;
-        Ok(Preset { bank, num })
+    ///
+    /// The value is published by the audio thread, it is updated after each rendered buffer.
+    pub fn get_active_preset(&self) -> Result<Preset, PianoError> {
+        Ok(self.shared.active_preset().context("no active preset")?)
     }
 
     /// Change currently active preset
-    pub fn set_active_preset(&self, preset: Preset) -> Result<()> {
-        let sfont_id = self.sfont_id.context("no active SoundFont")?;
-        let synth = &self.synth.lock().unwrap().synth;
-        synth.program_select(0, sfont_id, preset.bank, preset.num)?;
+    pub fn set_active_preset(&self, preset: Preset) -> Result<(), PianoError> {
+        if self.sfont_path.is_none() {
+            return Err(PianoError::NoSoundFont);
+        }
+        let channels = self.preset_channels();
+        let release_notes = self.release_on_preset_change.get();
+        // Probe before switching, so that the new preset is never heard uncompensated
+        let preset_gain = if self.normalize_presets.get() {
+            self.probe_preset_gain(preset).unwrap_or_else(|err| {
+                eprintln!("cannot measure loudness of preset {}:{}: {}", preset.bank, preset.num, err);
+                1.0
+            })
+        } else {
+            1.0
+        };
+        self.request(|reply| EngineCommand::SelectPreset { channels, preset, release_notes, reply })?;
+        self.shared.preset_gain.store(preset_gain.to_bits(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Compensate the loudness of presets when switching, or stop doing it (default)
+    ///
+    /// A probe note is rendered offline for each newly selected preset, to
+    /// measure its loudness. This keeps volume consistent while browsing a
+    /// font, at the cost of loading it a second time. The compensation gain
+    /// is applied on top of `gain()`.
+    pub fn set_normalize_presets(&self, enabled: bool) -> Result<(), PianoError> {
+        self.normalize_presets.set(enabled);
+        let mut preset_gain = 1.0;
+        if !enabled {
+            self.preset_probe.replace(None);
+        } else if let (Some(_), Ok(preset)) = (&self.sfont_path, self.get_active_preset()) {
+            preset_gain = self.probe_preset_gain(preset)?;
+        }
+        self.shared.preset_gain.store(preset_gain.to_bits(), Ordering::Relaxed);
         Ok(())
     }
 
+    fn probe_preset_gain(&self, preset: Preset) -> Result<f32> {
+        let sfont_path = self.sfont_path.as_deref().ok_or(PianoError::NoSoundFont)?;
+        let mut probe = self.preset_probe.borrow_mut();
+        if probe.as_ref().is_none_or(|probe| probe.sfont_path() != sfont_path) {
+            *probe = Some(PresetProbe::new(sfont_path)?);
+        }
+        probe.as_mut().unwrap().gain(preset)
+    }
+
+    /// Release playing notes when the active preset changes, or keep them (default)
+    ///
+    /// By default, program changes are seamless: playing notes keep the sound
+    /// of the previous preset. Released notes still fade out, with the
+    /// sustain pedal applying as usual.
+    pub fn set_release_on_preset_change(&self, enabled: bool) {
+        self.release_on_preset_change.set(enabled);
+    }
+
+    /// Select the preset of a General MIDI program, by name
+    ///
+    /// The program is looked up on bank 0, whatever the names of the presets of
+    /// the font. An error is returned if the font does not provide it.
+    pub fn set_gm_instrument(&self, name: &str) -> Result<(), PianoError> {
+        let num = crate::gm::gm_program(name)
+            .with_context(|| format!("unknown General MIDI instrument: {}", name))? as u32;
+        let preset = Preset { bank: 0, num };
+        if !self.presets_data.iter().any(|p| p.bank == preset.bank && p.num == preset.num) {
+            return Err(PianoError::InvalidPreset(preset));
+        }
+        self.set_active_preset(preset)
+    }
+
+    /// Convert aftertouch to the given controller, or disable the conversion
+    ///
+    /// This allows to use aftertouch with fonts not mapping it, for instance
+    /// by routing it to the modulation wheel.
+    pub fn set_aftertouch_routing(&self, dest: Option<wmidi::ControlFunction>) -> Result<(), PianoError> {
+        self.send_command(EngineCommand::SetAftertouchRouting(dest))
+    }
+
+    /// Also send note-on velocities to the given controller, scaled by `amount`
+    ///
+    /// For instance, routing velocity to brightness (CC74) makes harder hits
+    /// brighter with fonts mapping it to the filter cutoff. The controller is
+    /// set on the note channel, just before the note starts.
+    pub fn set_velocity_routing(&self, dest: Option<(wmidi::ControlFunction, f32)>) -> Result<(), PianoError> {
+        self.send_command(EngineCommand::SetVelocityRouting(dest))
+    }
+
+    /// Send note-off velocities to the given controller, or stop doing it
+    ///
+    /// The synth has no release velocity input, and most fonts ignore it.
+    /// Expressive fonts may map a controller instead, for instance to shorten
+    /// the release of fast releases. The controller is set on the note channel,
+    /// just before the note is released.
+    pub fn set_release_velocity_routing(&self, dest: Option<wmidi::ControlFunction>) -> Result<(), PianoError> {
+        self.send_command(EngineCommand::SetReleaseVelocityRouting(dest))
+    }
+
+    /// Enable or disable freeze, to hold currently playing notes
+    ///
+    /// Captured notes are released when freeze is disabled.
+    pub fn set_freeze(&self, enabled: bool) -> Result<(), PianoError> {
+        self.send_command(EngineCommand::SetFreeze(enabled))
+    }
+
+    /// Transpose notes of a channel, or of all channels if `None`
+    ///
+    /// Input channels are used, before MPE routing. Playing notes are stopped
+    /// with their initial transposition.
+    pub fn set_transpose(&self, channel: Option<wmidi::Channel>, semitones: i8) -> Result<(), PianoError> {
+        self.send_command(EngineCommand::SetTranspose(channel, semitones))?;
+        match channel {
+            Some(channel) => {
+                let mut transpose = self.transpose.get();
+                transpose[channel.index() as usize] = semitones;
+                self.transpose.set(transpose);
+            }
+            None => self.transpose.set([semitones; 16]),
+        }
+        Ok(())
+    }
+
+    /// Return the transposition of an input channel, as last set
+    pub fn transpose(&self, channel: wmidi::Channel) -> i8 {
+        self.transpose.get()[channel.index() as usize]
+    }
+
+    /// Play one note at a time on a channel, or play notes independently
+    ///
+    /// The last pressed note has priority. Input channels are used, before
+    /// MPE routing.
+    pub fn set_mono_mode(&self, channel: wmidi::Channel, config: MonoConfig) -> Result<(), PianoError> {
+        self.send_command(EngineCommand::SetMonoMode(channel, config))
+    }
+
+    /// Glide between successive notes of a channel, or disable glide if `None`
+    ///
+    /// FluidLite ignores portamento controllers (CC5 and CC65), glides are
+    /// rendered with pitch bend instead. They only apply to channels in
+    /// monophonic mode, and are limited to the pitch bend range.
+    pub fn set_portamento(&self, channel: wmidi::Channel, time: Option<Duration>) -> Result<(), PianoError> {
+        self.send_command(EngineCommand::SetPortamento(channel, time))
+    }
+
+    /// Enable or disable MPE mode
+    ///
+    /// Each note is routed to its own channel, so that per-channel pitch bends
+    /// apply to a single note. The active preset is used on all channels.
+    /// Mode should be changed while no note is playing.
+    pub fn set_mpe(&self, enabled: bool) -> Result<(), PianoError> {
+        let preset = self.get_active_preset().ok();
+        self.send_command(EngineCommand::SetMpe(enabled))?;
+        self.mpe.set(enabled);
+        if let (Some(preset), true) = (preset, self.sfont_path.is_some()) {
+            self.set_active_preset(preset)?;
+        }
+        Ok(())
+    }
+
+    /// Mute or unmute an input channel
+    ///
+    /// Note-ons of muted channels are dropped, playing notes are stopped.
+    pub fn set_channel_mute(&self, channel: wmidi::Channel, muted: bool) -> Result<(), PianoError> {
+        self.send_command(EngineCommand::SetChannelMute(channel, muted))?;
+        let mut muted_channels = self.muted_channels.get();
+        muted_channels[channel.index() as usize] = muted;
+        self.muted_channels.set(muted_channels);
+        Ok(())
+    }
+
+    /// Return whether an input channel is muted
+    pub fn is_channel_muted(&self, channel: wmidi::Channel) -> bool {
+        self.muted_channels.get()[channel.index() as usize]
+    }
+
+    /// Only play notes of the given input channel, or disable solo
+    ///
+    /// Mutes still apply to the soloed channel. Notes playing on other channels
+    /// are stopped.
+    pub fn set_solo(&self, channel: Option<wmidi::Channel>) -> Result<(), PianoError> {
+        self.send_command(EngineCommand::SetSolo(channel))?;
+        self.solo.set(channel);
+        Ok(())
+    }
+
+    /// Return the soloed input channel, if any
+    pub fn solo(&self) -> Option<wmidi::Channel> {
+        self.solo.get()
+    }
+
+    /// Capture the current state: font, gain, effects and channel state
+    ///
+    /// Presets and controllers are the ones of the synth, as published after
+    /// processed messages.
+    pub fn snapshot(&self) -> Result<StateSnapshot, PianoError> {
+        let presets = self.request(|reply| EngineCommand::GetPresets { reply })?;
+        let channels = ALL_CHANNELS.iter().zip(presets)
+            .map(|(&channel, preset)| ChannelSnapshot {
+                preset,
+                transpose: self.transpose(channel),
+                controllers: (0..wmidi::ControlFunction::ALL_SOUND_OFF.0.into())
+                    .filter_map(|cc: u8| {
+                        let value = self.shared.cc_value(channel, wmidi::U7::try_from(cc).unwrap().into())?;
+                        Some((cc, value))
+                    })
+                    .collect(),
+            })
+            .collect();
+        Ok(StateSnapshot {
+            sfont: self.sfont_path.clone(),
+            gain: self.gain(),
+            reverb: self.reverb(),
+            chorus: self.chorus(),
+            channels,
+        })
+    }
+
+    /// Restore a state captured by `snapshot()`
+    ///
+    /// The font is only loaded if it is not already. Bank select controllers
+    /// are not sent, presets are selected directly. Parameter number
+    /// controllers (RPN, NRPN and data entry) are not sent either, their
+    /// effect depends on message order.
+    pub fn restore(&mut self, snapshot: &StateSnapshot) -> Result<(), PianoError> {
+        use wmidi::ControlFunction;
+        const SKIPPED_CONTROLLERS: [ControlFunction; 10] = [
+            ControlFunction::BANK_SELECT,
+            ControlFunction::BANK_SELECT_LSB,
+            ControlFunction::DATA_ENTRY_MSB,
+            ControlFunction::DATA_ENTRY_LSB,
+            ControlFunction::DATA_INCREMENT,
+            ControlFunction::DATA_DECREMENT,
+            ControlFunction::NON_REGISTERED_PARAMETER_NUMBER_LSB,
+            ControlFunction::NON_REGISTERED_PARAMETER_NUMBER_MSB,
+            ControlFunction::REGISTERED_PARAMETER_NUMBER_LSB,
+            ControlFunction::REGISTERED_PARAMETER_NUMBER_MSB,
+        ];
+
+        if let Some(ref path) = snapshot.sfont {
+            if self.sfont_path.as_ref() != Some(path) {
+                self.load_sfont(path)?;
+            }
+        }
+        self.set_gain(snapshot.gain);
+        self.set_reverb(snapshot.reverb)?;
+        self.set_chorus(snapshot.chorus)?;
+        for (channel, state) in ALL_CHANNELS.iter().zip(&snapshot.channels) {
+            self.set_transpose(Some(*channel), state.transpose)?;
+            if let Some(preset) = state.preset {
+                let channels = std::slice::from_ref(channel);
+                self.request(|reply| EngineCommand::SelectPreset { channels, preset, release_notes: false, reply })?;
+            }
+            for &(cc, value) in &state.controllers {
+                let ctrl = ControlFunction::from(wmidi::U7::try_from(cc).map_err(anyhow::Error::from)?);
+                if SKIPPED_CONTROLLERS.contains(&ctrl) {
+                    continue;
+                }
+                let value = wmidi::U7::try_from(value).map_err(anyhow::Error::from)?;
+                self.send_input(MidiMessage::ControlChange(*channel, ctrl, value))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Silence output on panic, before the previous hook is called
+    ///
+    /// The hook waits for a silent buffer to be rendered, so that it is heard
+    /// before the process aborts. A panic of the audio thread itself leaves
+    /// the engine locked, which also outputs silence.
+    fn install_panic_hook(shared: &Arc<EngineShared>) {
+        let shared = Arc::downgrade(shared);
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if let Some(shared) = shared.upgrade() {
+                shared.silenced.store(true, Ordering::Relaxed);
+                let start = Instant::now();
+                let count = shared.render_count.load(Ordering::Relaxed);
+                // Wait for two buffers: the current one may have started before
+                while shared.render_count.load(Ordering::Relaxed).wrapping_sub(count) < 2
+                    && start.elapsed() < Self::PANIC_SILENCE_TIMEOUT
+                {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            }
+            previous(info);
+        }));
+    }
+
+    /// Lock the engine, to run an operation too heavy for the audio thread
+    fn lock_engine(&self) -> Result<MutexGuard<'_, Engine>, PianoError> {
+        // Poisoned if the audio thread panicked, output stays silent
+        self.engine.lock().map_err(|_| anyhow::anyhow!("audio engine stopped").into())
+    }
+
+    /// Send a command to the engine, without waiting for it to be applied
+    fn send_command(&self, command: EngineCommand) -> Result<(), PianoError> {
+        self.commands.send(command).map_err(|_| anyhow::anyhow!("audio engine stopped").into())
+    }
+
+    /// Send a message to the engine, like inputs
+    fn send_input(&self, message: MidiMessage) -> Result<(), PianoError> {
+        self.input_tx.send(message).map_err(|_| anyhow::anyhow!("audio engine stopped").into())
+    }
+
+    /// Send a command to the engine, wait for its result
+    fn request<T, F>(&self, command: F) -> Result<T, PianoError>
+    where
+        F: FnOnce(Sender<Result<T>>) -> EngineCommand,
+    {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        self.send_command(command(reply_tx))?;
+        let result = reply_rx.recv_timeout(Self::REQUEST_TIMEOUT)
+            .context("audio engine did not reply")?;
+        Ok(result?)
+    }
+
+    /// Return channels the active preset is selected on
+    fn preset_channels(&self) -> &'static [wmidi::Channel] {
+        if self.mpe.get() {
+            &MpeRouter::CHANNELS
+        } else {
+            &[wmidi::Channel::Ch1]
+        }
+    }
+
     /// Return data of all available presets
     pub fn presets_data(&self) -> &[PresetData] {
         &self.presets_data
     }
-}
 
+    /// Return data of all available presets, grouped by bank
+    ///
+    /// Banks are sorted, presets of a bank are in `presets_data()` order.
+    pub fn presets_by_bank(&self) -> BTreeMap<u32, Vec<&PresetData>> {
+        let mut banks: BTreeMap<u32, Vec<&PresetData>> = BTreeMap::new();
+        for preset_data in &self.presets_data {
+            banks.entry(preset_data.bank).or_default().push(preset_data);
+        }
+        banks
+    }
+}
 
 impl From<&PresetData> for Preset {
     fn from(o: &PresetData) -> Self {
@@ -158,23 +1004,76 @@ pub trait PianoInput {
     /// Connect the input to the given queue
     ///
     /// Input must be disconnected with returned data is dropped.
-    fn connect_input(self, queue: Sender<MidiMessage>) -> Result<Box<dyn std::any::Any>>;
+    fn connect_input(self, queue: Sender<MidiMessage>) -> Result<Box<dyn std::any::Any>, PianoError>;
 }
 
-/// MIDI input, with an optional port name to use
-pub struct PianoMidiInput<'a>(pub Option<&'a str>);
+/// MIDI input, with an optional port name or index to use
+///
+/// Index is the position of the port in `MidiInput::ports()`.
+pub struct PianoMidiInput<'a> {
+    pub port: Option<&'a str>,
+    /// Log dropped SysEx messages, instead of silently ignoring them
+    pub log_sysex: bool,
+}
+
+impl<'a> PianoMidiInput<'a> {
+    pub fn new(port: Option<&'a str>) -> Self {
+        Self { port, log_sysex: false }
+    }
+}
 
 impl<'a> PianoInput for PianoMidiInput<'a> {
-    fn connect_input(self, queue: Sender<MidiMessage>) -> Result<Box<dyn std::any::Any>> {
-        let midi = MidiInput::new()?;
-        let port = if let Some(port_name) = self.0 {
-            midi.ports()?.into_iter().find(|p| p.name() == port_name)
-                .with_context(|| format!("MIDI input port not found: {}", port_name))?
-        } else {
-            midi.default_port().context("no MIDI input port")?
-        };
+    fn connect_input(self, queue: Sender<MidiMessage>) -> Result<Box<dyn std::any::Any>, PianoError> {
+        let midi = MidiInput::new()?.with_sysex_log(self.log_sysex);
+        let port = midi.find_port(self.port)?;
         let source = midi.connect_queue(port, queue)?;
         Ok(Box::new(source))
     }
 }
 
+/// Raw MIDI bytes read from the standard input, for use in pipelines
+///
+/// Messages may be split over several reads, and use running status.
+/// Reading stops at the end of the input, or on the first read after the
+/// input has been disconnected, since a blocking read cannot be interrupted.
+#[derive(Default)]
+pub struct PianoStdinInput {
+    /// Log dropped SysEx messages, instead of silently ignoring them
+    pub log_sysex: bool,
+}
+
+impl PianoInput for PianoStdinInput {
+    fn connect_input(self, queue: Sender<MidiMessage>) -> Result<Box<dyn std::any::Any>, PianoError> {
+        use std::io::Read;
+
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        let mut parser = MidiParser::new();
+        parser.set_log_sysex(self.log_sysex);
+        std::thread::spawn(move || {
+            let mut stdin = std::io::stdin().lock();
+            let mut buf = [0u8; 256];
+            loop {
+                let len = match stdin.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => len,
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        eprintln!("failed to read MIDI data from stdin: {}", err);
+                        break;
+                    }
+                };
+                // Stop when the returned handle has been dropped
+                if matches!(stop_rx.try_recv(), Err(mpsc::TryRecvError::Disconnected)) {
+                    break;
+                }
+                let mut disconnected = false;
+                parser.parse(&buf[..len], |message| disconnected |= queue.send(message).is_err());
+                if disconnected {
+                    break;
+                }
+            }
+        });
+        Ok(Box::new(stop_tx))
+    }
+}
+
@@ -0,0 +1,652 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use wmidi::{Channel, ControlFunction, Note, U7, U14};
+use crate::midi::MidiMessage;
+use crate::smf::MidiFile;
+use crate::error::PianoError;
+
+
+/// Handle on a MIDI file being played on a piano
+///
+/// Events are sent from a dedicated thread, to the piano input queue.
+/// Playback stops when the handle is dropped.
+pub struct MidiFilePlayer {
+    commands: Sender<PlayerCommand>,
+    thread: Option<JoinHandle<()>>,
+    shared: Arc<PlayerShared>,
+    duration: Duration,
+}
+
+enum PlayerCommand {
+    Pause,
+    Resume,
+    Seek(Duration),
+    SetLoop(Option<(Duration, Duration)>),
+    SetTempoScale(f32),
+    Stop,
+}
+
+/// State published by the player thread
+struct PlayerShared {
+    /// Position of the last sent event, in microseconds
+    position_us: AtomicU64,
+    /// Set when all events have been sent
+    finished: AtomicBool,
+}
+
+impl MidiFilePlayer {
+    /// Start playing a file, sending its events to the given queue
+    ///
+    /// If `count_in_bars` is not null, metronome clicks are played before the
+    /// file, using its initial tempo and time signature.
+    pub(crate) fn start(file: MidiFile, queue: Sender<MidiMessage>, count_in_bars: u32) -> Result<Self> {
+        let (commands_tx, commands_rx) = mpsc::channel();
+        let shared = Arc::new(PlayerShared {
+            position_us: AtomicU64::new(0),
+            finished: AtomicBool::new(false),
+        });
+        let duration = file.duration();
+        let mut state = PlayerState::new(file, queue, Arc::clone(&shared), count_in_bars);
+        let thread = std::thread::Builder::new()
+            .name("midi-file-player".into())
+            .spawn(move || state.run(commands_rx))
+            .context("failed to start MIDI file player thread")?;
+        Ok(Self { commands: commands_tx, thread: Some(thread), shared, duration })
+    }
+
+    /// Pause playback, stop sounding notes
+    pub fn pause(&self) -> Result<(), PianoError> {
+        self.send_command(PlayerCommand::Pause)
+    }
+
+    /// Resume playback, from the current position
+    pub fn resume(&self) -> Result<(), PianoError> {
+        self.send_command(PlayerCommand::Resume)
+    }
+
+    /// Move to given position, keep playing if not paused
+    ///
+    /// Controller, program and pitch bend values in effect at the new position
+    /// are sent, so that the file plays as if it was played from the start.
+    pub fn seek(&self, position: Duration) -> Result<(), PianoError> {
+        self.send_command(PlayerCommand::Seek(position))
+    }
+
+    /// Repeat playback between given positions, or stop looping
+    ///
+    /// When the end position is reached, sounding notes are stopped and playback
+    /// jumps back to the start position, with controllers chased.
+    pub fn set_loop(&self, range: Option<(Duration, Duration)>) -> Result<(), PianoError> {
+        if let Some((start, end)) = range {
+            if start >= end {
+                return Err(anyhow::anyhow!("loop start must be before its end").into());
+            }
+        }
+        self.send_command(PlayerCommand::SetLoop(range))
+    }
+
+    /// Change the playback speed, 1.0 for the file tempo
+    ///
+    /// For instance, 0.5 plays the file at half speed. Pitch is not changed.
+    /// Changes apply from the current position.
+    pub fn set_tempo_scale(&self, scale: f32) -> Result<(), PianoError> {
+        if !(scale > 0.0 && scale.is_finite()) {
+            return Err(anyhow::anyhow!("invalid tempo scale: {}", scale).into());
+        }
+        self.send_command(PlayerCommand::SetTempoScale(scale))
+    }
+
+    /// Stop playback, wait for the player thread to end
+    pub fn stop(self) {}
+
+    /// Return the position of the last played event
+    pub fn position(&self) -> Duration {
+        Duration::from_micros(self.shared.position_us.load(Ordering::Relaxed))
+    }
+
+    /// Return the duration of the file
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Return true if all events have been played
+    pub fn is_finished(&self) -> bool {
+        self.shared.finished.load(Ordering::Relaxed)
+    }
+
+    fn send_command(&self, command: PlayerCommand) -> Result<(), PianoError> {
+        self.commands.send(command).map_err(|_| anyhow::anyhow!("MIDI file player stopped").into())
+    }
+}
+
+impl Drop for MidiFilePlayer {
+    fn drop(&mut self) {
+        // Fails if the thread already ended, because the piano was dropped
+        let _ = self.commands.send(PlayerCommand::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+
+/// State of the player thread
+struct PlayerState {
+    file: MidiFile,
+    queue: Sender<MidiMessage>,
+    shared: Arc<PlayerShared>,
+    /// Index of the next event to send
+    index: usize,
+    /// Time and file position from which playback is timed, `None` when paused
+    anchor: Option<(Instant, Duration)>,
+    /// Position at which playback is paused
+    paused_position: Duration,
+    /// Playback speed, relative to the file tempo
+    tempo_scale: f32,
+    /// Notes started by the file and not stopped yet
+    sounding: Vec<(Channel, Note)>,
+    /// Start and end positions of the looped section
+    loop_range: Option<(Duration, Duration)>,
+    /// Remaining count-in clicks, with their time
+    count_in: VecDeque<(Instant, MidiMessage)>,
+}
+
+impl PlayerState {
+    /// Percussion channel, used by count-in clicks
+    const CLICK_CHANNEL: Channel = Channel::Ch10;
+    /// General MIDI metronome bell, played on the first beat of bars
+    const CLICK_BAR_NOTE: Note = Note::Bb1;
+    /// General MIDI metronome click
+    const CLICK_BEAT_NOTE: Note = Note::A1;
+
+    fn new(file: MidiFile, queue: Sender<MidiMessage>, shared: Arc<PlayerShared>, count_in_bars: u32) -> Self {
+        let now = Instant::now();
+        let beat = file.beat_duration();
+        let beats_per_bar = file.beats_per_bar() as u32;
+        let velocity = U7::from_u8_lossy(100);
+        let count_in = (0..count_in_bars * beats_per_bar)
+            .flat_map(|i| {
+                let note = if i % beats_per_bar == 0 { Self::CLICK_BAR_NOTE } else { Self::CLICK_BEAT_NOTE };
+                let time = now + beat * i;
+                [
+                    (time, MidiMessage::NoteOn(Self::CLICK_CHANNEL, note, velocity)),
+                    (time + beat / 2, MidiMessage::NoteOff(Self::CLICK_CHANNEL, note, U7::MIN)),
+                ]
+            })
+            .collect();
+        // File time zero is aligned on the first beat after the count-in
+        let start = now + beat * count_in_bars * beats_per_bar;
+        Self {
+            file,
+            queue,
+            shared,
+            index: 0,
+            anchor: Some((start, Duration::ZERO)),
+            paused_position: Duration::ZERO,
+            tempo_scale: 1.0,
+            sounding: vec![],
+            loop_range: None,
+            count_in,
+        }
+    }
+
+    /// Send events until stopped, or until the piano is dropped
+    fn run(&mut self, commands: Receiver<PlayerCommand>) {
+        loop {
+            let next = self.file.events().get(self.index).map(|(time, _)| *time);
+            // Jump back before sending events at or after the loop end
+            let loop_end = self.loop_range
+                .map(|(_, end)| end)
+                .filter(|end| next.is_none_or(|time| time >= *end));
+            let deadline = match (self.anchor, loop_end.or(next), self.count_in.front()) {
+                (None, ..) => None,
+                (Some(_), _, Some((click_time, _))) => Some(*click_time),
+                (Some(_), time, None) => time.map(|time| self.instant_of(time)),
+            };
+            let command = match deadline {
+                Some(deadline) => {
+                    match commands.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                        Ok(command) => command,
+                        Err(RecvTimeoutError::Timeout) => {
+                            let result = if let Some((_, click)) = self.count_in.pop_front() {
+                                self.send(click)
+                            } else if loop_end.is_some() {
+                                self.restart_loop()
+                            } else {
+                                self.send_next()
+                            };
+                            if result.is_err() {
+                                return;
+                            }
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => PlayerCommand::Stop,
+                    }
+                }
+                _ => commands.recv().unwrap_or(PlayerCommand::Stop),
+            };
+
+            let result = match command {
+                PlayerCommand::Pause => self.pause(),
+                PlayerCommand::Resume => {
+                    if self.anchor.is_none() {
+                        self.anchor = Some((Instant::now(), self.paused_position));
+                    }
+                    Ok(())
+                }
+                PlayerCommand::Seek(position) => self.seek(position),
+                PlayerCommand::SetLoop(range) => {
+                    self.loop_range = range;
+                    Ok(())
+                }
+                PlayerCommand::SetTempoScale(scale) => {
+                    // Restart timing from the current position, to avoid a jump
+                    // Playback start is kept during count-in
+                    if let Some((anchor_time, _)) = self.anchor {
+                        let now = Instant::now().max(anchor_time);
+                        self.anchor = Some((now, self.position_at(now)));
+                    }
+                    self.tempo_scale = scale;
+                    Ok(())
+                }
+                PlayerCommand::Stop => {
+                    let _ = self.stop_notes();
+                    return;
+                }
+            };
+            if result.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Return the file position played at the given time
+    ///
+    /// Must only be called when not paused.
+    fn position_at(&self, time: Instant) -> Duration {
+        let (anchor_time, anchor_position) = self.anchor.expect("player is paused");
+        anchor_position + time.saturating_duration_since(anchor_time).mul_f32(self.tempo_scale)
+    }
+
+    /// Return the time at which the given file position is played
+    ///
+    /// Must only be called when not paused.
+    fn instant_of(&self, position: Duration) -> Instant {
+        let (anchor_time, anchor_position) = self.anchor.expect("player is paused");
+        anchor_time + position.saturating_sub(anchor_position).div_f32(self.tempo_scale)
+    }
+
+    fn send(&self, message: MidiMessage) -> Result<()> {
+        self.queue.send(message)?;
+        Ok(())
+    }
+
+    /// Send the next event, track sounding notes
+    fn send_next(&mut self) -> Result<()> {
+        let (time, message) = self.file.events()[self.index].clone();
+        match message {
+            MidiMessage::NoteOn(channel, note, velocity) if velocity != U7::MIN => {
+                self.sounding.push((channel, note));
+            }
+            MidiMessage::NoteOn(channel, note, _) | MidiMessage::NoteOff(channel, note, _) => {
+                self.sounding.retain(|held| *held != (channel, note));
+            }
+            _ => {}
+        }
+        self.send(message)?;
+        self.index += 1;
+        self.shared.position_us.store(time.as_micros() as u64, Ordering::Relaxed);
+        if self.index == self.file.events().len() {
+            self.shared.finished.store(true, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        if self.anchor.is_some() {
+            self.paused_position = self.position_at(Instant::now());
+            self.anchor = None;
+            self.stop_notes()?;
+        }
+        Ok(())
+    }
+
+    fn seek(&mut self, position: Duration) -> Result<()> {
+        self.stop_notes()?;
+        self.index = self.file.events().partition_point(|(time, _)| *time < position);
+        self.shared.position_us.store(position.as_micros() as u64, Ordering::Relaxed);
+        self.shared.finished.store(self.index == self.file.events().len(), Ordering::Relaxed);
+        for message in chase_controllers(self.file.events(), position) {
+            self.send(message)?;
+        }
+        match self.anchor {
+            Some(_) => self.anchor = Some((Instant::now(), position)),
+            None => self.paused_position = position,
+        }
+        Ok(())
+    }
+
+    /// Jump back to the loop start
+    fn restart_loop(&mut self) -> Result<()> {
+        if let (Some(_), Some((begin, end))) = (self.anchor, self.loop_range) {
+            let end_time = self.instant_of(end);
+            self.seek(begin)?;
+            // Keep passes aligned, whatever the time spent chasing
+            self.anchor = Some((end_time, begin));
+        }
+        Ok(())
+    }
+
+    /// Stop notes started by the file, including sustained ones
+    ///
+    /// Remaining count-in is dropped.
+    fn stop_notes(&mut self) -> Result<()> {
+        self.count_in.clear();
+        for (channel, note) in std::mem::take(&mut self.sounding) {
+            self.send(MidiMessage::NoteOff(channel, note, U7::MIN))?;
+        }
+        for channel in (0..16).map(|i| Channel::from_index(i).unwrap()) {
+            self.send(MidiMessage::ControlChange(channel, ControlFunction::ALL_SOUND_OFF, U7::MIN))?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Return messages restoring channel state at the given position
+///
+/// Events must be sorted by time. Program, controller and pitch bend values
+/// set before `up_to` are restored; controllers which are only changed later
+/// are reset to their default value, for backward seeks. RPN and NRPN values
+/// are restored for each parameter, then the selected parameter is restored.
+///
+/// Programs and parameters not set before `up_to` are left unchanged, since
+/// their initial value depends on the piano configuration.
+pub(crate) fn chase_controllers(events: &[(Duration, MidiMessage)], up_to: Duration) -> Vec<MidiMessage> {
+    let mut states: Vec<ChannelState> = (0..16).map(|_| ChannelState::default()).collect();
+    for (time, message) in events {
+        let state = match message.channel() {
+            Some(channel) => &mut states[channel.index() as usize],
+            None => continue,
+        };
+        if *time < up_to {
+            state.apply(message);
+        } else if let MidiMessage::ControlChange(_, function, _) = message {
+            state.used_later[u8::from(function.0) as usize] = true;
+        } else if let MidiMessage::PitchBendChange(..) = message {
+            state.pitch_bend_used_later = true;
+        }
+    }
+
+    let mut messages = vec![];
+    for (channel, state) in (0..16).map(|i| Channel::from_index(i).unwrap()).zip(&states) {
+        state.restore(channel, &mut messages);
+    }
+    messages
+}
+
+/// Channel state, tracked to chase controllers
+struct ChannelState {
+    /// Last value of each controller, channel mode messages excluded
+    controls: [Option<U7>; 120],
+    /// Controllers changed after the chase position
+    used_later: [bool; 128],
+    program: Option<U7>,
+    pitch_bend: Option<U14>,
+    pitch_bend_used_later: bool,
+    /// Selected parameter, NRPN or RPN
+    parameter: Parameter,
+    /// Data entry values (MSB and LSB) of parameters
+    parameter_values: BTreeMap<Parameter, (U7, Option<U7>)>,
+}
+
+/// Registered or non-registered parameter number, MSB then LSB
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
+enum Parameter {
+    Rpn(U7, U7),
+    Nrpn(U7, U7),
+}
+
+impl ChannelState {
+    const BANK_SELECT_MSB: u8 = 0;
+    const BANK_SELECT_LSB: u8 = 32;
+    const DATA_ENTRY_MSB: u8 = 6;
+    const DATA_ENTRY_LSB: u8 = 38;
+    const DATA_INCREMENT: u8 = 96;
+    const DATA_DECREMENT: u8 = 97;
+    const NRPN_LSB: u8 = 98;
+    const NRPN_MSB: u8 = 99;
+    const RPN_LSB: u8 = 100;
+    const RPN_MSB: u8 = 101;
+
+    /// Controllers restored separately, before or after the others
+    const SPECIAL_CONTROLS: [u8; 10] = [
+        Self::BANK_SELECT_MSB, Self::BANK_SELECT_LSB,
+        Self::DATA_ENTRY_MSB, Self::DATA_ENTRY_LSB,
+        Self::DATA_INCREMENT, Self::DATA_DECREMENT,
+        Self::NRPN_LSB, Self::NRPN_MSB, Self::RPN_LSB, Self::RPN_MSB,
+    ];
+
+    /// Null parameter, selected to disable data entry
+    const NULL_PARAMETER: Parameter = Parameter::Rpn(U7::MAX, U7::MAX);
+
+    fn apply(&mut self, message: &MidiMessage) {
+        match *message {
+            MidiMessage::ControlChange(_, function, value) => {
+                let num = u8::from(function.0);
+                match num {
+                    Self::DATA_ENTRY_MSB if self.parameter != Self::NULL_PARAMETER => {
+                        self.parameter_values.insert(self.parameter, (value, None));
+                    }
+                    Self::DATA_ENTRY_LSB => {
+                        if let Some(values) = self.parameter_values.get_mut(&self.parameter) {
+                            values.1 = Some(value);
+                        }
+                    }
+                    Self::NRPN_LSB | Self::RPN_LSB => self.parameter = self.parameter.with_lsb(num == Self::NRPN_LSB, value),
+                    Self::NRPN_MSB | Self::RPN_MSB => self.parameter = self.parameter.with_msb(num == Self::NRPN_MSB, value),
+                    _ => {}
+                }
+                if let Some(control) = self.controls.get_mut(num as usize) {
+                    *control = Some(value);
+                }
+            }
+            MidiMessage::ProgramChange(_, program) => self.program = Some(program),
+            MidiMessage::PitchBendChange(_, value) => self.pitch_bend = Some(value),
+            _ => {}
+        }
+    }
+
+    /// Push messages restoring the state
+    ///
+    /// Bank selection is sent before program changes, for them to apply.
+    fn restore(&self, channel: Channel, messages: &mut Vec<MidiMessage>) {
+        let control = |num: u8, value: U7| {
+            MidiMessage::ControlChange(channel, ControlFunction(U7::from_u8_lossy(num)), value)
+        };
+
+        for num in [Self::BANK_SELECT_MSB, Self::BANK_SELECT_LSB] {
+            if let Some(value) = self.controls[num as usize] {
+                messages.push(control(num, value));
+            }
+        }
+        if let Some(program) = self.program {
+            messages.push(MidiMessage::ProgramChange(channel, program));
+        }
+
+        for (num, value) in self.controls.iter().enumerate() {
+            let num = num as u8;
+            if Self::SPECIAL_CONTROLS.contains(&num) {
+                continue;
+            }
+            match value {
+                Some(value) => messages.push(control(num, *value)),
+                None if self.used_later[num as usize] => messages.push(control(num, Self::default_control(num))),
+                None => {}
+            }
+        }
+
+        for (parameter, (msb, lsb)) in &self.parameter_values {
+            messages.extend(parameter.select(channel));
+            messages.push(control(Self::DATA_ENTRY_MSB, *msb));
+            if let Some(lsb) = lsb {
+                messages.push(control(Self::DATA_ENTRY_LSB, *lsb));
+            }
+        }
+        if !self.parameter_values.is_empty() || self.parameter != Self::NULL_PARAMETER {
+            messages.extend(self.parameter.select(channel));
+        }
+
+        match self.pitch_bend {
+            Some(value) => messages.push(MidiMessage::PitchBendChange(channel, value)),
+            None if self.pitch_bend_used_later => {
+                messages.push(MidiMessage::PitchBendChange(channel, U14::try_from(0x2000).unwrap()));
+            }
+            None => {}
+        }
+    }
+
+    /// Return the value of a controller after a reset
+    fn default_control(num: u8) -> U7 {
+        match num {
+            // Channel volume
+            7 => U7::from_u8_lossy(100),
+            // Balance, pan
+            8 | 10 => U7::from_u8_lossy(64),
+            // Expression
+            11 => U7::MAX,
+            _ => U7::MIN,
+        }
+    }
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        Self {
+            controls: [None; 120],
+            used_later: [false; 128],
+            program: None,
+            pitch_bend: None,
+            pitch_bend_used_later: false,
+            parameter: Self::NULL_PARAMETER,
+            parameter_values: BTreeMap::new(),
+        }
+    }
+}
+
+impl Parameter {
+    /// Change the MSB, select an NRPN or an RPN
+    fn with_msb(self, nrpn: bool, msb: U7) -> Self {
+        match (self, nrpn) {
+            (Self::Nrpn(_, lsb), true) => Self::Nrpn(msb, lsb),
+            (Self::Rpn(_, lsb), false) => Self::Rpn(msb, lsb),
+            (_, true) => Self::Nrpn(msb, U7::MAX),
+            (_, false) => Self::Rpn(msb, U7::MAX),
+        }
+    }
+
+    /// Change the LSB, select an NRPN or an RPN
+    fn with_lsb(self, nrpn: bool, lsb: U7) -> Self {
+        match (self, nrpn) {
+            (Self::Nrpn(msb, _), true) => Self::Nrpn(msb, lsb),
+            (Self::Rpn(msb, _), false) => Self::Rpn(msb, lsb),
+            (_, true) => Self::Nrpn(U7::MAX, lsb),
+            (_, false) => Self::Rpn(U7::MAX, lsb),
+        }
+    }
+
+    /// Return messages selecting the parameter
+    fn select(self, channel: Channel) -> [MidiMessage; 2] {
+        let (msb_num, lsb_num, msb, lsb) = match self {
+            Self::Rpn(msb, lsb) => (ControlFunction::REGISTERED_PARAMETER_NUMBER_MSB, ControlFunction::REGISTERED_PARAMETER_NUMBER_LSB, msb, lsb),
+            Self::Nrpn(msb, lsb) => (ControlFunction::NON_REGISTERED_PARAMETER_NUMBER_MSB, ControlFunction::NON_REGISTERED_PARAMETER_NUMBER_LSB, msb, lsb),
+        };
+        [
+            MidiMessage::ControlChange(channel, msb_num, msb),
+            MidiMessage::ControlChange(channel, lsb_num, lsb),
+        ]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use wmidi::{Channel, ControlFunction, MidiMessage, U7, U14};
+    use super::{chase_controllers, ChannelState};
+
+    fn cc(num: u8, value: u8) -> MidiMessage<'static> {
+        MidiMessage::ControlChange(Channel::Ch1, ControlFunction(U7::from_u8_lossy(num)), U7::from_u8_lossy(value))
+    }
+
+    fn bend(value: u16) -> MidiMessage<'static> {
+        MidiMessage::PitchBendChange(Channel::Ch1, U14::try_from(value).unwrap())
+    }
+
+    /// Chase events given with their time in milliseconds, up to 100 ms
+    fn chase(events: &[(u64, MidiMessage<'static>)]) -> Vec<MidiMessage<'static>> {
+        let events: Vec<_> = events.iter().map(|(ms, message)| (Duration::from_millis(*ms), message.clone())).collect();
+        chase_controllers(&events, Duration::from_millis(100))
+    }
+
+    #[test]
+    fn bank_select_before_program() {
+        let program = MidiMessage::ProgramChange(Channel::Ch1, U7::from_u8_lossy(5));
+        let messages = chase(&[(0, program.clone()), (10, cc(0, 1)), (20, cc(32, 2)), (30, cc(7, 90))]);
+        assert_eq!(messages, [cc(0, 1), cc(32, 2), program, cc(7, 90)]);
+    }
+
+    #[test]
+    fn controllers_used_later() {
+        let messages = chase(&[(50, cc(1, 30)), (100, cc(7, 90)), (200, cc(10, 0)), (200, cc(1, 40)), (300, cc(64, 127))]);
+        // Set later only: reset to their default value
+        assert_eq!(messages, [cc(1, 30), cc(7, 100), cc(10, 64), cc(64, 0)]);
+    }
+
+    #[test]
+    fn pitch_bend() {
+        assert_eq!(chase(&[(50, bend(0x3000)), (200, bend(0x1000))]), [bend(0x3000)]);
+        assert_eq!(chase(&[(200, bend(0x1000))]), [bend(0x2000)]);
+        assert_eq!(chase(&[(200, cc(7, 90))]), [cc(7, 100)]);
+    }
+
+    #[test]
+    fn parameter_values() {
+        let messages = chase(&[
+            // Pitch bend range, then fine tuning, then pitch bend range again
+            (0, cc(101, 0)), (0, cc(100, 1)), (0, cc(6, 64)), (0, cc(38, 0)),
+            (10, cc(101, 0)), (10, cc(100, 0)), (10, cc(6, 12)),
+            (20, cc(101, 0)), (20, cc(100, 1)), (20, cc(6, 70)),
+            (30, cc(101, 0)), (30, cc(100, 0)),
+        ]);
+        assert_eq!(messages, [
+            cc(101, 0), cc(100, 0), cc(6, 12),
+            cc(101, 0), cc(100, 1), cc(6, 70),
+            cc(101, 0), cc(100, 0),
+        ]);
+    }
+
+    #[test]
+    fn null_parameter_restored() {
+        let messages = chase(&[(0, cc(101, 0)), (0, cc(100, 0)), (0, cc(6, 12)), (10, cc(101, 127)), (10, cc(100, 127))]);
+        assert_eq!(messages, [cc(101, 0), cc(100, 0), cc(6, 12), cc(101, 127), cc(100, 127)]);
+    }
+
+    #[test]
+    fn restore_skips_data_increment() {
+        let mut state = ChannelState::default();
+        for message in [cc(101, 0), cc(100, 0), cc(6, 2), cc(96, 0), cc(97, 0), cc(7, 90)] {
+            state.apply(&message);
+        }
+        let mut messages = vec![];
+        state.restore(Channel::Ch1, &mut messages);
+        assert!(messages.contains(&cc(7, 90)));
+        assert!(messages.contains(&cc(6, 2)));
+        assert!(!messages.iter().any(|m| matches!(m, MidiMessage::ControlChange(_, f, _) if [96, 97].contains(&u8::from(f.0)))));
+    }
+}
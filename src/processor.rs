@@ -0,0 +1,751 @@
+use std::time::Duration;
+use wmidi::{Channel, Note, U14};
+use crate::midi::{MidiMessage, NoteEvent};
+use crate::mpe::MpeRouter;
+
+
+/// Monophonic mode of a channel
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum MonoConfig {
+    /// Notes are played independently
+    #[default]
+    Off,
+    /// One note at a time: a new note cuts the playing one
+    Exclusive,
+    /// Like `Exclusive`, and releasing the playing note resumes the last
+    /// still-held one
+    Legato,
+}
+
+/// Process input MIDI messages before sending them to the synth
+pub struct MidiProcessor {
+    /// Multiplier applied to note-on velocities
+    input_gain: f32,
+    /// Controller aftertouch is converted to, if any
+    aftertouch_routing: Option<wmidi::ControlFunction>,
+    /// Controller set from note-on velocities, and velocity multiplier
+    velocity_routing: Option<(wmidi::ControlFunction, f32)>,
+    /// Controller set from note-off (release) velocities, if any
+    release_velocity_routing: Option<wmidi::ControlFunction>,
+    /// Route notes to separate channels, if enabled
+    mpe: Option<MpeRouter>,
+    /// Currently held input notes
+    held_notes: Vec<(Channel, Note)>,
+    /// Notes captured when freeze has been enabled
+    frozen_notes: Option<Vec<(Channel, Note)>>,
+    /// Transposition of each channel, in semitones
+    transpose: [i8; 16],
+    /// Whether note-ons of each channel are dropped
+    muted: [bool; 16],
+    /// Only channel whose note-ons are kept, if any
+    solo: Option<Channel>,
+    /// Notes output for started input notes, `None` if out of range
+    transposed_notes: Vec<((Channel, Note), Option<Note>)>,
+    /// Monophonic mode of each channel
+    mono: [MonoConfig; 16],
+    /// Held notes of monophonic channels, with their velocity, the last played one last
+    mono_stacks: [Vec<(Note, wmidi::U7)>; 16],
+    /// Note playing on each monophonic channel, if any
+    mono_playing: [Option<Note>; 16],
+    /// Glide time of each channel, if portamento is enabled
+    portamento: [Option<Duration>; 16],
+    /// Ongoing glide of each channel
+    glides: [Option<Glide>; 16],
+    /// Last input pitch bend of each channel, glides are added to it
+    input_bends: [u16; 16],
+    /// Pitch bend range of the synth, in semitones
+    bend_range: f32,
+}
+
+/// Pitch glide between two notes, rendered with pitch bend
+#[derive(Copy, Clone)]
+struct Glide {
+    /// Pitch offset when the glide starts, in semitones
+    offset: f32,
+    elapsed: Duration,
+    time: Duration,
+}
+
+impl Glide {
+    /// Return the current pitch offset, it decreases linearly
+    fn offset(&self) -> f32 {
+        self.offset * (1.0 - self.elapsed.as_secs_f32() / self.time.as_secs_f32()).max(0.0)
+    }
+}
+
+impl MidiProcessor {
+    pub fn new() -> Self {
+        Self {
+            input_gain: 1.0,
+            aftertouch_routing: None,
+            velocity_routing: None,
+            release_velocity_routing: None,
+            mpe: None,
+            held_notes: vec![],
+            frozen_notes: None,
+            transpose: [0; 16],
+            muted: [false; 16],
+            solo: None,
+            transposed_notes: vec![],
+            mono: [MonoConfig::Off; 16],
+            mono_stacks: Default::default(),
+            mono_playing: [None; 16],
+            portamento: [None; 16],
+            glides: [None; 16],
+            input_bends: [Self::BEND_CENTER; 16],
+            bend_range: Self::DEFAULT_BEND_RANGE,
+        }
+    }
+
+    /// Pitch bend range of FluidLite, until it is changed
+    const DEFAULT_BEND_RANGE: f32 = 2.0;
+    const BEND_CENTER: u16 = 0x2000;
+
+    pub fn set_input_gain(&mut self, gain: f32) {
+        self.input_gain = gain;
+    }
+
+    pub fn set_aftertouch_routing(&mut self, dest: Option<wmidi::ControlFunction>) {
+        self.aftertouch_routing = dest;
+    }
+
+    pub fn set_velocity_routing(&mut self, dest: Option<(wmidi::ControlFunction, f32)>) {
+        self.velocity_routing = dest;
+    }
+
+    pub fn set_release_velocity_routing(&mut self, dest: Option<wmidi::ControlFunction>) {
+        self.release_velocity_routing = dest;
+    }
+
+    pub fn set_mpe(&mut self, enabled: bool) {
+        if enabled != self.mpe.is_some() {
+            self.mpe = enabled.then(MpeRouter::new);
+        }
+    }
+
+    /// Transpose notes of a channel, or of all channels
+    ///
+    /// Started notes are stopped with their initial transposition.
+    pub fn set_transpose(&mut self, channel: Option<Channel>, semitones: i8) {
+        match channel {
+            Some(channel) => self.transpose[channel.index() as usize] = semitones,
+            None => self.transpose = [semitones; 16],
+        }
+    }
+
+    /// Change the monophonic mode of a channel
+    ///
+    /// The playing note is kept, and stopped normally when released.
+    pub fn set_mono_mode(&mut self, channel: Channel, config: MonoConfig) {
+        self.mono[channel.index() as usize] = config;
+        self.mono_stacks[channel.index() as usize].clear();
+        self.mono_playing[channel.index() as usize] = None;
+    }
+
+    /// Glide between successive notes of a monophonic channel, or stop gliding
+    ///
+    /// FluidLite ignores portamento controllers, glides are rendered with pitch
+    /// bend, added to input pitch bend. Intervals are limited to the pitch bend
+    /// range.
+    pub fn set_portamento<E>(&mut self, channel: Channel, time: Option<Duration>, emit: E)
+    where
+        E: FnMut(MidiMessage),
+    {
+        let i = channel.index() as usize;
+        self.portamento[i] = time.filter(|time| !time.is_zero());
+        if self.portamento[i].is_none() && self.glides[i].take().is_some() {
+            self.output_bend(channel, emit);
+        }
+    }
+
+    /// Change the pitch bend range used to render glides
+    pub fn set_bend_range(&mut self, semitones: u8) {
+        self.bend_range = semitones as f32;
+    }
+
+    /// Advance ongoing glides
+    pub fn update_glides<E>(&mut self, elapsed: Duration, mut emit: E)
+    where
+        E: FnMut(MidiMessage),
+    {
+        for i in 0..16 {
+            let Some(ref mut glide) = self.glides[i] else { continue };
+            glide.elapsed += elapsed;
+            if glide.elapsed >= glide.time {
+                self.glides[i] = None;
+            }
+            self.output_bend(Channel::from_index(i as u8).unwrap(), &mut emit);
+        }
+    }
+
+    /// Start a glide to a note, before it is started
+    fn start_glide<E>(&mut self, channel: Channel, from: Note, to: Note, emit: E)
+    where
+        E: FnMut(MidiMessage),
+    {
+        let i = channel.index() as usize;
+        if let Some(time) = self.portamento[i] {
+            // Start from the current pitch, if the previous glide is not done
+            let current = self.glides[i].map_or(0.0, |glide| glide.offset());
+            let offset = (u8::from(from) as f32 + current - u8::from(to) as f32).clamp(-self.bend_range, self.bend_range);
+            self.glides[i] = Some(Glide { offset, elapsed: Duration::ZERO, time });
+            self.output_bend(channel, emit);
+        }
+    }
+
+    /// Output the input pitch bend of a channel, with its glide offset
+    fn output_bend<E>(&mut self, channel: Channel, emit: E)
+    where
+        E: FnMut(MidiMessage),
+    {
+        let i = channel.index() as usize;
+        let offset = match self.glides[i] {
+            // A zero range does not allow to glide
+            Some(glide) if self.bend_range > 0.0 => glide.offset() / self.bend_range * Self::BEND_CENTER as f32,
+            _ => 0.0,
+        };
+        let value = self.input_bends[i] as f32 + offset;
+        let value = value.round().clamp(0.0, u16::from(U14::MAX) as f32) as u16;
+        self.output(MidiMessage::PitchBendChange(channel, U14::try_from(value).unwrap()), emit);
+    }
+
+    /// Mute or unmute a channel
+    ///
+    /// Notes playing on a muted channel are stopped.
+    pub fn set_channel_mute<E>(&mut self, channel: Channel, muted: bool, emit: E)
+    where
+        E: FnMut(MidiMessage),
+    {
+        let silenced = self.silenced_channels();
+        self.muted[channel.index() as usize] = muted;
+        self.stop_silenced(silenced, emit);
+    }
+
+    /// Only play notes of the given channel, or of all unmuted channels
+    ///
+    /// Notes playing on other channels are stopped.
+    pub fn set_solo<E>(&mut self, channel: Option<Channel>, emit: E)
+    where
+        E: FnMut(MidiMessage),
+    {
+        let silenced = self.silenced_channels();
+        self.solo = channel;
+        self.stop_silenced(silenced, emit);
+    }
+
+    fn is_silenced(&self, channel: Channel) -> bool {
+        self.muted[channel.index() as usize] || self.solo.is_some_and(|solo| solo != channel)
+    }
+
+    fn silenced_channels(&self) -> [bool; 16] {
+        std::array::from_fn(|i| self.is_silenced(Channel::from_index(i as u8).unwrap()))
+    }
+
+    /// Stop notes of channels silenced since `previous` has been computed
+    fn stop_silenced<E>(&mut self, previous: [bool; 16], mut emit: E)
+    where
+        E: FnMut(MidiMessage),
+    {
+        for (i, was_silenced) in previous.into_iter().enumerate() {
+            let channel = Channel::from_index(i as u8).unwrap();
+            if !was_silenced && self.is_silenced(channel) {
+                let message = MidiMessage::ControlChange(channel, wmidi::ControlFunction::ALL_NOTES_OFF, wmidi::U7::MIN);
+                self.output_mono(message, &mut emit);
+            }
+        }
+    }
+
+    /// Return currently held input notes, in press order
+    pub fn held_notes(&self) -> &[(Channel, Note)] {
+        &self.held_notes
+    }
+
+    /// Reset notes state, after the synth has been reset
+    pub fn reset(&mut self) {
+        self.held_notes.clear();
+        self.frozen_notes = None;
+        self.transposed_notes.clear();
+        self.mono_stacks.iter_mut().for_each(Vec::clear);
+        self.mono_playing = [None; 16];
+        self.glides = [None; 16];
+        self.input_bends = [Self::BEND_CENTER; 16];
+        if self.mpe.is_some() {
+            self.mpe = Some(MpeRouter::new());
+        }
+    }
+
+    /// Enable or disable freeze
+    ///
+    /// When enabled, currently held notes are captured and are kept playing
+    /// when released. When disabled, note-offs are sent for captured notes
+    /// that are not held anymore.
+    pub fn set_freeze<E>(&mut self, enabled: bool, mut emit: E)
+    where
+        E: FnMut(MidiMessage),
+    {
+        if enabled {
+            if self.frozen_notes.is_none() {
+                self.frozen_notes = Some(self.held_notes.clone());
+            }
+        } else if let Some(frozen_notes) = self.frozen_notes.take() {
+            for (chan, note) in frozen_notes {
+                if !self.held_notes.contains(&(chan, note)) {
+                    // Released notes have been kept in the mono stack
+                    let i = chan.index() as usize;
+                    self.mono_stacks[i].retain(|(n, _)| *n != note);
+                    if self.mono_playing[i] == Some(note) {
+                        self.mono_playing[i] = None;
+                    }
+                    self.output(MidiMessage::NoteOff(chan, note, wmidi::U7::MIN), &mut emit);
+                }
+            }
+        }
+    }
+
+    /// Process a message, call `emit` with resulting messages
+    pub fn process<E>(&mut self, message: MidiMessage, mut emit: E)
+    where
+        E: FnMut(MidiMessage),
+    {
+        let message = match message {
+            MidiMessage::PitchBendChange(chan, value) => {
+                self.input_bends[chan.index() as usize] = value.into();
+                if self.glides[chan.index() as usize].is_some() {
+                    return self.output_bend(chan, emit);
+                }
+                message
+            }
+            // Note-offs are kept, so that notes started before muting are stopped
+            MidiMessage::NoteOn(chan, _, vel) if u8::from(vel) > 0 && self.is_silenced(chan) => return,
+            MidiMessage::NoteOn(chan, key, vel) => MidiMessage::NoteOn(chan, key, self.scale_velocity(vel)),
+            MidiMessage::ChannelPressure(chan, vel) | MidiMessage::PolyphonicKeyPressure(chan, _, vel) => {
+                match self.aftertouch_routing {
+                    Some(ctrl) => MidiMessage::ControlChange(chan, ctrl, vel),
+                    None => message,
+                }
+            }
+            message => message,
+        };
+
+        match NoteEvent::from_message(&message) {
+            Some(NoteEvent::On { channel, note, .. }) if !self.held_notes.contains(&(channel, note)) => {
+                self.held_notes.push((channel, note));
+            }
+            Some(NoteEvent::Off { channel, note }) => {
+                self.held_notes.retain(|&n| n != (channel, note));
+                if let Some(ref frozen_notes) = self.frozen_notes {
+                    if frozen_notes.contains(&(channel, note)) {
+                        return;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        // Set the routed controller before the note starts, so that it applies to it
+        if let (MidiMessage::NoteOn(chan, _, vel), Some((ctrl, amount))) = (&message, self.velocity_routing) {
+            if u8::from(*vel) > 0 {
+                let value = (u8::from(*vel) as f32 * amount).round().clamp(0.0, 127.0);
+                self.output(MidiMessage::ControlChange(*chan, ctrl, wmidi::U7::from_u8_lossy(value as u8)), &mut emit);
+            }
+        }
+        // The synth ignores note-off velocities, forward them before the release
+        if let (MidiMessage::NoteOff(chan, _, vel), Some(ctrl)) = (&message, self.release_velocity_routing) {
+            self.output(MidiMessage::ControlChange(*chan, ctrl, *vel), &mut emit);
+        }
+
+        self.output_mono(message, emit)
+    }
+
+    /// Output a processed message, playing one note at a time on monophonic channels
+    fn output_mono<E>(&mut self, message: MidiMessage, mut emit: E)
+    where
+        E: FnMut(MidiMessage),
+    {
+        let chan = match message {
+            MidiMessage::NoteOn(chan, ..) | MidiMessage::NoteOff(chan, ..) | MidiMessage::ControlChange(chan, ..) => chan,
+            _ => return self.output(message, emit),
+        };
+        let i = chan.index() as usize;
+        let mono = self.mono[i];
+        if mono == MonoConfig::Off {
+            return self.output(message, emit);
+        }
+
+        match message {
+            MidiMessage::NoteOn(_, key, vel) if u8::from(vel) > 0 => {
+                // Cut the playing note, even if it is the same one
+                if let Some(playing) = self.mono_playing[i] {
+                    self.output(MidiMessage::NoteOff(chan, playing, wmidi::U7::MIN), &mut emit);
+                    self.start_glide(chan, playing, key, &mut emit);
+                }
+                let stack = &mut self.mono_stacks[i];
+                stack.retain(|(note, _)| *note != key);
+                stack.push((key, vel));
+                self.mono_playing[i] = Some(key);
+                self.output(message, emit);
+            }
+            MidiMessage::NoteOn(_, key, _) | MidiMessage::NoteOff(_, key, _) => {
+                let stack = &mut self.mono_stacks[i];
+                match stack.iter().position(|(note, _)| *note == key) {
+                    Some(pos) if self.mono_playing[i] == Some(key) => {
+                        stack.remove(pos);
+                        let resumed = stack.last().copied();
+                        self.mono_playing[i] = None;
+                        self.output(message, &mut emit);
+                        if let Some((note, vel)) = resumed.filter(|_| mono == MonoConfig::Legato && !self.is_silenced(chan)) {
+                            self.start_glide(chan, key, note, &mut emit);
+                            self.mono_playing[i] = Some(note);
+                            self.output(MidiMessage::NoteOn(chan, note, vel), emit);
+                        }
+                    }
+                    // Held but cut, it is not playing
+                    Some(pos) => {
+                        stack.remove(pos);
+                    }
+                    // Started before mono mode has been enabled
+                    None => self.output(message, emit),
+                }
+            }
+            MidiMessage::ControlChange(_, wmidi::ControlFunction::ALL_NOTES_OFF | wmidi::ControlFunction::ALL_SOUND_OFF, _) => {
+                self.mono_stacks[i].clear();
+                self.mono_playing[i] = None;
+                self.output(message, emit);
+            }
+            message => self.output(message, emit),
+        }
+    }
+
+    /// Output a processed message
+    fn output<E>(&mut self, message: MidiMessage, mut emit: E)
+    where
+        E: FnMut(MidiMessage),
+    {
+        let mut route = |message| match self.mpe {
+            Some(ref mut mpe) => mpe.route(message, &mut emit),
+            None => emit(message),
+        };
+        match message {
+            MidiMessage::NoteOn(chan, key, vel) if u8::from(vel) > 0 => {
+                let note = Self::transpose_note(key, self.transpose[chan.index() as usize]);
+                let pos = self.transposed_notes.iter().position(|(input, _)| *input == (chan, key));
+                // Stop a retriggered note, if transposition changed meanwhile
+                if let Some((_, Some(previous))) = pos.map(|pos| self.transposed_notes.swap_remove(pos)) {
+                    if Some(previous) != note {
+                        route(MidiMessage::NoteOff(chan, previous, wmidi::U7::MIN));
+                    }
+                }
+                self.transposed_notes.push(((chan, key), note));
+                if let Some(note) = note {
+                    route(MidiMessage::NoteOn(chan, note, vel));
+                }
+            }
+            MidiMessage::NoteOn(chan, key, vel) | MidiMessage::NoteOff(chan, key, vel) => {
+                let note = match self.transposed_notes.iter().position(|(input, _)| *input == (chan, key)) {
+                    Some(pos) => self.transposed_notes.swap_remove(pos).1,
+                    None => Some(key),
+                };
+                if let Some(note) = note {
+                    route(match message {
+                        MidiMessage::NoteOn(..) => MidiMessage::NoteOn(chan, note, vel),
+                        _ => MidiMessage::NoteOff(chan, note, vel),
+                    });
+                }
+            }
+            MidiMessage::PolyphonicKeyPressure(chan, key, value) => {
+                let note = match self.transposed_notes.iter().find(|(input, _)| *input == (chan, key)) {
+                    Some((_, note)) => *note,
+                    None => Self::transpose_note(key, self.transpose[chan.index() as usize]),
+                };
+                if let Some(note) = note {
+                    route(MidiMessage::PolyphonicKeyPressure(chan, note, value));
+                }
+            }
+            message => route(message),
+        }
+    }
+
+    /// Transpose a note, return `None` if out of range
+    fn transpose_note(note: Note, semitones: i8) -> Option<Note> {
+        let num = u8::from(note) as i16 + semitones as i16;
+        u8::try_from(num).ok().and_then(|num| Note::try_from(num).ok())
+    }
+
+    /// Apply input gain to a note-on velocity
+    ///
+    /// Non-zero velocities are kept non-zero, to not be handled as a note-off.
+    fn scale_velocity(&self, vel: wmidi::U7) -> wmidi::U7 {
+        let vel = u8::from(vel);
+        if vel == 0 {
+            return wmidi::U7::MIN;
+        }
+        let scaled = (vel as f32 * self.input_gain).round().clamp(1.0, 127.0);
+        wmidi::U7::from_u8_lossy(scaled as u8)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use wmidi::{Channel, Note, U7, U14};
+    use crate::midi::MidiMessage;
+    use super::{MidiProcessor, MonoConfig};
+
+    const VELOCITY: U7 = U7::from_u8_lossy(100);
+
+    fn process(processor: &mut MidiProcessor, message: MidiMessage) -> Vec<MidiMessage> {
+        let mut messages = vec![];
+        processor.process(message, |m| messages.push(m));
+        messages
+    }
+
+    fn on(note: Note) -> MidiMessage {
+        MidiMessage::NoteOn(Channel::Ch1, note, VELOCITY)
+    }
+
+    fn off(note: Note) -> MidiMessage {
+        MidiMessage::NoteOff(Channel::Ch1, note, U7::MIN)
+    }
+
+    fn set_freeze(processor: &mut MidiProcessor, enabled: bool) -> Vec<MidiMessage> {
+        let mut messages = vec![];
+        processor.set_freeze(enabled, |m| messages.push(m));
+        messages
+    }
+
+    fn all_notes_off(channel: Channel) -> MidiMessage {
+        MidiMessage::ControlChange(channel, wmidi::ControlFunction::ALL_NOTES_OFF, U7::MIN)
+    }
+
+    fn bend(value: u16) -> MidiMessage {
+        MidiMessage::PitchBendChange(Channel::Ch1, U14::try_from(value).unwrap())
+    }
+
+    fn update_glides(processor: &mut MidiProcessor, elapsed: Duration) -> Vec<MidiMessage> {
+        let mut messages = vec![];
+        processor.update_glides(elapsed, |m| messages.push(m));
+        messages
+    }
+
+    fn glide_processor(bend_range: u8) -> MidiProcessor {
+        let mut processor = mono_processor(MonoConfig::Legato);
+        processor.set_bend_range(bend_range);
+        processor.set_portamento(Channel::Ch1, Some(Duration::from_millis(100)), |_| {});
+        processor
+    }
+
+    fn mono_processor(config: MonoConfig) -> MidiProcessor {
+        let mut processor = MidiProcessor::new();
+        processor.set_mono_mode(Channel::Ch1, config);
+        processor
+    }
+
+    #[test]
+    fn legato_resumes_held_note() {
+        let mut processor = mono_processor(MonoConfig::Legato);
+        assert_eq!(process(&mut processor, on(Note::C4)), [on(Note::C4)]);
+        assert_eq!(process(&mut processor, on(Note::E4)), [off(Note::C4), on(Note::E4)]);
+        assert_eq!(process(&mut processor, off(Note::E4)), [off(Note::E4), on(Note::C4)]);
+        assert_eq!(process(&mut processor, off(Note::C4)), [off(Note::C4)]);
+    }
+
+    #[test]
+    fn exclusive_does_not_resume() {
+        let mut processor = mono_processor(MonoConfig::Exclusive);
+        assert_eq!(process(&mut processor, on(Note::C4)), [on(Note::C4)]);
+        assert_eq!(process(&mut processor, on(Note::E4)), [off(Note::C4), on(Note::E4)]);
+        assert_eq!(process(&mut processor, off(Note::E4)), [off(Note::E4)]);
+        assert_eq!(process(&mut processor, off(Note::C4)), []);
+        assert_eq!(process(&mut processor, on(Note::G4)), [on(Note::G4)]);
+    }
+
+    #[test]
+    fn retrigger_playing_note() {
+        let mut processor = mono_processor(MonoConfig::Legato);
+        assert_eq!(process(&mut processor, on(Note::C4)), [on(Note::C4)]);
+        assert_eq!(process(&mut processor, on(Note::C4)), [off(Note::C4), on(Note::C4)]);
+        assert_eq!(process(&mut processor, off(Note::C4)), [off(Note::C4)]);
+    }
+
+    #[test]
+    fn release_cut_note() {
+        let mut processor = mono_processor(MonoConfig::Legato);
+        process(&mut processor, on(Note::C4));
+        process(&mut processor, on(Note::E4));
+        assert_eq!(process(&mut processor, off(Note::C4)), []);
+        // Nothing to resume
+        assert_eq!(process(&mut processor, off(Note::E4)), [off(Note::E4)]);
+    }
+
+    #[test]
+    fn note_started_before_mono() {
+        let mut processor = MidiProcessor::new();
+        assert_eq!(process(&mut processor, on(Note::C4)), [on(Note::C4)]);
+        processor.set_mono_mode(Channel::Ch1, MonoConfig::Legato);
+        assert_eq!(process(&mut processor, on(Note::E4)), [on(Note::E4)]);
+        assert_eq!(process(&mut processor, off(Note::C4)), [off(Note::C4)]);
+        assert_eq!(process(&mut processor, off(Note::E4)), [off(Note::E4)]);
+    }
+
+    #[test]
+    fn freeze_released_note() {
+        let mut processor = MidiProcessor::new();
+        process(&mut processor, on(Note::C4));
+        assert_eq!(set_freeze(&mut processor, true), []);
+        assert_eq!(process(&mut processor, off(Note::C4)), []);
+        assert_eq!(set_freeze(&mut processor, false), [off(Note::C4)]);
+        assert_eq!(process(&mut processor, off(Note::C4)), [off(Note::C4)]);
+    }
+
+    #[test]
+    fn freeze_held_note() {
+        let mut processor = MidiProcessor::new();
+        process(&mut processor, on(Note::C4));
+        set_freeze(&mut processor, true);
+        assert_eq!(set_freeze(&mut processor, false), []);
+        assert_eq!(process(&mut processor, off(Note::C4)), [off(Note::C4)]);
+    }
+
+    #[test]
+    fn freeze_note_pressed_again() {
+        let mut processor = MidiProcessor::new();
+        process(&mut processor, on(Note::C4));
+        set_freeze(&mut processor, true);
+        assert_eq!(process(&mut processor, off(Note::C4)), []);
+        assert_eq!(process(&mut processor, on(Note::C4)), [on(Note::C4)]);
+        assert_eq!(process(&mut processor, off(Note::C4)), []);
+        assert_eq!(set_freeze(&mut processor, false), [off(Note::C4)]);
+    }
+
+    #[test]
+    fn freeze_mono_note() {
+        let mut processor = mono_processor(MonoConfig::Legato);
+        process(&mut processor, on(Note::C4));
+        set_freeze(&mut processor, true);
+        process(&mut processor, off(Note::C4));
+        assert_eq!(set_freeze(&mut processor, false), [off(Note::C4)]);
+        // The unfrozen note is neither cut nor resumed
+        assert_eq!(process(&mut processor, on(Note::E4)), [on(Note::E4)]);
+        assert_eq!(process(&mut processor, off(Note::E4)), [off(Note::E4)]);
+    }
+
+    #[test]
+    fn transpose_changed_while_held() {
+        let mut processor = MidiProcessor::new();
+        processor.set_transpose(Some(Channel::Ch1), 2);
+        assert_eq!(process(&mut processor, on(Note::C4)), [on(Note::D4)]);
+        processor.set_transpose(None, -1);
+        assert_eq!(process(&mut processor, off(Note::C4)), [off(Note::D4)]);
+        // Retriggered with a new transposition
+        process(&mut processor, on(Note::C4));
+        processor.set_transpose(Some(Channel::Ch1), 0);
+        assert_eq!(process(&mut processor, on(Note::C4)), [off(Note::B3), on(Note::C4)]);
+        assert_eq!(process(&mut processor, off(Note::C4)), [off(Note::C4)]);
+    }
+
+    #[test]
+    fn transpose_out_of_range() {
+        let mut processor = MidiProcessor::new();
+        processor.set_transpose(Some(Channel::Ch1), 12);
+        assert_eq!(process(&mut processor, on(Note::G9)), []);
+        processor.set_transpose(Some(Channel::Ch1), 0);
+        // Dropped, not sent for the untransposed note
+        assert_eq!(process(&mut processor, off(Note::G9)), []);
+        assert_eq!(process(&mut processor, on(Note::G9)), [on(Note::G9)]);
+    }
+
+    #[test]
+    fn mute_and_solo() {
+        let mut processor = MidiProcessor::new();
+        let mut messages = vec![];
+        processor.set_channel_mute(Channel::Ch2, true, |m| messages.push(m));
+        assert_eq!(messages, [all_notes_off(Channel::Ch2)]);
+        // Already muted
+        messages.clear();
+        processor.set_channel_mute(Channel::Ch2, true, |m| messages.push(m));
+        assert_eq!(messages, []);
+
+        messages.clear();
+        processor.set_solo(Some(Channel::Ch1), |m| messages.push(m));
+        let channels: Vec<_> = messages.iter().map(|m| m.channel().unwrap()).collect();
+        let expected: Vec<_> = (2..16).map(|i| Channel::from_index(i).unwrap()).collect();
+        assert_eq!(channels, expected);
+
+        // Ch2 is still muted
+        messages.clear();
+        processor.set_solo(Some(Channel::Ch2), |m| messages.push(m));
+        assert_eq!(messages, [all_notes_off(Channel::Ch1)]);
+        messages.clear();
+        processor.set_solo(None, |m| messages.push(m));
+        assert_eq!(messages, []);
+    }
+
+    #[test]
+    fn note_off_on_muted_channel() {
+        let mut processor = MidiProcessor::new();
+        process(&mut processor, on(Note::C4));
+        let mut messages = vec![];
+        processor.set_channel_mute(Channel::Ch1, true, |m| messages.push(m));
+        assert_eq!(messages, [all_notes_off(Channel::Ch1)]);
+        assert_eq!(process(&mut processor, on(Note::E4)), []);
+        assert_eq!(process(&mut processor, off(Note::C4)), [off(Note::C4)]);
+    }
+
+    #[test]
+    fn mute_mono_channel() {
+        let mut processor = mono_processor(MonoConfig::Legato);
+        process(&mut processor, on(Note::C4));
+        process(&mut processor, on(Note::E4));
+        processor.set_channel_mute(Channel::Ch1, true, |_| {});
+        processor.set_channel_mute(Channel::Ch1, false, |_| {});
+        // Held notes have been stopped, they are not cut nor resumed
+        assert_eq!(process(&mut processor, on(Note::G4)), [on(Note::G4)]);
+        assert_eq!(process(&mut processor, off(Note::G4)), [off(Note::G4)]);
+    }
+
+    #[test]
+    fn glide() {
+        let mut processor = glide_processor(4);
+        assert_eq!(process(&mut processor, on(Note::C4)), [on(Note::C4)]);
+        // Two semitones below, half of the bend range
+        assert_eq!(process(&mut processor, on(Note::D4)), [off(Note::C4), bend(0x1000), on(Note::D4)]);
+        assert_eq!(update_glides(&mut processor, Duration::from_millis(50)), [bend(0x1800)]);
+        assert_eq!(update_glides(&mut processor, Duration::from_millis(50)), [bend(0x2000)]);
+        // Done
+        assert_eq!(update_glides(&mut processor, Duration::from_millis(50)), []);
+    }
+
+    #[test]
+    fn glide_with_input_bend() {
+        let mut processor = glide_processor(4);
+        assert_eq!(process(&mut processor, bend(0x2800)), [bend(0x2800)]);
+        process(&mut processor, on(Note::C4));
+        assert_eq!(process(&mut processor, on(Note::D4)), [off(Note::C4), bend(0x1800), on(Note::D4)]);
+        // Input bend changes are added to the glide
+        assert_eq!(process(&mut processor, bend(0x3000)), [bend(0x2000)]);
+        assert_eq!(update_glides(&mut processor, Duration::from_millis(50)), [bend(0x2800)]);
+        assert_eq!(update_glides(&mut processor, Duration::from_millis(60)), [bend(0x3000)]);
+    }
+
+    #[test]
+    fn glide_interrupted() {
+        let mut processor = glide_processor(4);
+        process(&mut processor, on(Note::C4));
+        process(&mut processor, on(Note::D4));
+        assert_eq!(update_glides(&mut processor, Duration::from_millis(50)), [bend(0x1800)]);
+        // Start from the current pitch, one semitone below D4
+        assert_eq!(process(&mut processor, on(Note::E4)), [off(Note::D4), bend(0x0800), on(Note::E4)]);
+        assert_eq!(update_glides(&mut processor, Duration::from_millis(100)), [bend(0x2000)]);
+    }
+
+    #[test]
+    fn glide_without_bend_range() {
+        let mut processor = glide_processor(0);
+        process(&mut processor, on(Note::C4));
+        assert_eq!(process(&mut processor, on(Note::D4)), [off(Note::C4), bend(0x2000), on(Note::D4)]);
+        let mut messages = vec![];
+        processor.set_portamento(Channel::Ch1, None, |m| messages.push(m));
+        assert_eq!(messages, [bend(0x2000)]);
+    }
+}
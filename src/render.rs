@@ -0,0 +1,168 @@
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use crate::compressor::{CompConfig, Compressor};
+use crate::loudness::Normalization;
+use crate::midi::MidiMessage;
+use crate::piano::Preset;
+use crate::smf::MidiFile;
+use crate::synth::{SettingValue, Synth};
+use crate::wav::{write_wav, WavFormat};
+use crate::error::PianoError;
+
+
+/// Offline rendering of timed MIDI events, with reproducible output
+///
+/// Events are processed at their exact frame, counted from the start of the
+/// rendering, instead of using the clock and the audio buffers of live play.
+/// Float samples are never dithered. As a result, the same font, events and
+/// options always produce bit-identical samples, with the same build.
+///
+/// All options affect the output: sample rate, gain, effects, compressor and
+/// synth settings (for instance `synth.polyphony`, which changes voice
+/// stealing). Another FluidLite version or target platform may produce
+/// slightly different samples.
+pub struct OfflineRender {
+    pub sample_rate: u32,
+    pub gain: f32,
+    /// Enable reverb and chorus
+    pub effects: bool,
+    /// Compressor applied on output, if any
+    pub compressor: Option<CompConfig>,
+    /// FluidSynth settings, applied in order before loading the font
+    pub settings: Vec<(String, SettingValue)>,
+    /// Preset selected on the first channel, before events
+    pub preset: Option<Preset>,
+    /// Time rendered after the last event, to hear releases
+    pub tail: Duration,
+    /// Level samples are scaled to once rendered, after the compressor
+    pub normalize: Option<Normalization>,
+}
+
+impl Default for OfflineRender {
+    fn default() -> Self {
+        Self {
+            sample_rate: 44100,
+            gain: Synth::DEFAULT_GAIN,
+            effects: false,
+            compressor: None,
+            settings: vec![],
+            preset: None,
+            tail: Duration::from_secs(1),
+            normalize: None,
+        }
+    }
+}
+
+impl OfflineRender {
+    /// Render events sorted by time, return interleaved stereo samples
+    pub fn render<P: AsRef<Path>>(&self, sfont: P, events: &[(Duration, MidiMessage)]) -> Result<Vec<f32>, PianoError> {
+        let sfont = sfont.as_ref();
+        let synth = Synth::new(self.sample_rate as f64, self.gain, self.effects)?;
+        for (key, value) in &self.settings {
+            synth.set_setting(key, value)?;
+        }
+        let sfont_id = synth.synth.sfload(sfont, true)
+            .map_err(|err| anyhow::anyhow!("{}", err))
+            .with_context(|| format!("failed to load SoundFont {}", sfont.display()))?;
+        if let Some(preset) = self.preset {
+            synth.synth.program_select(0, sfont_id, preset.bank, preset.num)
+                .with_context(|| format!("cannot select preset {}:{}", preset.bank, preset.num))?;
+        }
+
+        let frame = |time: Duration| (time.as_secs_f64() * self.sample_rate as f64) as usize;
+        let end = events.last().map_or(0, |(time, _)| frame(*time)) + frame(self.tail);
+        let mut samples = vec![0.0; end * 2];
+        let mut pos = 0;
+        for (time, message) in events {
+            let offset = frame(*time).max(pos);
+            if offset > pos {
+                synth.write_samples(&mut samples[pos * 2..offset * 2])?;
+                pos = offset;
+            }
+            // Like live play, a message that cannot be applied does not stop rendering
+            synth.send_midi_message(message.clone())
+                .unwrap_or_else(|err| eprintln!("failed to process MIDI message: {}", err));
+        }
+        synth.write_samples(&mut samples[pos * 2..])?;
+
+        if let Some(ref config) = self.compressor {
+            Compressor::new(config, self.sample_rate as f64).process(&mut samples);
+        }
+        if let Some(normalize) = self.normalize {
+            normalize.apply(&mut samples, self.sample_rate);
+        }
+        Ok(samples)
+    }
+
+    /// Render the events of a MIDI file
+    pub fn render_file<P: AsRef<Path>>(&self, sfont: P, file: &MidiFile) -> Result<Vec<f32>, PianoError> {
+        self.render(sfont, file.events())
+    }
+
+    /// Render events to a WAV file
+    ///
+    /// Output is reproducible for all formats, including dithered 16-bit PCM.
+    pub fn write_wav<P, Q>(&self, sfont: P, events: &[(Duration, MidiMessage)], path: Q, format: WavFormat) -> Result<(), PianoError>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let samples = self.render(sfont, events)?;
+        Ok(write_wav(path.as_ref(), &samples, self.sample_rate, format)?)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use wmidi::{Channel, Note, U7};
+    use crate::compressor::CompConfig;
+    use crate::midi::MidiMessage;
+    use crate::test_sfont;
+    use super::OfflineRender;
+
+    fn events() -> Vec<(Duration, MidiMessage)> {
+        vec![
+            (Duration::from_millis(10), MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::MAX)),
+            (Duration::from_millis(30), MidiMessage::NoteOn(Channel::Ch1, Note::E4, U7::from_u8_lossy(64))),
+            (Duration::from_millis(200), MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::MIN)),
+            (Duration::from_millis(250), MidiMessage::NoteOff(Channel::Ch1, Note::E4, U7::MIN)),
+        ]
+    }
+
+    #[test]
+    fn reproducible_output() {
+        let path = test_sfont::temp_path("render.sf2");
+        test_sfont::write(&path, &[(0, 0)]);
+        let renders = [
+            OfflineRender { tail: Duration::from_millis(100), ..Default::default() },
+            OfflineRender { tail: Duration::from_millis(100), effects: true, compressor: Some(CompConfig::default()), ..Default::default() },
+        ];
+        let events = events();
+        let samples: Vec<_> = renders.iter()
+            .map(|render| (render.render(&path, &events).unwrap(), render.render(&path, &events).unwrap()))
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+
+        for (first, second) in &samples {
+            assert_eq!(first.len(), 2 * 44100 * 350 / 1000);
+            assert!(first == second);
+        }
+        assert!(samples[0].0 != samples[1].0);
+    }
+
+    #[test]
+    fn note_is_rendered() {
+        let path = test_sfont::temp_path("render-note.sf2");
+        test_sfont::write(&path, &[(0, 0)]);
+        let samples = OfflineRender::default().render(&path, &events()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let peak = |samples: &[f32]| samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        // Silent before the first note-on, 10 ms
+        assert_eq!(peak(&samples[..2 * 441]), 0.0);
+        assert!(peak(&samples[2 * 441..]) > 0.01);
+    }
+}
@@ -0,0 +1,127 @@
+use std::path::{Path, PathBuf};
+use anyhow::Result;
+use crate::error::PianoError;
+
+
+/// Environment variable with additional SoundFont directories
+pub const SFONT_PATH_ENV: &str = "PIANOTE_SF2_PATH";
+
+/// Return directories searched for SoundFont files, in search order
+///
+/// Directories from `PIANOTE_SF2_PATH` come first, then user and system
+/// directories.
+pub fn sfont_search_paths() -> Vec<PathBuf> {
+    let mut paths = vec![];
+    if let Some(env_paths) = std::env::var_os(SFONT_PATH_ENV) {
+        paths.extend(std::env::split_paths(&env_paths));
+    }
+    if let Some(data_home) = std::env::var_os("XDG_DATA_HOME") {
+        paths.push(PathBuf::from(data_home).join("sounds/sf2"));
+    } else if let Some(home) = std::env::var_os("HOME") {
+        paths.push(PathBuf::from(home).join(".local/share/sounds/sf2"));
+    }
+    paths.push("/usr/local/share/sounds/sf2".into());
+    paths.push("/usr/share/sounds/sf2".into());
+    paths.push("/usr/share/soundfonts".into());
+    paths
+}
+
+/// Resolve a SoundFont file path or name
+///
+/// Existing paths are returned as is. Otherwise, the name is searched in
+/// search paths, with `.sf2` and `.sf3` extensions if not provided.
+pub fn resolve_sfont<P: AsRef<Path>>(name: P) -> Result<PathBuf, PianoError> {
+    let name = name.as_ref();
+    if name.exists() {
+        return Ok(name.into());
+    }
+    // Only search bare names
+    if name.components().count() == 1 {
+        // Append extensions, names may contain dots (e.g. `GeneralUser GS v1.471`)
+        let with_extension = |ext: &str| {
+            let mut path = name.as_os_str().to_owned();
+            path.push(ext);
+            PathBuf::from(path)
+        };
+        let candidates = [name.to_path_buf(), with_extension(".sf2"), with_extension(".sf3")];
+        for dir in sfont_search_paths() {
+            for candidate in &candidates {
+                let path = dir.join(candidate);
+                if path.is_file() {
+                    return Ok(path);
+                }
+            }
+        }
+    }
+    Err(anyhow::anyhow!("SoundFont not found: {}", name.display()).into())
+}
+
+/// Find a General MIDI SoundFont commonly installed on the system
+pub fn find_default_sfont() -> Option<PathBuf> {
+    const NAMES: [&str; 5] = ["default-GM", "default", "FluidR3_GM", "TimGM6mb", "GeneralUser GS"];
+    NAMES.iter().find_map(|name| resolve_sfont(name).ok())
+}
+
+/// Return true if the file is a SoundFont 3 (compressed samples)
+///
+/// The version is read from the `ifil` chunk, which starts the file.
+pub fn is_sf3<P: AsRef<Path>>(path: P) -> bool {
+    use std::io::Read;
+    let mut header = [0u8; 34];
+    let read = std::fs::File::open(path).and_then(|mut file| file.read_exact(&mut header));
+    read.is_ok()
+        && &header[0..4] == b"RIFF"
+        && &header[8..12] == b"sfbk"
+        && &header[24..28] == b"ifil"
+        && u16::from_le_bytes([header[32], header[33]]) == 3
+}
+
+/// Check that a SoundFont file exists and is supported, before loading it
+///
+/// This gives clearer errors than FluidLite for common mistakes.
+pub fn check_sfont_file(path: &Path) -> Result<()> {
+    anyhow::ensure!(path.is_file(), "SoundFont file not found: {}", path.display());
+    if !cfg!(feature = "sf3") && is_sf3(path) {
+        anyhow::bail!("SF3 SoundFonts are not supported, build with the `sf3` feature: {}", path.display());
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::test_sfont;
+    use super::{check_sfont_file, resolve_sfont, SFONT_PATH_ENV};
+
+    #[test]
+    fn missing_file() {
+        let err = check_sfont_file(Path::new("/nonexistent/piano.sf2")).unwrap_err();
+        assert_eq!(err.to_string(), "SoundFont file not found: /nonexistent/piano.sf2");
+    }
+
+    #[test]
+    fn valid_file() {
+        let path = test_sfont::temp_path("check.sf2");
+        test_sfont::write(&path, &[(0, 0)]);
+        let result = check_sfont_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn resolve_from_search_path() {
+        let dir = test_sfont::temp_path("sf2-path");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("GeneralUser GS v1.471.sf2");
+        test_sfont::write(&path, &[(0, 0)]);
+        // Only this test reads the variable
+        std::env::set_var(SFONT_PATH_ENV, &dir);
+        let resolved = resolve_sfont("GeneralUser GS v1.471");
+        let missing = resolve_sfont("GeneralUser GS v1");
+        std::env::remove_var(SFONT_PATH_ENV);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(resolved.unwrap(), path);
+        assert!(missing.is_err());
+    }
+}
@@ -0,0 +1,336 @@
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use crate::export;
+use crate::midi::MidiMessage;
+use crate::stats::NoteStats;
+use crate::error::PianoError;
+
+
+/// Standard MIDI file, with its events merged and timed
+pub struct MidiFile {
+    /// Channel events, sorted by time
+    events: Vec<(Duration, MidiMessage)>,
+    /// Time of the last event, including meta ones
+    duration: Duration,
+    /// Tempo at the start of the file, in microseconds per quarter note
+    initial_tempo: u32,
+    /// Time signature at the start of the file, numerator and denominator
+    time_signature: (u8, u8),
+}
+
+/// Time base of file ticks
+#[derive(Copy, Clone)]
+enum Division {
+    /// Ticks per quarter note, tempo changes apply
+    Metrical(u32),
+    /// Ticks per second, tempo changes are ignored
+    Timecode(u32),
+}
+
+/// Event of a track, timed in ticks
+enum TrackEvent {
+    Message(MidiMessage),
+    /// Tempo change, in microseconds per quarter note
+    Tempo(u32),
+    /// Time signature change, numerator and denominator
+    TimeSignature(u8, u8),
+    EndOfTrack,
+}
+
+/// Built-in demo tune: scales, arpeggios and a final chord, on the first channel
+const DEMO: &[u8] = include_bytes!("demo.mid");
+
+impl MidiFile {
+    /// Default tempo, 120 BPM
+    const DEFAULT_TEMPO: u32 = 500_000;
+
+    /// Return the built-in demo tune
+    ///
+    /// It only uses notes on the first channel, so that it can be played with
+    /// any preset.
+    pub fn demo() -> Self {
+        Self::parse(DEMO).expect("invalid built-in demo tune")
+    }
+
+    /// Read and parse a MIDI file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, PianoError> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)
+            .with_context(|| format!("cannot read MIDI file: {}", path.display()))?;
+        let file = Self::parse_data(&data)
+            .with_context(|| format!("invalid MIDI file: {}", path.display()))?;
+        Ok(file)
+    }
+
+    /// Parse the content of a MIDI file
+    ///
+    /// Tracks are merged, whatever the file format. System exclusive and meta
+    /// events are dropped, except tempo changes which are applied.
+    pub fn parse(data: &[u8]) -> Result<Self, PianoError> {
+        Ok(Self::parse_data(data)?)
+    }
+
+    fn parse_data(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader(data);
+        let (id, mut header) = reader.chunk()?;
+        anyhow::ensure!(id == b"MThd", "missing MThd header");
+        let _format = header.u16()?;
+        let ntracks = header.u16()?;
+        let division = match header.u16()? {
+            0 => anyhow::bail!("null time division"),
+            d if d & 0x8000 != 0 => {
+                // Frame rate is stored negated, as a signed byte
+                let fps = ((d >> 8) as u8).wrapping_neg();
+                let ticks_per_frame = d & 0xff;
+                anyhow::ensure!(matches!(fps, 24 | 25 | 29 | 30), "invalid SMPTE frame rate: {}", fps);
+                anyhow::ensure!(ticks_per_frame != 0, "null ticks per frame");
+                Division::Timecode(fps as u32 * ticks_per_frame as u32)
+            }
+            d => Division::Metrical(d as u32),
+        };
+
+        // Merge tracks by tick, keeping track order for simultaneous events
+        let mut events: Vec<(u64, TrackEvent)> = vec![];
+        let mut ntracks_read = 0;
+        while ntracks_read < ntracks && !reader.0.is_empty() {
+            let (id, track) = reader.chunk()?;
+            // Unknown chunks must be ignored
+            if id != b"MTrk" {
+                continue;
+            }
+            ntracks_read += 1;
+            let track_events = Self::parse_track(track.0)
+                .with_context(|| format!("invalid track {}", ntracks_read))?;
+            events.extend(track_events);
+        }
+        events.sort_by_key(|(tick, _)| *tick);
+
+        let mut timed = vec![];
+        let mut tempo = Self::DEFAULT_TEMPO;
+        let mut time_signature = (4, 4);
+        let mut initial_tempo = Self::DEFAULT_TEMPO;
+        let (mut last_tick, mut last_time) = (0, Duration::ZERO);
+        for (tick, event) in events {
+            let ticks = tick - last_tick;
+            last_time += match division {
+                Division::Metrical(tpqn) => Duration::from_micros(ticks * tempo as u64 / tpqn as u64),
+                Division::Timecode(tps) => Duration::from_micros(ticks * 1_000_000 / tps as u64),
+            };
+            last_tick = tick;
+            match event {
+                TrackEvent::Message(message) => timed.push((last_time, message)),
+                TrackEvent::Tempo(value) => {
+                    if tick == 0 {
+                        initial_tempo = value;
+                    }
+                    tempo = value;
+                }
+                TrackEvent::TimeSignature(numerator, denominator) if tick == 0 => {
+                    time_signature = (numerator, denominator);
+                }
+                TrackEvent::TimeSignature(..) | TrackEvent::EndOfTrack => {}
+            }
+        }
+
+        Ok(Self { events: timed, duration: last_time, initial_tempo, time_signature })
+    }
+
+    fn parse_track(data: &[u8]) -> Result<Vec<(u64, TrackEvent)>> {
+        let mut reader = Reader(data);
+        let mut events = vec![];
+        let mut tick = 0u64;
+        let mut running_status = None;
+        while !reader.0.is_empty() {
+            tick += reader.varlen()? as u64;
+            let status = match reader.peek()? {
+                status if status & 0x80 != 0 => {
+                    reader.u8()?;
+                    status
+                }
+                _ => running_status.context("data byte without running status")?,
+            };
+            match status {
+                // Meta and SysEx events cancel running status
+                0xff => {
+                    running_status = None;
+                    let kind = reader.u8()?;
+                    let len = reader.varlen()?;
+                    let data = reader.bytes(len as usize)?;
+                    match kind {
+                        0x51 if data.len() == 3 => {
+                            let tempo = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+                            anyhow::ensure!(tempo > 0, "invalid tempo: 0");
+                            events.push((tick, TrackEvent::Tempo(tempo)));
+                        }
+                        // Denominator is stored as a power of 2
+                        0x58 if data.len() == 4 && data[0] > 0 && data[1] < 8 => {
+                            events.push((tick, TrackEvent::TimeSignature(data[0], 1 << data[1])));
+                        }
+                        0x2f => {
+                            events.push((tick, TrackEvent::EndOfTrack));
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+                0xf0 | 0xf7 => {
+                    let len = reader.varlen()?;
+                    reader.bytes(len as usize)?;
+                    running_status = None;
+                }
+                0x80..=0xef => {
+                    running_status = Some(status);
+                    let len = if matches!(status & 0xf0, 0xc0 | 0xd0) { 1 } else { 2 };
+                    let mut bytes = [status, 0, 0];
+                    bytes[1..=len].copy_from_slice(reader.bytes(len)?);
+                    let message = wmidi::MidiMessage::try_from(&bytes[..=len])
+                        .map_err(|err| anyhow::anyhow!("invalid MIDI message: {:?}", err))?;
+                    if let Some(message) = message.drop_unowned_sysex() {
+                        events.push((tick, TrackEvent::Message(message)));
+                    }
+                }
+                _ => anyhow::bail!("unexpected status byte: {:#x}", status),
+            }
+        }
+        Ok(events)
+    }
+
+    /// Return channel events, sorted by time
+    pub fn events(&self) -> &[(Duration, MidiMessage)] {
+        &self.events
+    }
+
+    /// Return the time of the end of the file
+    pub fn duration(&self) -> Duration {
+        self.duration
+    }
+
+    /// Return statistics of the notes of the file
+    pub fn stats(&self) -> NoteStats {
+        NoteStats::from_events(&self.events)
+    }
+
+    /// Write notes as CSV: start, duration, note, velocity and channel
+    pub fn export_csv<P: AsRef<Path>>(&self, path: P) -> Result<(), PianoError> {
+        Ok(Self::export(path.as_ref(), |out| export::write_csv(out, &self.events))?)
+    }
+
+    /// Write notes as a MusicXML score, quantized to sixteenth notes
+    ///
+    /// Quantization uses the initial tempo and time signature of the file.
+    pub fn export_musicxml<P: AsRef<Path>>(&self, path: P) -> Result<(), PianoError> {
+        let quarter = Duration::from_micros(self.initial_tempo as u64);
+        Ok(Self::export(path.as_ref(), |out| export::write_musicxml(out, &self.events, quarter, self.time_signature))?)
+    }
+
+    fn export<F>(path: &Path, write: F) -> Result<()>
+    where
+        F: FnOnce(&mut std::io::BufWriter<std::fs::File>) -> Result<()>,
+    {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("cannot create {}", path.display()))?;
+        let mut out = std::io::BufWriter::new(file);
+        write(&mut out)
+            .and_then(|()| Ok(out.flush()?))
+            .with_context(|| format!("cannot write {}", path.display()))
+    }
+
+    /// Return the number of beats per bar, at the start of the file
+    pub fn beats_per_bar(&self) -> u8 {
+        self.time_signature.0
+    }
+
+    /// Return the duration of a beat, at the start of the file
+    pub fn beat_duration(&self) -> Duration {
+        Duration::from_micros(self.initial_tempo as u64 * 4 / self.time_signature.1 as u64)
+    }
+}
+
+
+/// Read big-endian values from MIDI file data
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        anyhow::ensure!(self.0.len() >= len, "unexpected end of data");
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn peek(&self) -> Result<u8> {
+        self.0.first().copied().context("unexpected end of data")
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Read a variable-length quantity, at most 4 bytes
+    fn varlen(&mut self) -> Result<u32> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        anyhow::bail!("variable-length quantity too long")
+    }
+
+    /// Read a chunk, return its type and content
+    fn chunk(&mut self) -> Result<(&'a [u8], Reader<'a>)> {
+        let id = self.bytes(4)?;
+        let len = self.u32()?;
+        let data = self.bytes(len as usize).context("truncated chunk")?;
+        Ok((id, Reader(data)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::MidiFile;
+
+    /// Build a file with a single empty track, from its time division
+    fn file_with_division(division: u16) -> Vec<u8> {
+        let mut data = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01".to_vec();
+        data.extend(division.to_be_bytes());
+        data.extend(b"MTrk\x00\x00\x00\x04\x00\xff\x2f\x00");
+        data
+    }
+
+    #[test]
+    fn zero_tempo() {
+        let mut data = file_with_division(96);
+        data.truncate(data.len() - 12);
+        data.extend(b"MTrk\x00\x00\x00\x0b\x00\xff\x51\x03\x00\x00\x00\x00\xff\x2f\x00");
+        assert!(MidiFile::parse(&data).is_err());
+    }
+
+    #[test]
+    fn timecode_division() {
+        // 25 fps, 40 ticks per frame
+        assert!(MidiFile::parse(&file_with_division(0xe728)).is_ok());
+    }
+
+    #[test]
+    fn invalid_timecode_division() {
+        for division in [0x8000, 0x8028, 0xe700, 0xe600] {
+            assert!(MidiFile::parse(&file_with_division(division)).is_err(), "division {:#x}", division);
+        }
+    }
+}
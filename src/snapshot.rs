@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use crate::piano::Preset;
+
+
+/// State of a piano, to recall a complete setup
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    /// Path of the loaded SoundFont, if any
+    pub sfont: Option<PathBuf>,
+    pub gain: f32,
+    pub reverb: bool,
+    pub chorus: bool,
+    /// State of each channel, in order
+    pub channels: Vec<ChannelSnapshot>,
+}
+
+/// State of a single channel
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChannelSnapshot {
+    /// Preset selected on the output channel
+    pub preset: Option<Preset>,
+    /// Transposition of the input channel, in semitones
+    pub transpose: i8,
+    /// Controller numbers and values, as received by the synth
+    pub controllers: Vec<(u8, u8)>,
+}
+
+impl StateSnapshot {
+    /// Serialize the snapshot to a JSON object
+    ///
+    /// Fail if the SoundFont path is not valid UTF-8.
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    /// Parse a snapshot serialized by `to_json()`
+    pub fn from_json(s: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(s)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_round_trip() {
+        let snapshot = StateSnapshot {
+            sfont: Some(PathBuf::from("fonts/\"grand\"\\piano.sf2")),
+            gain: 1.0,
+            reverb: true,
+            chorus: false,
+            channels: vec![
+                ChannelSnapshot { preset: Some(Preset { bank: 128, num: 0 }), transpose: -12, controllers: vec![(7, 100), (10, 64)] },
+                ChannelSnapshot::default(),
+            ],
+        };
+        let json = snapshot.to_json().unwrap();
+        assert!(json.contains("\"gain\":1.0"));
+        assert_eq!(StateSnapshot::from_json(&json).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn invalid_json() {
+        assert!(StateSnapshot::from_json("{\"gain\":1.0}").is_err());
+        assert!(StateSnapshot::from_json("not json").is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use std::time::Duration;
+use crate::midi::MidiMessage;
+
+
+/// Summary of played notes
+#[derive(PartialEq, Clone, Debug)]
+pub struct NoteStats {
+    /// Number of played notes
+    pub note_count: usize,
+    /// Lowest and highest played notes
+    pub note_range: Option<(wmidi::Note, wmidi::Note)>,
+    /// Average note-on velocity
+    pub average_velocity: Option<f32>,
+    /// Time between the first and the last note events
+    pub duration: Duration,
+    /// Estimated tempo, in beats per minute
+    pub tempo: Option<f32>,
+}
+
+impl NoteStats {
+    /// Notes starting within this delay are played together, as a chord
+    const CHORD_DELAY: Duration = Duration::from_millis(50);
+    /// Range in which the estimated tempo is folded
+    const TEMPO_RANGE: (f32, f32) = (60.0, 180.0);
+
+    /// Compute statistics of timed events, sorted by time
+    pub fn from_events(events: &[(Duration, MidiMessage)]) -> Self {
+        let mut note_count = 0;
+        let mut note_range: Option<(wmidi::Note, wmidi::Note)> = None;
+        let mut velocity_sum = 0u32;
+        let mut onsets = vec![];
+        let mut first_time = None;
+        let mut last_time = Duration::ZERO;
+
+        for (time, message) in events {
+            match *message {
+                MidiMessage::NoteOn(_, note, velocity) if velocity != wmidi::U7::MIN => {
+                    note_count += 1;
+                    velocity_sum += u8::from(velocity) as u32;
+                    note_range = Some(match note_range {
+                        Some((low, high)) => (low.min(note), high.max(note)),
+                        None => (note, note),
+                    });
+                    if onsets.last().is_none_or(|onset| *time >= *onset + Self::CHORD_DELAY) {
+                        onsets.push(*time);
+                    }
+                }
+                MidiMessage::NoteOn(..) | MidiMessage::NoteOff(..) => {}
+                _ => continue,
+            }
+            first_time.get_or_insert(*time);
+            last_time = *time;
+        }
+
+        Self {
+            note_count,
+            note_range,
+            average_velocity: (note_count > 0).then(|| velocity_sum as f32 / note_count as f32),
+            duration: last_time.saturating_sub(first_time.unwrap_or_default()),
+            tempo: Self::estimate_tempo(&onsets),
+        }
+    }
+
+    /// Estimate tempo from note onsets
+    ///
+    /// The median interval between onsets is assumed to be a beat, or a simple
+    /// fraction or multiple of it, and is folded into `TEMPO_RANGE`.
+    fn estimate_tempo(onsets: &[Duration]) -> Option<f32> {
+        let mut intervals: Vec<f32> = onsets.windows(2)
+            .map(|pair| (pair[1] - pair[0]).as_secs_f32())
+            .collect();
+        if intervals.len() < 2 {
+            return None;
+        }
+        intervals.sort_by(f32::total_cmp);
+        let mut tempo = 60.0 / intervals[intervals.len() / 2];
+        while tempo < Self::TEMPO_RANGE.0 {
+            tempo *= 2.0;
+        }
+        while tempo >= Self::TEMPO_RANGE.1 {
+            tempo /= 2.0;
+        }
+        Some(tempo)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use wmidi::{Channel, Note, U7};
+    use super::*;
+
+    fn note_on(ms: u64, note: Note, velocity: u8) -> (Duration, MidiMessage) {
+        (Duration::from_millis(ms), MidiMessage::NoteOn(Channel::Ch1, note, U7::from_u8_lossy(velocity)))
+    }
+
+    fn note_off(ms: u64, note: Note) -> (Duration, MidiMessage) {
+        (Duration::from_millis(ms), MidiMessage::NoteOff(Channel::Ch1, note, U7::MIN))
+    }
+
+    #[test]
+    fn no_notes() {
+        let events = [(Duration::from_millis(100), MidiMessage::ProgramChange(Channel::Ch1, U7::MIN))];
+        assert_eq!(NoteStats::from_events(&events), NoteStats {
+            note_count: 0,
+            note_range: None,
+            average_velocity: None,
+            duration: Duration::ZERO,
+            tempo: None,
+        });
+    }
+
+    #[test]
+    fn count_range_velocity() {
+        let events = [
+            (Duration::ZERO, MidiMessage::ControlChange(Channel::Ch1, wmidi::ControlFunction::DAMPER_PEDAL, U7::MAX)),
+            note_on(100, Note::C4, 40),
+            note_on(200, Note::A0, 80),
+            note_off(300, Note::C4),
+            note_on(400, Note::C8, 120),
+            note_on(500, Note::E4, 0),
+            note_off(600, Note::A0),
+        ];
+        let stats = NoteStats::from_events(&events);
+        assert_eq!(stats.note_count, 3);
+        assert_eq!(stats.note_range, Some((Note::A0, Note::C8)));
+        assert_eq!(stats.average_velocity, Some(80.0));
+        assert_eq!(stats.duration, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn tempo() {
+        // Half-second beats, with chords and an off-beat note
+        let events = [
+            note_on(0, Note::C4, 64),
+            note_on(20, Note::E4, 64),
+            note_on(500, Note::C4, 64),
+            note_on(1000, Note::C4, 64),
+            note_on(1030, Note::G4, 64),
+            note_on(1250, Note::C4, 64),
+            note_on(1500, Note::C4, 64),
+            note_on(2000, Note::C4, 64),
+        ];
+        assert_eq!(NoteStats::from_events(&events).tempo, Some(120.0));
+    }
+
+    #[test]
+    fn tempo_folded() {
+        let beats = |interval: u64| (0..5).map(|i| note_on(i * interval, Note::C4, 64)).collect::<Vec<_>>();
+        // 30 BPM is doubled to 60, 240 BPM is halved to 120
+        assert_eq!(NoteStats::from_events(&beats(2000)).tempo, Some(60.0));
+        assert_eq!(NoteStats::from_events(&beats(250)).tempo, Some(120.0));
+        assert_eq!(NoteStats::from_events(&beats(400)).tempo, Some(150.0));
+        // Too few onsets
+        assert_eq!(NoteStats::from_events(&beats(500)[..2]).tempo, None);
+    }
+}
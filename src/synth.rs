@@ -1,5 +1,7 @@
 use anyhow::Result;
+use fluidlite::{Bank, Chan, Ctrl, FontId, Key, PresetId, Prog, Status, Val, Vel};
 use crate::midi::MidiMessage;
+use crate::error::PianoError;
 
 
 /// Synthetizer, using SoundFont data and processing MIDI commands
@@ -8,42 +10,515 @@ use crate::midi::MidiMessage;
 /// Additional features should be implemented on the `Piano`.
 pub struct Synth {
     pub synth: fluidlite::Synth,
+    /// Whether zero-velocity note-ons are sent as note-offs
+    zero_velocity_note_off: bool,
+}
+
+/// Information on the synth backend, FluidLite
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct BackendInfo {
+    /// FluidLite version
+    pub version: String,
+    /// Whether SF3 fonts can be loaded
+    pub sf3: bool,
+}
+
+impl std::fmt::Display for BackendInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "FluidLite {}, SF3: {}", self.version, if self.sf3 { "yes" } else { "no" })
+    }
+}
+
+/// Value of a synth setting
+#[derive(PartialEq, Clone, Debug)]
+pub enum SettingValue {
+    Num(f64),
+    Int(i32),
+    Str(String),
 }
 
 impl Synth {
-    pub fn new(sample_rate: f64) -> Result<Self> {
+    /// Default gain, FluidSynth default for "synth.gain" is too low
+    pub const DEFAULT_GAIN: f32 = 1.5;
+
+    /// Default polyphony, FluidSynth default, enough for most piano playing
+    pub const DEFAULT_POLYPHONY: u32 = 256;
+    const MIN_POLYPHONY: u32 = 16;
+    const MAX_POLYPHONY: u32 = 4096;
+
+    /// Create a synth, `effects` enables reverb and chorus
+    ///
+    /// Disabling effects at creation saves the CPU they would use, even silent.
+    pub fn new(sample_rate: f64, gain: f32, effects: bool) -> Result<Self, PianoError> {
         use fluidlite::IsSettings;
 
         let settings = fluidlite::Settings::new()?;
         settings.num("synth.sample-rate")
             .expect("synth.sample-rate setting not available")
             .set(sample_rate);
+        if !effects {
+            for key in ["synth.reverb.active", "synth.chorus.active"] {
+                settings.str_(key)
+                    .unwrap_or_else(|| panic!("{} setting not available", key))
+                    .set("no");
+            }
+        }
 
         let synth = fluidlite::Synth::new(settings)?;
-        synth.set_gain(1.5);  //XXX Arbitrary value
-        Ok(Self { synth })
-    }
-
-    pub fn send_midi_message(&self, message: MidiMessage) -> Result<()> {
-        use fluidlite::{Chan, Ctrl, Key, Prog, Val, Vel};
-        match message {
-            MidiMessage::NoteOff(chan, key, _) => self.synth.note_off(chan as Chan, key as Key),
-            MidiMessage::NoteOn(chan, key, vel) => self.synth.note_on(chan as Chan, key as Key, u8::from(vel) as Vel),
-            MidiMessage::PolyphonicKeyPressure(chan, key, vel) => self.synth.key_pressure(chan as Chan, key as Key, u8::from(vel) as Vel),
-            MidiMessage::ControlChange(chan, ctrl, val) => self.synth.cc(chan as Chan, u8::from(ctrl) as Ctrl, u8::from(val) as Val),
-            MidiMessage::ProgramChange(chan, prog) => self.synth.program_change(chan as Chan, u8::from(prog) as Prog),
-            MidiMessage::ChannelPressure(chan, vel) => self.synth.channel_pressure(chan as Chan, u8::from(vel) as Vel),
-            MidiMessage::PitchBendChange(chan, val) => self.synth.pitch_bend(chan as Chan, u16::from(val) as Val),
-            MidiMessage::Reset => self.synth.system_reset(),
-            _ => Ok(()),
-        }?;
+        synth.set_gain(gain);
+        Ok(Self { synth, zero_velocity_note_off: true })
+    }
+
+    /// Return information on the synth backend
+    pub fn backend_info() -> BackendInfo {
+        BackendInfo {
+            version: fluidlite::Version::get().to_string(),
+            sf3: cfg!(feature = "sf3"),
+        }
+    }
+
+    /// Change a FluidSynth setting
+    ///
+    /// Integer values are also accepted for numeric settings.
+    /// Some settings are only read on synth creation and have no effect when changed.
+    pub fn set_setting(&self, key: &str, value: &SettingValue) -> Result<(), PianoError> {
+        use fluidlite::IsSettings;
+
+        let settings = self.synth.get_settings();
+        let updated = match value {
+            SettingValue::Int(v) => settings.int(key).map(|setting| setting.set(*v))
+                .or_else(|| settings.num(key).map(|setting| setting.set(*v as f64))),
+            SettingValue::Num(v) => settings.num(key).map(|setting| setting.set(*v)),
+            SettingValue::Str(v) => settings.str_(key).map(|setting| setting.set(v.as_str())),
+        };
+        let err = match updated {
+            Some(true) => return Ok(()),
+            Some(false) => anyhow::anyhow!("invalid value for setting {}: {:?}", key, value),
+            None if settings.pick::<_, ()>(key).is_some() => anyhow::anyhow!("cannot change setting {}", key),
+            None if settings.int(key).is_some() || settings.num(key).is_some() || settings.str_(key).is_some() => {
+                anyhow::anyhow!("invalid value type for setting {}: {:?}", key, value)
+            }
+            None => anyhow::anyhow!("unknown setting: {}", key),
+        };
+        Err(err.into())
+    }
+
+    pub fn send_midi_message(&self, message: MidiMessage) -> Result<(), PianoError> {
+        send_message(&self.synth, message, self.zero_velocity_note_off)?;
+        Ok(())
+    }
+
+    /// Send zero-velocity note-ons as note-offs (default), or unchanged
+    ///
+    /// Running status often encodes note-offs this way. Unchanged messages
+    /// are handled by FluidLite itself.
+    pub fn set_zero_velocity_note_off(&mut self, enabled: bool) {
+        self.zero_velocity_note_off = enabled;
+    }
+
+    /// Change the maximum number of voices played at once (16 to 4096)
+    ///
+    /// When the limit is reached, FluidLite stops a voice to play the new one.
+    /// Its priorities are fixed: voices on the drum channel, and voices of
+    /// held keys, are kept first; sustained, oldest and quietest voices are
+    /// stopped first. Stopping a voice can be heard as a click, a larger
+    /// polyphony avoids it in dense passages, at the cost of CPU.
+    pub fn set_polyphony(&self, polyphony: u32) -> Result<(), PianoError> {
+        if !(Self::MIN_POLYPHONY..=Self::MAX_POLYPHONY).contains(&polyphony) {
+            return Err(anyhow::anyhow!("polyphony out of range ({}-{}): {}", Self::MIN_POLYPHONY, Self::MAX_POLYPHONY, polyphony).into());
+        }
+        self.synth.set_polyphony(polyphony)?;
+        Ok(())
+    }
+
+    /// Change the pitch change of a full pitch bend, in semitones, on all channels
+    ///
+    /// The MIDI default is 2 semitones. Channels are reset to it on system reset.
+    pub fn set_pitch_bend_range(&self, semitones: u8) -> Result<(), PianoError> {
+        set_pitch_bend_range(&self.synth, semitones)?;
+        Ok(())
+    }
+
+    /// Stop all voices immediately, on all channels
+    pub fn all_sounds_off(&self) -> Result<(), PianoError> {
+        const ALL_SOUND_OFF: u32 = 0x78;
+        for chan in 0..self.synth.count_midi_channels() {
+            self.synth.cc(chan, ALL_SOUND_OFF, 0)?;
+        }
         Ok(())
     }
 
     /// Consume and write the next samples
-    pub fn write_samples(&self, samples: &mut [f32]) -> Result<()> {
+    pub fn write_samples(&self, samples: &mut [f32]) -> Result<(), PianoError> {
         self.synth.write(samples)?;
         Ok(())
     }
+
+    /// Render the given number of frames, return interleaved stereo samples
+    ///
+    /// This allocates a new buffer, use `write_samples()` for real-time output.
+    pub fn render(&self, frames: usize) -> Result<Vec<f32>, PianoError> {
+        let mut samples = vec![0.0; frames * 2];
+        self.write_samples(&mut samples)?;
+        Ok(samples)
+    }
+}
+
+
+/// MIDI calls of the synth backend
+///
+/// Implemented by FluidLite, and by a mock in tests, to check conversions.
+pub(crate) trait SynthBackend {
+    fn note_on(&self, chan: Chan, key: Key, vel: Vel) -> Status;
+    fn note_off(&self, chan: Chan, key: Key) -> Status;
+    fn key_pressure(&self, chan: Chan, key: Key, val: Val) -> Status;
+    fn cc(&self, chan: Chan, ctrl: Ctrl, val: Val) -> Status;
+    fn program_change(&self, chan: Chan, prog: Prog) -> Status;
+    fn channel_pressure(&self, chan: Chan, val: Val) -> Status;
+    fn pitch_bend(&self, chan: Chan, val: Val) -> Status;
+    fn pitch_wheel_sens(&self, chan: Chan, val: Val) -> Status;
+    fn program_select(&self, chan: Chan, sfont_id: FontId, bank: Bank, preset: PresetId) -> Status;
+    fn bank_select(&self, chan: Chan, bank: Bank) -> Status;
+    fn count_midi_channels(&self) -> Chan;
+    fn system_reset(&self) -> Status;
+}
+
+impl SynthBackend for fluidlite::Synth {
+    fn note_on(&self, chan: Chan, key: Key, vel: Vel) -> Status {
+        fluidlite::Synth::note_on(self, chan, key, vel)
+    }
+
+    fn note_off(&self, chan: Chan, key: Key) -> Status {
+        fluidlite::Synth::note_off(self, chan, key)
+    }
+
+    fn key_pressure(&self, chan: Chan, key: Key, val: Val) -> Status {
+        fluidlite::Synth::key_pressure(self, chan, key, val)
+    }
+
+    fn cc(&self, chan: Chan, ctrl: Ctrl, val: Val) -> Status {
+        fluidlite::Synth::cc(self, chan, ctrl, val)
+    }
+
+    fn program_change(&self, chan: Chan, prog: Prog) -> Status {
+        fluidlite::Synth::program_change(self, chan, prog)
+    }
+
+    fn channel_pressure(&self, chan: Chan, val: Val) -> Status {
+        fluidlite::Synth::channel_pressure(self, chan, val)
+    }
+
+    fn pitch_bend(&self, chan: Chan, val: Val) -> Status {
+        fluidlite::Synth::pitch_bend(self, chan, val)
+    }
+
+    fn pitch_wheel_sens(&self, chan: Chan, val: Val) -> Status {
+        fluidlite::Synth::pitch_wheel_sens(self, chan, val)
+    }
+
+    fn program_select(&self, chan: Chan, sfont_id: FontId, bank: Bank, preset: PresetId) -> Status {
+        fluidlite::Synth::program_select(self, chan, sfont_id, bank, preset)
+    }
+
+    fn bank_select(&self, chan: Chan, bank: Bank) -> Status {
+        fluidlite::Synth::bank_select(self, chan, bank)
+    }
+
+    fn count_midi_channels(&self) -> Chan {
+        fluidlite::Synth::count_midi_channels(self)
+    }
+
+    fn system_reset(&self) -> Status {
+        fluidlite::Synth::system_reset(self)
+    }
+}
+
+/// Change the pitch bend range of all channels of a synth backend
+fn set_pitch_bend_range<B: SynthBackend>(backend: &B, semitones: u8) -> Status {
+    for chan in 0..backend.count_midi_channels() {
+        backend.pitch_wheel_sens(chan, semitones as Val)?;
+    }
+    Ok(())
+}
+
+/// Send a MIDI message to a synth backend
+pub(crate) fn send_message<B: SynthBackend>(backend: &B, message: MidiMessage, zero_velocity_note_off: bool) -> Status {
+    match message {
+        // Release velocity is not supported by fluidlite, it can be routed to a controller
+        MidiMessage::NoteOff(chan, key, _) => backend.note_off(chan.index() as Chan, u8::from(key) as Key),
+        MidiMessage::NoteOn(chan, key, vel) if u8::from(vel) == 0 && zero_velocity_note_off => {
+            backend.note_off(chan.index() as Chan, u8::from(key) as Key)
+        }
+        MidiMessage::NoteOn(chan, key, vel) => backend.note_on(chan.index() as Chan, u8::from(key) as Key, u8::from(vel) as Vel),
+        MidiMessage::PolyphonicKeyPressure(chan, key, vel) => backend.key_pressure(chan.index() as Chan, u8::from(key) as Key, u8::from(vel) as Val),
+        MidiMessage::ControlChange(chan, ctrl, val) => backend.cc(chan.index() as Chan, u8::from(ctrl) as Ctrl, u8::from(val) as Val),
+        MidiMessage::ProgramChange(chan, prog) => backend.program_change(chan.index() as Chan, u8::from(prog) as Prog),
+        MidiMessage::ChannelPressure(chan, vel) => backend.channel_pressure(chan.index() as Chan, u8::from(vel) as Val),
+        // Both use the 0-16383 range, centered on 8192: a full bend changes pitch by the pitch bend range
+        MidiMessage::PitchBendChange(chan, val) => backend.pitch_bend(chan.index() as Chan, u16::from(val) as Val),
+        MidiMessage::Reset => backend.system_reset(),
+        _ => Ok(()),
+    }
+}
+
+impl std::str::FromStr for SettingValue {
+    type Err = std::convert::Infallible;
+
+    /// Parse a value, guessing its type
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = if let Ok(v) = s.parse() {
+            Self::Int(v)
+        } else if let Ok(v) = s.parse() {
+            Self::Num(v)
+        } else {
+            Self::Str(s.into())
+        };
+        Ok(value)
+    }
+}
+
+
+/// Backend recording calls, for tests
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::cell::RefCell;
+    use fluidlite::{Bank, Chan, Ctrl, FontId, Key, PresetId, Prog, Status, Val, Vel};
+    use super::SynthBackend;
+
+    #[derive(PartialEq, Eq, Copy, Clone, Debug)]
+    pub enum Call {
+        NoteOn(Chan, Key, Vel),
+        NoteOff(Chan, Key),
+        KeyPressure(Chan, Key, Val),
+        Cc(Chan, Ctrl, Val),
+        ProgramChange(Chan, Prog),
+        ChannelPressure(Chan, Val),
+        PitchBend(Chan, Val),
+        PitchWheelSens(Chan, Val),
+        ProgramSelect(Chan, FontId, Bank, PresetId),
+        BankSelect(Chan, Bank),
+        SystemReset,
+    }
+
+    #[derive(Default)]
+    pub struct MockBackend {
+        pub calls: RefCell<Vec<Call>>,
+        /// Presets `program_select()` fails on, as bank and number
+        pub missing_presets: Vec<(Bank, PresetId)>,
+    }
+
+    impl MockBackend {
+        pub const CHANNELS: Chan = 16;
+
+        /// Return the pitch change of a channel, in semitones, computed as FluidLite does
+        ///
+        /// Bend and range are the last ones sent, MIDI defaults otherwise.
+        pub fn pitch_offset(&self, chan: Chan) -> f32 {
+            let calls = self.calls.borrow();
+            let bend = calls.iter().rev()
+                .find_map(|call| match *call { Call::PitchBend(c, v) if c == chan => Some(v), _ => None })
+                .unwrap_or(8192);
+            let range = calls.iter().rev()
+                .find_map(|call| match *call { Call::PitchWheelSens(c, v) if c == chan => Some(v), _ => None })
+                .unwrap_or(2);
+            (bend as f32 - 8192.0) / 8192.0 * range as f32
+        }
+
+        /// Return recorded calls, and forget them
+        pub fn take_calls(&self) -> Vec<Call> {
+            self.calls.take()
+        }
+
+        fn record(&self, call: Call) -> Status {
+            self.calls.borrow_mut().push(call);
+            Ok(())
+        }
+    }
+
+    impl SynthBackend for MockBackend {
+        fn note_on(&self, chan: Chan, key: Key, vel: Vel) -> Status {
+            self.record(Call::NoteOn(chan, key, vel))
+        }
+
+        fn note_off(&self, chan: Chan, key: Key) -> Status {
+            self.record(Call::NoteOff(chan, key))
+        }
+
+        fn key_pressure(&self, chan: Chan, key: Key, val: Val) -> Status {
+            self.record(Call::KeyPressure(chan, key, val))
+        }
+
+        fn cc(&self, chan: Chan, ctrl: Ctrl, val: Val) -> Status {
+            self.record(Call::Cc(chan, ctrl, val))
+        }
+
+        fn program_change(&self, chan: Chan, prog: Prog) -> Status {
+            self.record(Call::ProgramChange(chan, prog))
+        }
+
+        fn channel_pressure(&self, chan: Chan, val: Val) -> Status {
+            self.record(Call::ChannelPressure(chan, val))
+        }
+
+        fn pitch_bend(&self, chan: Chan, val: Val) -> Status {
+            self.record(Call::PitchBend(chan, val))
+        }
+
+        fn pitch_wheel_sens(&self, chan: Chan, val: Val) -> Status {
+            self.record(Call::PitchWheelSens(chan, val))
+        }
+
+        fn program_select(&self, chan: Chan, sfont_id: FontId, bank: Bank, preset: PresetId) -> Status {
+            self.record(Call::ProgramSelect(chan, sfont_id, bank, preset))?;
+            if self.missing_presets.contains(&(bank, preset)) {
+                return Err(fluidlite::Error::Fluid("no such preset".into()));
+            }
+            Ok(())
+        }
+
+        fn bank_select(&self, chan: Chan, bank: Bank) -> Status {
+            self.record(Call::BankSelect(chan, bank))
+        }
+
+        fn count_midi_channels(&self) -> Chan {
+            Self::CHANNELS
+        }
+
+        fn system_reset(&self) -> Status {
+            self.record(Call::SystemReset)
+        }
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use wmidi::{Channel, ControlFunction, Note, U7, U14};
+    use crate::midi::MidiMessage;
+    use crate::test_sfont;
+    use super::mock::{Call, MockBackend};
+    use super::{send_message, set_pitch_bend_range, Synth};
+
+    fn calls(message: MidiMessage) -> Vec<Call> {
+        let backend = MockBackend::default();
+        send_message(&backend, message, true).unwrap();
+        backend.take_calls()
+    }
+
+    fn u7(value: u8) -> U7 {
+        U7::try_from(value).unwrap()
+    }
+
+    #[test]
+    fn note_on() {
+        assert_eq!(calls(MidiMessage::NoteOn(Channel::Ch3, Note::C4, u7(100))), [Call::NoteOn(2, 60, 100)]);
+        assert_eq!(calls(MidiMessage::NoteOn(Channel::Ch16, Note::G9, U7::MAX)), [Call::NoteOn(15, 127, 127)]);
+    }
+
+    #[test]
+    fn zero_velocity_note_on() {
+        // The note is stopped, as with a note-off
+        let backend = MockBackend::default();
+        send_message(&backend, MidiMessage::NoteOn(Channel::Ch1, Note::C4, u7(100)), true).unwrap();
+        send_message(&backend, MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::MIN), true).unwrap();
+        assert_eq!(backend.take_calls(), [Call::NoteOn(0, 60, 100), Call::NoteOff(0, 60)]);
+
+        send_message(&backend, MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::MIN), false).unwrap();
+        assert_eq!(backend.take_calls(), [Call::NoteOn(0, 60, 0)]);
+    }
+
+    #[test]
+    fn note_off() {
+        assert_eq!(calls(MidiMessage::NoteOff(Channel::Ch1, Note::A4, u7(64))), [Call::NoteOff(0, 69)]);
+    }
+
+    #[test]
+    fn polyphonic_key_pressure() {
+        assert_eq!(calls(MidiMessage::PolyphonicKeyPressure(Channel::Ch2, Note::E3, u7(90))), [Call::KeyPressure(1, 52, 90)]);
+    }
+
+    #[test]
+    fn control_change() {
+        let message = MidiMessage::ControlChange(Channel::Ch10, ControlFunction::MODULATION_WHEEL, u7(33));
+        assert_eq!(calls(message), [Call::Cc(9, 1, 33)]);
+        let message = MidiMessage::ControlChange(Channel::Ch1, ControlFunction::ALL_NOTES_OFF, U7::MIN);
+        assert_eq!(calls(message), [Call::Cc(0, 123, 0)]);
+    }
+
+    #[test]
+    fn program_change() {
+        assert_eq!(calls(MidiMessage::ProgramChange(Channel::Ch5, u7(42))), [Call::ProgramChange(4, 42)]);
+    }
+
+    #[test]
+    fn channel_pressure() {
+        assert_eq!(calls(MidiMessage::ChannelPressure(Channel::Ch4, u7(127))), [Call::ChannelPressure(3, 127)]);
+    }
+
+    #[test]
+    fn pitch_bend() {
+        // FluidLite uses the MIDI range, centered on 8192
+        let bend = |value: u16| calls(MidiMessage::PitchBendChange(Channel::Ch1, U14::try_from(value).unwrap()));
+        assert_eq!(bend(0), [Call::PitchBend(0, 0)]);
+        assert_eq!(bend(8192), [Call::PitchBend(0, 8192)]);
+        assert_eq!(bend(16383), [Call::PitchBend(0, 16383)]);
+    }
+
+    #[test]
+    fn pitch_bend_range() {
+        let backend = MockBackend::default();
+        set_pitch_bend_range(&backend, 12).unwrap();
+        let expected: Vec<_> = (0..MockBackend::CHANNELS).map(|chan| Call::PitchWheelSens(chan, 12)).collect();
+        assert_eq!(*backend.calls.borrow(), expected);
+
+        // A centered bend is no change, full bends change pitch by the range
+        let bend = |value: u16| {
+            send_message(&backend, MidiMessage::PitchBendChange(Channel::Ch1, U14::try_from(value).unwrap()), true).unwrap();
+            backend.pitch_offset(0)
+        };
+        assert_eq!(bend(8192), 0.0);
+        assert_eq!(bend(0), -12.0);
+        assert!((bend(16383) - 12.0).abs() < 0.01);
+        assert_eq!(backend.pitch_offset(1), 0.0);
+    }
+
+    #[test]
+    fn reset() {
+        assert_eq!(calls(MidiMessage::Reset), [Call::SystemReset]);
+    }
+
+    #[test]
+    fn ignored_messages() {
+        let messages = [
+            MidiMessage::SysEx(U7::try_from_bytes(&[0x7e, 0x7f, 0x09, 0x01]).unwrap()),
+            MidiMessage::MidiTimeCode(u7(0)),
+            MidiMessage::SongPositionPointer(U14::MIN),
+            MidiMessage::SongSelect(u7(1)),
+            MidiMessage::TuneRequest,
+            MidiMessage::TimingClock,
+            MidiMessage::Start,
+            MidiMessage::Continue,
+            MidiMessage::Stop,
+            MidiMessage::ActiveSensing,
+        ];
+        for message in messages {
+            assert_eq!(calls(message.clone()), [], "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn render_note() {
+        let path = test_sfont::temp_path("synth.sf2");
+        test_sfont::write(&path, &[(0, 0)]);
+        let synth = Synth::new(44100.0, 0.2, false).unwrap();
+        let loaded = synth.synth.sfload(&path, true);
+        std::fs::remove_file(&path).unwrap();
+        loaded.unwrap();
+
+        let peak = |samples: &[f32]| samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let samples = synth.render(512).unwrap();
+        assert_eq!(samples.len(), 1024);
+        assert_eq!(peak(&samples), 0.0);
+        synth.send_midi_message(MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::MAX)).unwrap();
+        assert!(peak(&synth.render(2048).unwrap()) > 0.01);
+    }
+}
@@ -0,0 +1,114 @@
+//! Minimal SoundFont files, generated for tests
+
+use std::path::{Path, PathBuf};
+
+
+/// Number of frames of the looped square wave sample
+const SAMPLE_LEN: u32 = 100;
+
+/// Write a SoundFont with the given presets, as bank and number
+///
+/// All presets play the same looped square wave, held until released.
+pub fn write(path: &Path, presets: &[(u16, u16)]) {
+    let info = [
+        chunk(b"ifil", &[2, 0, 1, 0]),
+        chunk(b"isng", b"EMU8000\0"),
+        chunk(b"INAM", b"Test\0\0"),
+    ].concat();
+
+    let mut smpl = vec![];
+    for i in 0..SAMPLE_LEN {
+        let value: i16 = if i % 50 < 25 { 8000 } else { -8000 };
+        smpl.extend(value.to_le_bytes());
+    }
+    // Samples must be followed by 46 zero frames
+    smpl.resize(smpl.len() + 46 * 2, 0);
+    let sdta = chunk(b"smpl", &smpl);
+
+    let mut phdr = vec![];
+    let mut pbag = vec![];
+    let mut pgen = vec![];
+    for (i, &(bank, num)) in presets.iter().enumerate() {
+        phdr.extend(preset_header(&format!("Preset {}:{}", bank, num), num, bank, i as u16));
+        pbag.extend(bag(i as u16));
+        // Instrument generator, with the only instrument
+        pgen.extend(generator(41, 0));
+    }
+    phdr.extend(preset_header("EOP", 0, 0, presets.len() as u16));
+    pbag.extend(bag(presets.len() as u16));
+    pgen.extend(generator(0, 0));
+
+    let mut inst = name(b"Instrument").to_vec();
+    inst.extend(0u16.to_le_bytes());
+    inst.extend(name(b"EOI"));
+    inst.extend(1u16.to_le_bytes());
+
+    let igen = [
+        generator(54, 1),  // loop continuously
+        generator(53, 0),  // sample ID, must be last
+        generator(0, 0),
+    ].concat();
+
+    let mut shdr = name(b"Square").to_vec();
+    for value in [0, SAMPLE_LEN, 0, SAMPLE_LEN, 44100] {
+        shdr.extend(value.to_le_bytes());
+    }
+    shdr.extend([60, 0]);  // original pitch, correction
+    shdr.extend(0u16.to_le_bytes());  // sample link
+    shdr.extend(1u16.to_le_bytes());  // mono sample
+    shdr.extend([0; 46]);  // EOS terminal record
+
+    let pdta = [
+        chunk(b"phdr", &phdr),
+        chunk(b"pbag", &pbag),
+        chunk(b"pmod", &[0; 10]),
+        chunk(b"pgen", &pgen),
+        chunk(b"inst", &inst),
+        chunk(b"ibag", &[bag(0), bag(2)].concat()),
+        chunk(b"imod", &[0; 10]),
+        chunk(b"igen", &igen),
+        chunk(b"shdr", &shdr),
+    ].concat();
+
+    let body = [b"sfbk".to_vec(), list(b"INFO", &info), list(b"sdta", &sdta), list(b"pdta", &pdta)].concat();
+    std::fs::write(path, chunk(b"RIFF", &body)).unwrap();
+}
+
+/// Return a path in the temporary directory, unique to the test process
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("pianote-{}-{}", std::process::id(), name))
+}
+
+fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut out = id.to_vec();
+    out.extend((data.len() as u32).to_le_bytes());
+    out.extend(data);
+    out
+}
+
+fn list(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    chunk(b"LIST", &[id, data].concat())
+}
+
+fn name(s: &[u8]) -> [u8; 20] {
+    let mut name = [0; 20];
+    name[..s.len()].copy_from_slice(s);
+    name
+}
+
+fn preset_header(preset_name: &str, num: u16, bank: u16, bag_index: u16) -> Vec<u8> {
+    let mut out = name(preset_name.as_bytes()).to_vec();
+    for value in [num, bank, bag_index] {
+        out.extend(value.to_le_bytes());
+    }
+    out.extend([0; 12]);  // library, genre, morphology
+    out
+}
+
+fn bag(gen_index: u16) -> Vec<u8> {
+    [gen_index.to_le_bytes(), 0u16.to_le_bytes()].concat()
+}
+
+fn generator(oper: u16, amount: u16) -> Vec<u8> {
+    [oper.to_le_bytes(), amount.to_le_bytes()].concat()
+}
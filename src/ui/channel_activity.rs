@@ -0,0 +1,32 @@
+use std::time::{Duration, Instant};
+use iced::{Color, Point, Rectangle, Size, Theme};
+use iced::widget::canvas::{Cursor, Frame, Geometry, Program};
+
+
+/// Time for an indicator to fade out after the last event of its channel
+const DECAY: Duration = Duration::from_millis(500);
+
+/// Row of indicators lit by recent events on each MIDI channel
+pub struct ChannelActivity<'a>(pub &'a [Option<Instant>; 16]);
+
+impl<Message> Program<Message> for ChannelActivity<'_> {
+    type State = ();
+
+    fn draw(&self, _state: &(), theme: &Theme, bounds: Rectangle, _cursor: Cursor) -> Vec<Geometry> {
+        let mut frame = Frame::new(bounds.size());
+        let color = theme.palette().primary;
+        let now = Instant::now();
+        let width = bounds.width / self.0.len() as f32;
+        for (i, last_event) in self.0.iter().enumerate() {
+            let level = last_event.map_or(0.0, |time| {
+                1.0 - (now.saturating_duration_since(time).as_secs_f32() / DECAY.as_secs_f32()).min(1.0)
+            });
+            frame.fill_rectangle(
+                Point::new(i as f32 * width, 0.0),
+                Size::new((width - 2.0).max(1.0), bounds.height),
+                Color { a: 0.1 + 0.9 * level, ..color },
+            );
+        }
+        vec![frame.into_geometry()]
+    }
+}
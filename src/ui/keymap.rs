@@ -0,0 +1,134 @@
+use iced::keyboard::KeyCode;
+use wmidi::Note;
+use crate::config::KeyLayout;
+
+
+/// Notes played by computer keys
+#[derive(Copy, Clone)]
+pub struct KeyMap(&'static [(KeyCode, Note)]);
+
+impl KeyMap {
+    /// Two octaves on a QWERTY keyboard, as in trackers
+    ///
+    /// The lower octave is on the `ZXCVBNM` row, its black keys on the row
+    /// above. The upper octave starts on `Q`, its black keys on the number row.
+    pub const QWERTY: Self = Self(&[
+        (KeyCode::Z, Note::C3),
+        (KeyCode::S, Note::Db3),
+        (KeyCode::X, Note::D3),
+        (KeyCode::D, Note::Eb3),
+        (KeyCode::C, Note::E3),
+        (KeyCode::V, Note::F3),
+        (KeyCode::G, Note::Gb3),
+        (KeyCode::B, Note::G3),
+        (KeyCode::H, Note::Ab3),
+        (KeyCode::N, Note::A3),
+        (KeyCode::J, Note::Bb3),
+        (KeyCode::M, Note::B3),
+        (KeyCode::Comma, Note::C4),
+        (KeyCode::Q, Note::C4),
+        (KeyCode::Key2, Note::Db4),
+        (KeyCode::W, Note::D4),
+        (KeyCode::Key3, Note::Eb4),
+        (KeyCode::E, Note::E4),
+        (KeyCode::R, Note::F4),
+        (KeyCode::Key5, Note::Gb4),
+        (KeyCode::T, Note::G4),
+        (KeyCode::Key6, Note::Ab4),
+        (KeyCode::Y, Note::A4),
+        (KeyCode::Key7, Note::Bb4),
+        (KeyCode::U, Note::B4),
+        (KeyCode::I, Note::C5),
+        (KeyCode::Key9, Note::Db5),
+        (KeyCode::O, Note::D5),
+        (KeyCode::Key0, Note::Eb5),
+        (KeyCode::P, Note::E5),
+    ]);
+
+    /// Same keys as `QWERTY` on a QWERTZ keyboard, with Y and Z swapped
+    pub const QWERTZ: Self = Self(&[
+        (KeyCode::Y, Note::C3),
+        (KeyCode::S, Note::Db3),
+        (KeyCode::X, Note::D3),
+        (KeyCode::D, Note::Eb3),
+        (KeyCode::C, Note::E3),
+        (KeyCode::V, Note::F3),
+        (KeyCode::G, Note::Gb3),
+        (KeyCode::B, Note::G3),
+        (KeyCode::H, Note::Ab3),
+        (KeyCode::N, Note::A3),
+        (KeyCode::J, Note::Bb3),
+        (KeyCode::M, Note::B3),
+        (KeyCode::Comma, Note::C4),
+        (KeyCode::Q, Note::C4),
+        (KeyCode::Key2, Note::Db4),
+        (KeyCode::W, Note::D4),
+        (KeyCode::Key3, Note::Eb4),
+        (KeyCode::E, Note::E4),
+        (KeyCode::R, Note::F4),
+        (KeyCode::Key5, Note::Gb4),
+        (KeyCode::T, Note::G4),
+        (KeyCode::Key6, Note::Ab4),
+        (KeyCode::Z, Note::A4),
+        (KeyCode::Key7, Note::Bb4),
+        (KeyCode::U, Note::B4),
+        (KeyCode::I, Note::C5),
+        (KeyCode::Key9, Note::Db5),
+        (KeyCode::O, Note::D5),
+        (KeyCode::Key0, Note::Eb5),
+        (KeyCode::P, Note::E5),
+    ]);
+
+    /// Return the note of a key, if any
+    pub fn note(&self, key_code: KeyCode) -> Option<Note> {
+        self.0.iter().find(|(key, _)| *key == key_code).map(|(_, note)| *note)
+    }
+
+    /// Return mapped keys and their notes, lower octave first
+    pub fn keys(&self) -> impl Iterator<Item = (KeyCode, Note)> {
+        self.0.iter().copied()
+    }
+
+    /// Return the lowest and highest mapped notes
+    pub fn range(&self) -> (Note, Note) {
+        let notes = self.0.iter().map(|(_, note)| *note);
+        (notes.clone().min().unwrap(), notes.max().unwrap())
+    }
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        Self::QWERTY
+    }
+}
+
+impl From<KeyLayout> for KeyMap {
+    fn from(layout: KeyLayout) -> Self {
+        match layout {
+            KeyLayout::Qwerty => Self::QWERTY,
+            KeyLayout::Qwertz => Self::QWERTZ,
+        }
+    }
+}
+
+
+/// Return the name of a key, as printed on it
+pub fn key_name(key_code: KeyCode) -> String {
+    match key_code {
+        KeyCode::Key0 => "0".into(),
+        KeyCode::Key1 => "1".into(),
+        KeyCode::Key2 => "2".into(),
+        KeyCode::Key3 => "3".into(),
+        KeyCode::Key4 => "4".into(),
+        KeyCode::Key5 => "5".into(),
+        KeyCode::Key6 => "6".into(),
+        KeyCode::Key7 => "7".into(),
+        KeyCode::Key8 => "8".into(),
+        KeyCode::Key9 => "9".into(),
+        KeyCode::Comma => ",".into(),
+        KeyCode::Period => ".".into(),
+        KeyCode::Semicolon => ";".into(),
+        KeyCode::Slash => "/".into(),
+        key_code => format!("{:?}", key_code),
+    }
+}
@@ -1,5 +1,6 @@
 use std::rc::{Rc, Weak};
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::Instant;
 use anyhow::Result;
 use iced::{
     keyboard,
@@ -15,43 +16,163 @@ use iced::{
     Subscription,
     Theme,
 };
-use crate::piano::{Piano, PianoInput};
-use crate::midi::MidiMessage;
+use crate::config::{Bindings, BoundControl, Favorites, UiState};
+use crate::piano::{InputId, Piano, PianoInput};
+use crate::midi::{ControlEvent, MidiMessage, NoteEvent};
 use crate::piano::Preset;
+use crate::player::MidiFilePlayer;
+use crate::visualizer::Visualizer;
+use crate::error::PianoError;
+use keymap::{key_name, KeyMap};
+
+mod channel_activity;
+mod keymap;
+mod screen_keyboard;
+mod spectrum;
 
 struct Ui {
     piano: Piano,
+    /// Gain set by the user, not dimmed
     gain: f32,
+    /// Whether output is temporarily dimmed
+    dimmed: bool,
+    stereo_width: f32,
     keyboard_input: Weak<PianoUiInput>,
+    /// Identifier of keyboard input, if enabled
+    keyboard_input_id: Option<InputId>,
+    /// Whether clicking on-screen keys toggles them, instead of playing while pressed
+    latch: bool,
+    /// Notes toggled on by clicking on-screen keys
+    latched_notes: Vec<(wmidi::Channel, wmidi::Note)>,
+    visualizer: Option<Visualizer>,
+    /// Levels of spectrum bands, last computed by the visualizer
+    spectrum: Vec<f32>,
+    /// Processed note events, to show channel activity
+    note_events: Option<Receiver<NoteEvent>>,
+    /// Time of the last note event of each channel
+    channel_activity: [Option<Instant>; 16],
+    /// Player of the MIDI file given on startup
+    player: Option<MidiFilePlayer>,
+    tempo_scale: f32,
+    /// Whether the help overlay is shown, instead of controls
+    show_help: bool,
+    favorites: Favorites,
+    bindings: Bindings,
+    /// State saved to the config file, including the channel of keyboard input
+    state: UiState,
+    /// Notes played by computer keys
+    keymap: KeyMap,
+    /// Input control changes, to apply bound controllers
+    control_events: Option<Receiver<ControlEvent>>,
+    /// Whether the next changed control is bound to the next moved controller
+    learning: bool,
+    /// Control changed in learn mode, waiting for a controller
+    learn_target: Option<BoundControl>,
 }
 
 #[derive(Debug, Clone, Copy)]
 enum Message {
     GainChanged(f32),
-    KeyNoteOn(wmidi::Note),
-    KeyNoteOff(wmidi::Note),
+    DimChanged(bool),
+    StereoWidthChanged(f32),
+    TempoScaleChanged(f32),
+    KeyPressed(KeyCode),
+    KeyReleased(KeyCode),
+    ScreenKeyPressed(wmidi::Note),
+    ScreenKeyReleased(wmidi::Note),
+    LatchToggled(bool),
+    Panic,
     PresetChanged(Preset),
+    KeyboardChannelChanged(UiChannel),
+    FreezeChanged(bool),
+    PlayingToggled,
+    KeyboardInputToggled(bool),
+    SpectrumTick,
+    HelpToggled,
+    FavoriteToggled,
+    EffectsToggled,
+    ChannelMuteToggled(wmidi::Channel),
+    SoloChanged(UiSolo),
+    LearnToggled(bool),
+}
+
+/// Preset with its name, displayable in widgets
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct UiPreset(Preset, String);
+
+impl std::fmt::Display for UiPreset {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.1)
+    }
+}
 
+/// MIDI channel, displayable in widgets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct UiChannel(wmidi::Channel);
+
+impl std::fmt::Display for UiChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Channel {}", self.0.number())
+    }
+}
+
+/// Soloed MIDI channel, displayable in widgets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct UiSolo(Option<wmidi::Channel>);
+
+impl std::fmt::Display for UiSolo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.0 {
+            Some(channel) => write!(f, "Solo channel {}", channel.number()),
+            None => f.write_str("No solo"),
+        }
+    }
 }
 
 impl Application for Ui {
     type Executor = executor::Default;
-    type Flags = Piano;
+    type Flags = (Piano, Option<MidiFilePlayer>, Favorites, Bindings, UiState);
     type Message = Message;
     type Theme = Theme;
 
-    fn new(piano: Piano) -> (Self, Command<Self::Message>) {
+    fn new((piano, player, favorites, bindings, state): Self::Flags) -> (Self, Command<Self::Message>) {
         let mut ui = Self {
+            gain: piano.gain(),
+            dimmed: false,
+            stereo_width: piano.stereo_width(),
             piano,
-            gain: 1.5,  // FluidSynth default "synth.gain" value
             keyboard_input: Weak::new(),
+            keyboard_input_id: None,
+            latch: false,
+            latched_notes: vec![],
+            visualizer: None,
+            spectrum: vec![],
+            note_events: None,
+            channel_activity: [None; 16],
+            player,
+            tempo_scale: 1.0,
+            show_help: false,
+            favorites,
+            bindings,
+            control_events: None,
+            learning: false,
+            learn_target: None,
+            keymap: state.key_layout().into(),
+            state,
         };
-        ui.piano.set_gain(ui.gain);
+        ui.visualizer = ui.piano.visualizer()
+            .map_err(|err| eprintln!("failed to setup visualizer: {}", err))
+            .ok();
+        ui.note_events = ui.piano.note_events()
+            .map_err(|err| eprintln!("failed to listen to note events: {}", err))
+            .ok();
+        ui.control_events = ui.piano.control_events()
+            .map_err(|err| eprintln!("failed to listen to control events: {}", err))
+            .ok();
 
         // Enable the UI input if there is none yet 
         if !ui.piano.has_input() {
-            ui.piano.set_input(&mut ui.keyboard_input)
-                .unwrap_or_else(|err| eprintln!("failed to setup UI MIDI input: {}", err));
+            ui.set_keyboard_input(true);
         }
 
         (ui, Command::none())
@@ -64,46 +185,251 @@ impl Application for Ui {
     fn update(&mut self, message: Self::Message) -> Command<Self::Message> {
         match message {
             Message::GainChanged(gain) => {
+                self.learn(BoundControl::Gain);
                 self.gain = gain;
-                self.piano.set_gain(self.gain);
+                self.apply_gain();
+            }
+            Message::DimChanged(dimmed) => {
+                // Ignore key repeats
+                if dimmed != self.dimmed {
+                    self.dimmed = dimmed;
+                    self.apply_gain();
+                }
             }
-            Message::KeyNoteOn(note) => {
-                if let Some(input) = self.keyboard_input.upgrade() {
-                    input.queue.send(MidiMessage::NoteOn(wmidi::Channel::Ch1, note, wmidi::U7::MAX)).unwrap();
+            Message::StereoWidthChanged(width) => {
+                self.learn(BoundControl::StereoWidth);
+                self.stereo_width = width;
+                self.piano.set_stereo_width(self.stereo_width);
+            }
+            Message::TempoScaleChanged(scale) => {
+                self.learn(BoundControl::TempoScale);
+                if let Some(ref player) = self.player {
+                    self.tempo_scale = scale;
+                    player.set_tempo_scale(scale)
+                        .unwrap_or_else(|err| eprintln!("failed to change tempo: {}", err));
+                }
+            }
+            Message::KeyPressed(key_code) => {
+                if let Some(note) = self.keymap.note(key_code) {
+                    self.send_input(MidiMessage::NoteOn(self.state.keyboard_channel(), note, wmidi::U7::MAX));
+                }
+            }
+            Message::KeyReleased(key_code) => {
+                if let Some(note) = self.keymap.note(key_code) {
+                    self.send_input(MidiMessage::NoteOff(self.state.keyboard_channel(), note, wmidi::U7::MAX));
+                }
+            }
+            Message::ScreenKeyPressed(note) => {
+                let key = (self.state.keyboard_channel(), note);
+                if !self.latch {
+                    self.send_input(MidiMessage::NoteOn(key.0, note, wmidi::U7::MAX));
+                } else if let Some(pos) = self.latched_notes.iter().position(|latched| *latched == key) {
+                    self.latched_notes.remove(pos);
+                    self.send_input(MidiMessage::NoteOff(key.0, note, wmidi::U7::MAX));
+                } else {
+                    self.latched_notes.push(key);
+                    self.send_input(MidiMessage::NoteOn(key.0, note, wmidi::U7::MAX));
                 }
             }
-            Message::KeyNoteOff(note) => {
-                if let Some(input) = self.keyboard_input.upgrade() {
-                    input.queue.send(MidiMessage::NoteOff(wmidi::Channel::Ch1, note, wmidi::U7::MAX)).unwrap();
+            Message::ScreenKeyReleased(note) => {
+                if !self.latch {
+                    self.send_input(MidiMessage::NoteOff(self.state.keyboard_channel(), note, wmidi::U7::MAX));
+                }
+            }
+            Message::LatchToggled(enabled) => {
+                self.latch = enabled;
+                if !enabled {
+                    self.release_latched_notes();
+                }
+            }
+            Message::Panic => {
+                self.release_latched_notes();
+                for channel in (0..16).map(|i| wmidi::Channel::from_index(i).unwrap()) {
+                    self.piano.send_message(MidiMessage::ControlChange(channel, wmidi::ControlFunction::ALL_NOTES_OFF, wmidi::U7::MIN))
+                        .unwrap_or_else(|err| eprintln!("failed to stop notes: {}", err));
                 }
             }
             Message::PresetChanged(preset) => {
                 self.piano.set_active_preset(preset)
                     .unwrap_or_else(|err| eprintln!("failed to set preset {:?}: {}", preset, err));
             }
+            Message::KeyboardChannelChanged(UiChannel(channel)) => {
+                self.state.set_keyboard_channel(channel)
+                    .unwrap_or_else(|err| eprintln!("failed to save keyboard channel: {}", err));
+            }
+            Message::FreezeChanged(enabled) => {
+                self.piano.set_freeze(enabled)
+                    .unwrap_or_else(|err| eprintln!("failed to change freeze: {}", err));
+            }
+            Message::KeyboardInputToggled(enabled) => {
+                self.set_keyboard_input(enabled);
+            }
+            Message::PlayingToggled => {
+                let result = if self.piano.is_playing() {
+                    self.piano.pause()
+                } else {
+                    self.piano.play()
+                };
+                result.unwrap_or_else(|err| eprintln!("failed to toggle audio output: {}", err));
+            }
+            Message::HelpToggled => self.show_help = !self.show_help,
+            Message::EffectsToggled => {
+                self.learn(BoundControl::Effects);
+                // Compare dry and wet: any enabled effect is disabled
+                let enabled = !self.effects_enabled();
+                self.piano.set_reverb(enabled)
+                    .and_then(|()| self.piano.set_chorus(enabled))
+                    .unwrap_or_else(|err| eprintln!("failed to toggle effects: {}", err));
+            }
+            Message::ChannelMuteToggled(channel) => {
+                self.piano.set_channel_mute(channel, !self.piano.is_channel_muted(channel))
+                    .unwrap_or_else(|err| eprintln!("failed to toggle mute: {}", err));
+            }
+            Message::SoloChanged(UiSolo(channel)) => {
+                self.piano.set_solo(channel)
+                    .unwrap_or_else(|err| eprintln!("failed to change solo: {}", err));
+            }
+            Message::LearnToggled(enabled) => {
+                self.learning = enabled;
+                self.learn_target = None;
+            }
+            Message::FavoriteToggled => {
+                if let (Some(sfont), Ok(preset)) = (self.piano.sfont_path(), self.piano.get_active_preset()) {
+                    self.favorites.toggle(sfont, preset)
+                        .unwrap_or_else(|err| eprintln!("failed to save favorites: {}", err));
+                }
+            }
+            Message::SpectrumTick => {
+                if let Some(ref mut visualizer) = self.visualizer {
+                    self.spectrum = visualizer.spectrum(spectrum::BANDS);
+                }
+                if let Some(ref note_events) = self.note_events {
+                    let now = Instant::now();
+                    for event in note_events.try_iter() {
+                        let (NoteEvent::On { channel, .. } | NoteEvent::Off { channel, .. }) = event;
+                        self.channel_activity[channel.index() as usize] = Some(now);
+                    }
+                }
+                let control_events: Vec<_> = self.control_events.iter().flat_map(|rx| rx.try_iter()).collect();
+                for event in control_events {
+                    self.apply_control_event(event);
+                }
+            }
         }
         Command::none()
     }
 
-    fn view(&self) -> Element<Self::Message> {
+    fn view(&self) -> Element<'_, Self::Message> {
         use iced::widget::{*, column};
         use iced::Padding;
 
+        if self.show_help {
+            return self.help_view();
+        }
+
         column![
+            {
+                let xrun_count = self.piano.xrun_count();
+                let timing = self.piano.timing_stats();
+                row![
+                    button(if self.piano.is_playing() { "Pause" } else { "Play" })
+                        .on_press(Message::PlayingToggled),
+                    checkbox("Effects", self.effects_enabled(), |_| Message::EffectsToggled),
+                    text(if xrun_count > 0 { format!("{} dropouts", xrun_count) } else { String::new() }),
+                    text(format!("Callback {:.1?} max / {:.1?}", timing.max, timing.buffer)),
+                ].spacing(5).padding(Padding::from(5))
+            },
+            canvas(spectrum::Spectrum(&self.spectrum))
+                .width(iced::Length::Fill)
+                .height(iced::Length::Units(60)),
+            canvas(channel_activity::ChannelActivity(&self.channel_activity))
+                .width(iced::Length::Fill)
+                .height(iced::Length::Units(8)),
+            row![
+                text(format!("Gain {:4.1}{}", self.gain, if self.dimmed { " (dim)" } else { "" })),
+                slider(Self::GAIN_RANGE, self.gain, Message::GainChanged).step(0.1)
+            ].spacing(5).padding(Padding::from(5)),
             row![
-                text(format!("Gain {:4.1}", self.gain)),
-                slider(0.0..=10.0, self.gain, Message::GainChanged).step(0.1)
+                text(format!("Width {:3.1}", self.stereo_width)),
+                slider(Self::STEREO_WIDTH_RANGE, self.stereo_width, Message::StereoWidthChanged).step(0.1)
             ].spacing(5).padding(Padding::from(5)),
+            if self.player.is_some() {
+                Element::from(row![
+                    text(format!("Tempo {:3.0}%", self.tempo_scale * 100.0)),
+                    slider(Self::TEMPO_SCALE_RANGE, self.tempo_scale, Message::TempoScaleChanged).step(0.05)
+                ].spacing(5).padding(Padding::from(5)))
+            } else {
+                column![].into()
+            },
+            {
+                let status = match (self.learning, self.learn_target) {
+                    (false, _) => String::new(),
+                    (true, None) => "Change a control".into(),
+                    (true, Some(control)) => format!("Move a controller for {}", control.name()),
+                };
+                row![
+                    checkbox("MIDI learn", self.learning, Message::LearnToggled),
+                    text(status).size(12),
+                ].spacing(5).padding(Padding::from(5))
+            },
+            self.channels_view(),
+            {
+                let channels: Vec<_> = (0..16)
+                    .map(|i| UiChannel(wmidi::Channel::from_index(i).unwrap()))
+                    .collect();
+                let naming = self.piano.note_naming();
+                let (low, high) = self.keymap.range();
+                column![
+                    row![
+                        checkbox(
+                            format!("Keyboard {}-{}", naming.name(low), naming.name(high)),
+                            self.keyboard_input_id.is_some(),
+                            Message::KeyboardInputToggled,
+                        ),
+                        pick_list(channels, Some(UiChannel(self.state.keyboard_channel())), Message::KeyboardChannelChanged),
+                    ].spacing(5),
+                    text("Press F1 or ? for key help").size(12),
+                ].spacing(5).padding(Padding::from(5))
+            },
+            {
+                let held = self.piano.held_notes().into_iter()
+                    .chain(self.latched_notes.iter().copied())
+                    .filter(|(channel, _)| *channel == self.state.keyboard_channel())
+                    .map(|(_, note)| note)
+                    .collect();
+                column![
+                    canvas(screen_keyboard::Keyboard {
+                        held,
+                        on_press: Message::ScreenKeyPressed,
+                        on_release: Message::ScreenKeyReleased,
+                    })
+                        .width(iced::Length::Fill)
+                        .height(iced::Length::Units(60)),
+                    row![
+                        checkbox("Latch", self.latch, Message::LatchToggled),
+                        button("Panic").on_press(Message::Panic),
+                    ].spacing(5),
+                ].spacing(5).padding(Padding::from(5))
+            },
+            self.favorites_view(),
             {
                 let active_preset = self.piano.get_active_preset().ok();
-                let items = self.piano.presets_data().iter().map(|preset_data| {
-                    radio(
-                        preset_data.name.as_deref().unwrap_or("?"),
-                        preset_data.into(),
-                        active_preset,
-                        Message::PresetChanged,
-                    ).into()
-                }).collect();
+                let banks = self.piano.presets_by_bank();
+                let mut items = vec![];
+                for (bank, presets) in &banks {
+                    // Only show bank headers when there are several ones
+                    if banks.len() > 1 {
+                        items.push(text(format!("Bank {}", bank)).size(16).into());
+                    }
+                    items.extend(presets.iter().map(|preset_data| {
+                        radio(
+                            preset_data.name.as_deref().unwrap_or("?"),
+                            (*preset_data).into(),
+                            active_preset,
+                            Message::PresetChanged,
+                        ).into()
+                    }));
+                }
                 scrollable(column(items).padding(Padding::from(5)))
             }
         ].max_width(200)
@@ -111,41 +437,225 @@ impl Application for Ui {
     }
 
     fn subscription(&self) -> Subscription<Message> {
-        subscription::events_with(|event, status| {
+        let events = subscription::events_with(|event, status| {
             if status == event::Status::Ignored {
                 match event {
+                    Event::Keyboard(keyboard::Event::KeyPressed { key_code: KeyCode::Space, .. }) => {
+                        Some(Message::FreezeChanged(true))
+                    },
+                    Event::Keyboard(keyboard::Event::KeyReleased { key_code: KeyCode::Space, .. }) => {
+                        Some(Message::FreezeChanged(false))
+                    },
+                    Event::Keyboard(keyboard::Event::KeyPressed { key_code: KeyCode::Tab, .. }) => {
+                        Some(Message::DimChanged(true))
+                    },
+                    Event::Keyboard(keyboard::Event::KeyReleased { key_code: KeyCode::Tab, .. }) => {
+                        Some(Message::DimChanged(false))
+                    },
+                    Event::Keyboard(keyboard::Event::KeyPressed { key_code: KeyCode::F2, .. }) => {
+                        Some(Message::EffectsToggled)
+                    },
+                    Event::Keyboard(keyboard::Event::KeyPressed { key_code: KeyCode::F1, .. }) |
+                    Event::Keyboard(keyboard::Event::CharacterReceived('?')) => {
+                        Some(Message::HelpToggled)
+                    },
+                    // Mapped to notes by the keymap, the handler cannot capture it
                     Event::Keyboard(keyboard::Event::KeyPressed { key_code, .. }) => {
-                        Self::key_code_to_note(key_code).map(Message::KeyNoteOn)
+                        Some(Message::KeyPressed(key_code))
                     },
                     Event::Keyboard(keyboard::Event::KeyReleased { key_code, .. }) => {
-                        Self::key_code_to_note(key_code).map(Message::KeyNoteOff)
+                        Some(Message::KeyReleased(key_code))
                     },
                     _ => None,
                 }
             } else {
                 None
             }
-        })
+        });
+        let spectrum_tick = iced::time::every(spectrum::REFRESH_PERIOD).map(|_| Message::SpectrumTick);
+        Subscription::batch([events, spectrum_tick])
     }
 }
 
 impl Ui {
-    fn key_code_to_note(key_code: KeyCode) -> Option<wmidi::Note> {
-        match key_code {
-            KeyCode::E => Some(wmidi::Note::C4),
-            KeyCode::Key4 => Some(wmidi::Note::Db4),
-            KeyCode::R => Some(wmidi::Note::D4),
-            KeyCode::Key5 => Some(wmidi::Note::Eb4),
-            KeyCode::T => Some(wmidi::Note::E4),
-            KeyCode::Y => Some(wmidi::Note::F4),
-            KeyCode::Key7 => Some(wmidi::Note::Gb4),
-            KeyCode::U => Some(wmidi::Note::G4),
-            KeyCode::Key8 => Some(wmidi::Note::Ab4),
-            KeyCode::I => Some(wmidi::Note::A4),
-            KeyCode::Key9 => Some(wmidi::Note::Bb4),
-            KeyCode::O => Some(wmidi::Note::B4),
-            KeyCode::P => Some(wmidi::Note::C5),
-            _ => None,
+    /// Gain reduction applied while dimmed (-12 dB)
+    const DIM_FACTOR: f32 = 0.25;
+
+    /// Ranges of sliders, also used by bound controllers
+    const GAIN_RANGE: std::ops::RangeInclusive<f32> = 0.0..=10.0;
+    const STEREO_WIDTH_RANGE: std::ops::RangeInclusive<f32> = 0.0..=2.0;
+    const TEMPO_SCALE_RANGE: std::ops::RangeInclusive<f32> = 0.25..=2.0;
+
+    /// Keys not playing notes, and their action
+    const SHORTCUTS: [(&'static str, &'static str); 4] = [
+        ("F1, ?", "Show or hide this help"),
+        ("F2", "Toggle reverb and chorus"),
+        ("Space", "Freeze notes, while held"),
+        ("Tab", "Dim output, while held"),
+    ];
+
+    /// Quick-pick list of favorite presets, and a button to add or remove the active one
+    fn favorites_view(&self) -> Element<'_, Message> {
+        use iced::widget::{*, column};
+        use iced::Padding;
+
+        let Some(sfont) = self.piano.sfont_path() else {
+            return column![].into();
+        };
+        let name = |preset: Preset| {
+            self.piano.presets_data().iter()
+                .find(|data| Preset::from(*data) == preset)
+                .and_then(|data| data.name.clone())
+                .unwrap_or_else(|| format!("{}:{}", preset.bank, preset.num))
+        };
+        let favorites: Vec<_> = self.favorites.presets(sfont).into_iter()
+            .map(|preset| UiPreset(preset, name(preset)))
+            .collect();
+        let active_preset = self.piano.get_active_preset().ok();
+        let is_favorite = active_preset.is_some_and(|preset| self.favorites.contains(sfont, preset));
+        let selected = favorites.iter().find(|favorite| Some(favorite.0) == active_preset).cloned();
+        row![
+            pick_list(favorites, selected, |favorite| Message::PresetChanged(favorite.0))
+                .placeholder("Favorites")
+                .width(iced::Length::Fill),
+            button(if is_favorite { "\u{2605}" } else { "\u{2606}" })
+                .on_press(Message::FavoriteToggled),
+        ].spacing(5).padding(Padding::from(5))
+        .into()
+    }
+
+    /// Mute and solo of channels, when playing a MIDI file
+    fn channels_view(&self) -> Element<'_, Message> {
+        use iced::widget::{*, column};
+        use iced::Padding;
+
+        if self.player.is_none() {
+            return column![].into();
+        }
+        let channels: Vec<_> = (0..16).map(|i| wmidi::Channel::from_index(i).unwrap()).collect();
+        // Two rows of 8 channels, to fit the window width
+        let rows = channels.chunks(8).map(|chunk| {
+            row(chunk.iter().map(|&channel| {
+                let style = if self.piano.is_channel_muted(channel) {
+                    iced::theme::Button::Destructive
+                } else {
+                    iced::theme::Button::Secondary
+                };
+                button(text(channel.number()).size(12))
+                    .style(style)
+                    .width(iced::Length::Fill)
+                    .on_press(Message::ChannelMuteToggled(channel))
+                    .into()
+            }).collect()).spacing(2).into()
+        });
+        let solos: Vec<_> = std::iter::once(UiSolo(None))
+            .chain(channels.iter().map(|&channel| UiSolo(Some(channel))))
+            .collect();
+        column![
+            text("Mute channels").size(12),
+            column(rows.collect()).spacing(2),
+            pick_list(solos, Some(UiSolo(self.piano.solo())), Message::SoloChanged)
+                .width(iced::Length::Fill),
+        ].spacing(5).padding(Padding::from(5))
+        .into()
+    }
+
+    /// Help overlay, listing shortcuts and notes of computer keys
+    fn help_view(&self) -> Element<'_, Message> {
+        use iced::widget::{*, column};
+        use iced::Padding;
+
+        let naming = self.piano.note_naming();
+        let (low, high) = self.keymap.range();
+        let shortcuts = Self::SHORTCUTS.iter()
+            .map(|(key, action)| text(format!("{}: {}", key, action)).into())
+            .collect();
+        let notes = self.keymap.keys()
+            .map(|(key, note)| text(format!("{}: {}", key_name(key), naming.name(note))).size(16).into())
+            .collect();
+        column![
+            row![
+                text("Help").size(24).width(iced::Length::Fill),
+                button("Close").on_press(Message::HelpToggled),
+            ].spacing(5),
+            column(shortcuts).spacing(2),
+            text(format!("Notes {} to {}, on channel {}", naming.name(low), naming.name(high), self.state.keyboard_channel().number())),
+            scrollable(column(notes).spacing(2)),
+        ].spacing(10).padding(Padding::from(5)).max_width(200)
+        .into()
+    }
+
+    /// In learn mode, select the control to bind the next moved controller to
+    fn learn(&mut self, control: BoundControl) {
+        if self.learning {
+            self.learn_target = Some(control);
+        }
+    }
+
+    /// Bind a moved controller in learn mode, or apply a bound controller
+    ///
+    /// Controllers are still sent to the synth.
+    fn apply_control_event(&mut self, event: ControlEvent) {
+        if self.learning {
+            if let Some(control) = self.learn_target.take() {
+                self.bindings.bind(control, event.channel, event.control)
+                    .unwrap_or_else(|err| eprintln!("failed to save bindings: {}", err));
+                self.learning = false;
+            }
+            return;
+        }
+        let Some(control) = self.bindings.control(event.channel, event.control) else {
+            return;
+        };
+        let scale = |range: std::ops::RangeInclusive<f32>| {
+            range.start() + (range.end() - range.start()) * event.value as f32 / 127.0
+        };
+        let message = match control {
+            BoundControl::Gain => Message::GainChanged(scale(Self::GAIN_RANGE)),
+            BoundControl::StereoWidth => Message::StereoWidthChanged(scale(Self::STEREO_WIDTH_RANGE)),
+            BoundControl::TempoScale => Message::TempoScaleChanged(scale(Self::TEMPO_SCALE_RANGE)),
+            BoundControl::Effects if (event.value >= 64) != self.effects_enabled() => Message::EffectsToggled,
+            BoundControl::Effects => return,
+        };
+        let _ = self.update(message);
+    }
+
+    /// Return true if reverb or chorus is enabled
+    fn effects_enabled(&self) -> bool {
+        self.piano.reverb() || self.piano.chorus()
+    }
+
+    /// Apply user gain to the piano, reduced if dimmed
+    fn apply_gain(&self) {
+        let gain = if self.dimmed { self.gain * Self::DIM_FACTOR } else { self.gain };
+        self.piano.set_gain(gain);
+    }
+
+    /// Send a message to the UI input, if enabled
+    fn send_input(&self, message: MidiMessage) {
+        if let Some(input) = self.keyboard_input.upgrade() {
+            input.queue.send(message).unwrap();
+        }
+    }
+
+    /// Stop notes toggled on by clicking keys
+    fn release_latched_notes(&mut self) {
+        for (channel, note) in std::mem::take(&mut self.latched_notes) {
+            self.send_input(MidiMessage::NoteOff(channel, note, wmidi::U7::MAX));
+        }
+    }
+
+    /// Enable or disable the keyboard input
+    fn set_keyboard_input(&mut self, enabled: bool) {
+        if enabled && self.keyboard_input_id.is_none() {
+            match self.piano.add_input(&mut self.keyboard_input) {
+                Ok(id) => self.keyboard_input_id = Some(id),
+                Err(err) => eprintln!("failed to setup UI MIDI input: {}", err),
+            }
+        } else if !enabled {
+            if let Some(id) = self.keyboard_input_id.take() {
+                self.piano.remove_input(id);
+            }
         }
     }
 }
@@ -156,7 +666,7 @@ struct PianoUiInput {
 }
 
 impl PianoInput for &mut Weak<PianoUiInput> {
-    fn connect_input(self, queue: Sender<MidiMessage>) -> Result<Box<dyn std::any::Any>> {
+    fn connect_input(self, queue: Sender<MidiMessage>) -> Result<Box<dyn std::any::Any>, PianoError> {
         println!("connecting input");
         let input = Rc::new(PianoUiInput { queue });
         *self = Rc::downgrade(&input);
@@ -165,7 +675,53 @@ impl PianoInput for &mut Weak<PianoUiInput> {
 }
 
 
-pub fn run(piano: Piano) -> iced::Result {
-    Ui::run(Settings::with_flags(piano))
+/// Error returned when the UI cannot be run
+pub enum UiError {
+    /// No display is available, the piano and player are given back
+    NoDisplay(Box<Piano>, Option<MidiFilePlayer>),
+    Iced(iced::Error),
+}
+
+impl std::fmt::Debug for UiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::NoDisplay(..) => f.write_str("NoDisplay"),
+            Self::Iced(err) => f.debug_tuple("Iced").field(err).finish(),
+        }
+    }
+}
+
+impl std::fmt::Display for UiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::NoDisplay(..) => f.write_str("no display available"),
+            Self::Iced(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for UiError {}
+
+/// Return false if there is no display to open a window on
+///
+/// Only X11 and Wayland are checked, other platforms are assumed to have one.
+pub fn has_display() -> bool {
+    if cfg!(all(unix, not(target_os = "macos"))) {
+        std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some()
+    } else {
+        true
+    }
+}
+
+/// Run the UI, until its window is closed
+///
+/// If a MIDI file player is given, its tempo can be changed from the UI.
+/// Favorite presets, controller bindings and UI state are edited from the UI.
+/// Fail early if there is no display, instead of letting the window creation panic.
+pub fn run(piano: Piano, player: Option<MidiFilePlayer>, favorites: Favorites, bindings: Bindings, state: UiState) -> Result<(), UiError> {
+    if !has_display() {
+        return Err(UiError::NoDisplay(Box::new(piano), player));
+    }
+    Ui::run(Settings::with_flags((piano, player, favorites, bindings, state))).map_err(UiError::Iced)
 }
 
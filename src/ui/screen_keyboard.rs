@@ -0,0 +1,110 @@
+use iced::{mouse, Color, Point, Rectangle, Size, Theme};
+use iced::widget::canvas::{event, Cursor, Event, Frame, Geometry, Path, Program, Stroke};
+use wmidi::Note;
+
+
+/// Lowest key of the on-screen keyboard
+pub const LOWEST_NOTE: Note = Note::C4;
+/// Highest key of the on-screen keyboard
+pub const HIGHEST_NOTE: Note = Note::C5;
+
+/// Height of black keys, relative to white ones
+const BLACK_KEY_HEIGHT: f32 = 0.6;
+/// Width of black keys, relative to white ones
+const BLACK_KEY_WIDTH: f32 = 0.6;
+
+/// Clickable piano keyboard, highlighting held notes
+pub struct Keyboard<Message> {
+    /// Notes drawn as held
+    pub held: Vec<Note>,
+    /// Message sent when a key is clicked
+    pub on_press: fn(Note) -> Message,
+    /// Message sent when the mouse button is released, for the clicked key
+    pub on_release: fn(Note) -> Message,
+}
+
+/// Key clicked with the mouse, until the button is released
+#[derive(Default)]
+pub struct KeyboardState {
+    pressed: Option<Note>,
+}
+
+impl<Message> Keyboard<Message> {
+    fn is_black(note: Note) -> bool {
+        matches!(u8::from(note) % 12, 1 | 3 | 6 | 8 | 10)
+    }
+
+    fn notes() -> impl Iterator<Item = Note> {
+        (u8::from(LOWEST_NOTE)..=u8::from(HIGHEST_NOTE)).map(|num| Note::try_from(num).unwrap())
+    }
+
+    /// Return the rectangle of each key, white keys first
+    fn key_rectangles(bounds: Size) -> Vec<(Note, Rectangle)> {
+        let white_count = Self::notes().filter(|note| !Self::is_black(*note)).count();
+        let white_width = bounds.width / white_count as f32;
+        let mut whites = vec![];
+        let mut blacks = vec![];
+        let mut x = 0.0;
+        for note in Self::notes() {
+            if Self::is_black(note) {
+                let width = white_width * BLACK_KEY_WIDTH;
+                let position = Point::new(x - width / 2.0, 0.0);
+                blacks.push((note, Rectangle::new(position, Size::new(width, bounds.height * BLACK_KEY_HEIGHT))));
+            } else {
+                whites.push((note, Rectangle::new(Point::new(x, 0.0), Size::new(white_width, bounds.height))));
+                x += white_width;
+            }
+        }
+        whites.extend(blacks);
+        whites
+    }
+
+    /// Return the key at the given position, relative to the keyboard
+    fn key_at(bounds: Size, position: Point) -> Option<Note> {
+        // Black keys are drawn above white ones
+        Self::key_rectangles(bounds).into_iter().rev()
+            .find(|(_, rect)| rect.contains(position))
+            .map(|(note, _)| note)
+    }
+}
+
+impl<Message> Program<Message> for Keyboard<Message> {
+    type State = KeyboardState;
+
+    fn update(&self, state: &mut KeyboardState, event: Event, bounds: Rectangle, cursor: Cursor) -> (event::Status, Option<Message>) {
+        match event {
+            Event::Mouse(mouse::Event::ButtonPressed(mouse::Button::Left)) => {
+                let note = cursor.position_in(&bounds).and_then(|position| Self::key_at(bounds.size(), position));
+                if let Some(note) = note {
+                    state.pressed = Some(note);
+                    return (event::Status::Captured, Some((self.on_press)(note)));
+                }
+            }
+            Event::Mouse(mouse::Event::ButtonReleased(mouse::Button::Left)) => {
+                if let Some(note) = state.pressed.take() {
+                    return (event::Status::Captured, Some((self.on_release)(note)));
+                }
+            }
+            _ => {}
+        }
+        (event::Status::Ignored, None)
+    }
+
+    fn draw(&self, _state: &KeyboardState, theme: &Theme, bounds: Rectangle, _cursor: Cursor) -> Vec<Geometry> {
+        let mut frame = Frame::new(bounds.size());
+        let held_color = theme.palette().primary;
+        for (note, rect) in Self::key_rectangles(bounds.size()) {
+            let color = if self.held.contains(&note) {
+                held_color
+            } else if Self::is_black(note) {
+                Color::BLACK
+            } else {
+                Color::WHITE
+            };
+            let path = Path::rectangle(rect.position(), rect.size());
+            frame.fill(&path, color);
+            frame.stroke(&path, Stroke::default().with_color(Color::BLACK).with_width(1.0));
+        }
+        vec![frame.into_geometry()]
+    }
+}
@@ -0,0 +1,35 @@
+use std::time::Duration;
+use iced::{Color, Point, Rectangle, Size, Theme};
+use iced::widget::canvas::{Cursor, Frame, Geometry, Program};
+
+
+/// Number of displayed frequency bands
+pub const BANDS: usize = 32;
+
+/// Time between two spectrum updates
+pub const REFRESH_PERIOD: Duration = Duration::from_millis(50);
+
+/// Bars of output spectrum band levels
+pub struct Spectrum<'a>(pub &'a [f32]);
+
+impl<Message> Program<Message> for Spectrum<'_> {
+    type State = ();
+
+    fn draw(&self, _state: &(), theme: &Theme, bounds: Rectangle, _cursor: Cursor) -> Vec<Geometry> {
+        let mut frame = Frame::new(bounds.size());
+        let color = theme.palette().primary;
+        frame.fill_rectangle(Point::ORIGIN, bounds.size(), Color { a: 0.1, ..color });
+        if !self.0.is_empty() {
+            let width = bounds.width / self.0.len() as f32;
+            for (i, level) in self.0.iter().enumerate() {
+                let height = level * bounds.height;
+                frame.fill_rectangle(
+                    Point::new(i as f32 * width, bounds.height - height),
+                    Size::new((width - 1.0).max(1.0), height),
+                    color,
+                );
+            }
+        }
+        vec![frame.into_geometry()]
+    }
+}
@@ -0,0 +1,123 @@
+use std::sync::mpsc::Receiver;
+
+
+/// Number of mono samples in blocks sent by the audio thread
+pub const BLOCK_SIZE: usize = 256;
+
+/// Block of mono output samples
+pub type AudioBlock = [f32; BLOCK_SIZE];
+
+/// Analyze output samples, to display their spectrum
+///
+/// Samples are copied by the audio thread, all analysis is done by the
+/// thread calling `spectrum()`.
+pub struct Visualizer {
+    blocks: Receiver<AudioBlock>,
+    /// Last received samples, ring buffer
+    samples: Vec<f32>,
+    /// Position of the oldest sample in `samples`
+    pos: usize,
+    /// Hann window applied before each FFT
+    window: Vec<f32>,
+}
+
+impl Visualizer {
+    /// Number of samples used by each analysis
+    pub const FFT_SIZE: usize = 2048;
+
+    /// Lowest level of bands, in dB
+    const MIN_DB: f32 = -80.0;
+
+    pub(crate) fn new(blocks: Receiver<AudioBlock>) -> Self {
+        let window = (0..Self::FFT_SIZE)
+            .map(|i| {
+                let x = std::f32::consts::PI * i as f32 / (Self::FFT_SIZE - 1) as f32;
+                x.sin().powi(2)
+            })
+            .collect();
+        Self {
+            blocks,
+            samples: vec![0.0; Self::FFT_SIZE],
+            pos: 0,
+            window,
+        }
+    }
+
+    /// Analyze the last samples, return the level of `bands` frequency bands
+    ///
+    /// Bands are spaced logarithmically. Levels are between 0 (-80 dB or less)
+    /// and 1 (full scale).
+    pub fn spectrum(&mut self, bands: usize) -> Vec<f32> {
+        for block in self.blocks.try_iter() {
+            for sample in block {
+                self.samples[self.pos] = sample;
+                self.pos = (self.pos + 1) % Self::FFT_SIZE;
+            }
+        }
+
+        let mut re: Vec<f32> = (0..Self::FFT_SIZE)
+            .map(|i| self.samples[(self.pos + i) % Self::FFT_SIZE] * self.window[i])
+            .collect();
+        let mut im = vec![0.0; Self::FFT_SIZE];
+        fft(&mut re, &mut im);
+
+        // Amplitude of a full scale sine, with the Hann window
+        let scale = 4.0 / Self::FFT_SIZE as f32;
+        let magnitudes: Vec<f32> = re.iter().zip(&im)
+            .take(Self::FFT_SIZE / 2)
+            .map(|(re, im)| (re * re + im * im).sqrt() * scale)
+            .collect();
+
+        // Group bins in bands, skipping the DC bin
+        let max_bin = magnitudes.len() as f32;
+        (0..bands)
+            .map(|band| {
+                let start = max_bin.powf(band as f32 / bands as f32) as usize;
+                let end = (max_bin.powf((band + 1) as f32 / bands as f32) as usize).max(start + 1);
+                let peak = magnitudes[start..end.min(magnitudes.len())].iter().copied().fold(0.0, f32::max);
+                let db = 20.0 * peak.max(1e-10).log10();
+                (1.0 - db / Self::MIN_DB).clamp(0.0, 1.0)
+            })
+            .collect()
+    }
+}
+
+
+/// In-place radix-2 FFT, length must be a power of two
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    debug_assert!(n.is_power_of_two() && im.len() == n);
+
+    // Bit-reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -std::f32::consts::TAU / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_im, w_re) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        len <<= 1;
+    }
+}
@@ -0,0 +1,198 @@
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use anyhow::{Context, Result};
+
+
+/// Sample format of written WAV files
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum WavFormat {
+    /// 16-bit PCM, dithered, the most compatible one
+    #[default]
+    Pcm16,
+    /// 24-bit PCM
+    Pcm24,
+    /// 32-bit float, samples are written unchanged
+    Float32,
+}
+
+impl WavFormat {
+    fn bytes_per_sample(self) -> u16 {
+        match self {
+            Self::Pcm16 => 2,
+            Self::Pcm24 => 3,
+            Self::Float32 => 4,
+        }
+    }
+}
+
+impl std::str::FromStr for WavFormat {
+    type Err = anyhow::Error;
+
+    /// Parse `16`, `24` or `float`
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "16" => Ok(Self::Pcm16),
+            "24" => Ok(Self::Pcm24),
+            "float" => Ok(Self::Float32),
+            _ => anyhow::bail!("invalid WAV format, expected 16, 24 or float: {}", s),
+        }
+    }
+}
+
+/// Write interleaved stereo samples to a WAV file
+///
+/// PCM samples are clipped to [-1, 1]. 16-bit samples get triangular dither,
+/// from a fixed seed so that the same samples always produce the same file.
+pub fn write_wav(path: &Path, samples: &[f32], sample_rate: u32, format: WavFormat) -> Result<()> {
+    std::fs::File::create(path)
+        .and_then(|file| {
+            let mut out = BufWriter::new(file);
+            encode_wav(&mut out, samples, sample_rate, format)?;
+            out.flush()
+        })
+        .with_context(|| format!("cannot write {}", path.display()))
+}
+
+/// Encode interleaved stereo samples as WAV data, see `write_wav()`
+fn encode_wav(out: &mut impl Write, samples: &[f32], sample_rate: u32, format: WavFormat) -> std::io::Result<()> {
+    const CHANNELS: u16 = 2;
+    let bytes_per_sample = format.bytes_per_sample();
+    let is_float = format == WavFormat::Float32;
+
+    let data_len = (samples.len() * bytes_per_sample as usize) as u32;
+    // Non-PCM formats have an extension size in their format chunk, and a fact chunk
+    let fmt_len: u32 = if is_float { 18 } else { 16 };
+    let fact_len: u32 = if is_float { 12 } else { 0 };
+    out.write_all(b"RIFF")?;
+    out.write_all(&(20 + fmt_len + fact_len + data_len).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+    out.write_all(b"fmt ")?;
+    out.write_all(&fmt_len.to_le_bytes())?;
+    out.write_all(&(if is_float { 3u16 } else { 1u16 }).to_le_bytes())?;  // IEEE float or PCM
+    out.write_all(&CHANNELS.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * (CHANNELS * bytes_per_sample) as u32).to_le_bytes())?;
+    out.write_all(&(CHANNELS * bytes_per_sample).to_le_bytes())?;
+    out.write_all(&(bytes_per_sample * 8).to_le_bytes())?;
+    if is_float {
+        out.write_all(&0u16.to_le_bytes())?;
+        out.write_all(b"fact")?;
+        out.write_all(&4u32.to_le_bytes())?;
+        out.write_all(&((samples.len() / CHANNELS as usize) as u32).to_le_bytes())?;
+    }
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+    match format {
+        WavFormat::Pcm16 => {
+            let mut dither = Dither(0x2545_f491);
+            for sample in samples {
+                let value = (sample * i16::MAX as f32 + dither.next()).round()
+                    .clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+                out.write_all(&value.to_le_bytes())?;
+            }
+        }
+        WavFormat::Pcm24 => {
+            const MAX: f32 = 8_388_607.0;
+            for sample in samples {
+                let value = (sample.clamp(-1.0, 1.0) * MAX).round() as i32;
+                out.write_all(&value.to_le_bytes()[..3])?;
+            }
+        }
+        WavFormat::Float32 => {
+            for sample in samples {
+                out.write_all(&sample.to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Triangular dither noise, from a xorshift generator
+struct Dither(u32);
+
+impl Dither {
+    /// Return noise in ]-1, 1[, in output steps
+    fn next(&mut self) -> f32 {
+        let mut uniform = || {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 as f32 / u32::MAX as f32
+        };
+        uniform() - uniform()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_wav, WavFormat};
+
+    fn encode(samples: &[f32], format: WavFormat) -> Vec<u8> {
+        let mut data = vec![];
+        encode_wav(&mut data, samples, 48000, format).unwrap();
+        data
+    }
+
+    fn u16_at(data: &[u8], pos: usize) -> u16 {
+        u16::from_le_bytes(data[pos..pos + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn headers() {
+        let samples = [0.0; 8];
+        // Format, size of a sample, offset of the data chunk
+        for (format, tag, bytes, data_pos) in [(WavFormat::Pcm16, 1, 2, 36), (WavFormat::Pcm24, 1, 3, 36), (WavFormat::Float32, 3, 4, 50)] {
+            let data = encode(&samples, format);
+            assert_eq!(data.len(), data_pos + 8 + 8 * bytes, "{:?}", format);
+            assert_eq!(&data[..4], b"RIFF");
+            assert_eq!(u32_at(&data, 4) as usize, data.len() - 8);
+            assert_eq!(&data[8..16], b"WAVEfmt ");
+            assert_eq!(u16_at(&data, 20), tag);
+            assert_eq!(u16_at(&data, 22), 2);
+            assert_eq!(u32_at(&data, 24), 48000);
+            assert_eq!(u32_at(&data, 28) as usize, 48000 * 2 * bytes);
+            assert_eq!(u16_at(&data, 32) as usize, 2 * bytes);
+            assert_eq!(u16_at(&data, 34) as usize, 8 * bytes);
+            assert_eq!(&data[data_pos..data_pos + 4], b"data");
+            assert_eq!(u32_at(&data, data_pos + 4) as usize, 8 * bytes);
+        }
+        let data = encode(&samples, WavFormat::Float32);
+        assert_eq!(&data[38..42], b"fact");
+        assert_eq!(u32_at(&data, 42), 4);
+        // Frame count
+        assert_eq!(u32_at(&data, 46), 4);
+    }
+
+    #[test]
+    fn pcm24_samples() {
+        let data = encode(&[1.0, -1.0, 0.0, 2.0], WavFormat::Pcm24);
+        assert_eq!(data[44..], [0xff, 0xff, 0x7f, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0xff, 0xff, 0x7f]);
+    }
+
+    #[test]
+    fn pcm16_clipping() {
+        let data = encode(&[4.0, -4.0, 1.0, -1.0], WavFormat::Pcm16);
+        let values: Vec<i16> = data[44..].chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+        assert_eq!(values[..2], [i16::MAX, i16::MIN]);
+        // Dither is less than one step
+        assert!(values[2] >= i16::MAX - 1);
+        assert!(values[3] <= i16::MIN + 2);
+    }
+
+    #[test]
+    fn float_samples() {
+        let data = encode(&[0.25, -1.5], WavFormat::Float32);
+        assert_eq!(data[58..], [0.25f32.to_le_bytes(), (-1.5f32).to_le_bytes()].concat());
+    }
+
+    #[test]
+    fn reproducible_dither() {
+        let samples: Vec<f32> = (0..1000).map(|i| (i as f32 * 0.01).sin() * 0.5).collect();
+        assert_eq!(encode(&samples, WavFormat::Pcm16), encode(&samples, WavFormat::Pcm16));
+    }
+}